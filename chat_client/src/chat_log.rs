@@ -0,0 +1,246 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chat_core::{
+    encryption::PublicKey,
+    event::{Event, RoomInfo},
+    message::{Message, MessageId},
+    request::RequestError,
+    user::User,
+    value::Value,
+};
+
+use crate::e2e::KeyPair;
+
+/// Max characters of a message shown when it is quoted
+const QUOTE_LENGTH: usize = 60;
+
+/// A line shown in the messages area
+pub enum ChatLine {
+    Message(Box<Message>),
+    /// An error the server sent back for one of our requests
+    Error(RequestError),
+    /// A note from the client itself
+    Notice(String),
+}
+
+/// Everything the server has sent, shared between the thread reading from the server and the gui
+#[derive(Default)]
+pub struct ChatLog {
+    lines: Vec<ChatLine>,
+    /// Messages that mention us
+    mentioned: HashSet<MessageId>,
+    /// Mentions that have not been looked at yet
+    unread_mentions: usize,
+    /// The users in our room that are typing, by id
+    typing: BTreeMap<usize, User>,
+    /// The users in our room as of the last `UserList`
+    members: Vec<User>,
+    /// The rooms we have been in, sorted by name
+    rooms: Vec<RoomInfo>,
+    /// The room we are in, `None` until the server sends the room list
+    current_room: Option<String>,
+    /// Decrypts the encrypted direct messages sent to us
+    key_pair: Option<KeyPair>,
+    /// The public keys the server sent, by user id
+    keys: HashMap<usize, PublicKey>,
+}
+
+impl ChatLog {
+    pub fn lines(&self) -> &[ChatLine] {
+        &self.lines
+    }
+    pub fn push_error(&mut self, error: RequestError) {
+        self.lines.push(ChatLine::Error(error));
+    }
+    pub fn push_notice(&mut self, notice: impl Into<String>) {
+        self.lines.push(ChatLine::Notice(notice.into()));
+    }
+    /// Use the key pair to decrypt encrypted direct messages from now on
+    pub fn set_key_pair(&mut self, key_pair: KeyPair) {
+        self.key_pair = Some(key_pair);
+    }
+    pub fn key_pair(&self) -> Option<&KeyPair> {
+        self.key_pair.as_ref()
+    }
+    /// The public key of the user, if the server has sent it
+    pub fn public_key(&self, user: usize) -> Option<PublicKey> {
+        self.keys.get(&user).copied()
+    }
+    pub fn message(&self, id: MessageId) -> Option<&Message> {
+        self.lines.iter().rev().find_map(|line| match line {
+            ChatLine::Message(message) if message.id() == Some(id) => Some(message.as_ref()),
+            _ => None,
+        })
+    }
+    fn message_mut(&mut self, id: MessageId) -> Option<&mut Message> {
+        self.lines.iter_mut().rev().find_map(|line| match line {
+            ChatLine::Message(message) if message.id() == Some(id) => Some(message.as_mut()),
+            _ => None,
+        })
+    }
+    /// The messages that reply to the message with the id
+    pub fn replies(&self, id: MessageId) -> impl Iterator<Item = &Message> {
+        self.lines.iter().filter_map(move |line| match line {
+            ChatLine::Message(message) if message.reply_to() == Some(id) => Some(message.as_ref()),
+            _ => None,
+        })
+    }
+    pub fn is_mentioned(&self, id: MessageId) -> bool {
+        self.mentioned.contains(&id)
+    }
+    pub fn unread_mentions(&self) -> usize {
+        self.unread_mentions
+    }
+    pub fn clear_unread_mentions(&mut self) {
+        self.unread_mentions = 0;
+    }
+    pub fn rooms(&self) -> &[RoomInfo] {
+        &self.rooms
+    }
+    pub fn current_room(&self) -> Option<&str> {
+        self.current_room.as_deref()
+    }
+    pub fn members(&self) -> &[User] {
+        &self.members
+    }
+    /// The id of the newest message in `room`
+    pub fn latest_in(&self, room: &str) -> Option<MessageId> {
+        self.lines.iter().rev().find_map(|line| match line {
+            ChatLine::Message(message) if message.room() == Some(room) => message.id(),
+            _ => None,
+        })
+    }
+    /// What to show under the messages while other users are typing, e.g. `alice is typing...`
+    pub fn typing(&self) -> Option<String> {
+        let names = self
+            .typing
+            .values()
+            .map(|user| user.username().to_string())
+            .collect::<Vec<_>>();
+
+        match names.as_slice() {
+            [] => None,
+            [name] => Some(format!("{name} is typing...")),
+            [first, second] => Some(format!("{first} and {second} are typing...")),
+            names => Some(format!("{} people are typing...", names.len())),
+        }
+    }
+    /// Add a new message or apply a change to the message it is for, changes to messages older than anything shown
+    /// are ignored
+    pub fn apply(&mut self, event: Event) {
+        match event {
+            Event::Message(mut message) => {
+                self.typing.remove(&message.from().id());
+                self.decrypt(&mut message);
+                self.lines.push(ChatLine::Message(Box::new(message)));
+            }
+            Event::TypingStarted { user } => {
+                self.typing.insert(user.id(), user);
+            }
+            Event::TypingStopped { user } => {
+                self.typing.remove(&user.id());
+            }
+            Event::Mention(mut message) => {
+                let Some(id) = message.id() else {
+                    return;
+                };
+                self.mentioned.insert(id);
+                self.unread_mentions += 1;
+                // Mentions from other rooms are the only way we see those messages
+                if self.message(id).is_none() {
+                    self.decrypt(&mut message);
+                    self.lines.push(ChatLine::Message(Box::new(message)));
+                }
+            }
+            Event::MessageEdited {
+                id,
+                payload,
+                edited_at,
+            } => {
+                if let Some(message) = self.message_mut(id) {
+                    message.edit(payload, edited_at);
+                }
+            }
+            Event::MessageDeleted { id } => {
+                if let Some(message) = self.message_mut(id) {
+                    message.delete();
+                }
+            }
+            Event::ReactionAdded { id, emoji, .. } => {
+                if let Some(message) = self.message_mut(id) {
+                    message.add_reaction(&emoji);
+                }
+            }
+            Event::ReactionRemoved { id, emoji, .. } => {
+                if let Some(message) = self.message_mut(id) {
+                    message.remove_reaction(&emoji);
+                }
+            }
+            Event::RoomList { current, rooms } => {
+                if self.current_room.as_ref() != Some(&current) {
+                    // Whoever was typing is in the room we left
+                    self.typing.clear();
+                    self.members.clear();
+                }
+                self.current_room = Some(current);
+                self.rooms = rooms;
+            }
+            Event::UserList { room, users } => {
+                // A list for the room we just left is out of date
+                if self.current_room.as_ref().is_none_or(|current| *current == room) {
+                    self.members = users;
+                }
+            }
+            Event::RoomUnread { room, unread } => {
+                match self.rooms.binary_search_by(|info| info.name.cmp(&room)) {
+                    Ok(index) => self.rooms[index].unread = unread,
+                    Err(index) => self.rooms.insert(
+                        index,
+                        RoomInfo {
+                            name: room,
+                            users: 0,
+                            unread,
+                        },
+                    ),
+                }
+            }
+            Event::PublicKey { user, key } => match key {
+                Some(key) => {
+                    self.keys.insert(user, key);
+                }
+                None => {
+                    self.keys.remove(&user);
+                }
+            },
+        }
+    }
+    /// Replace an encrypted payload with the text it holds, messages we cannot decrypt are left as they are
+    fn decrypt(&self, message: &mut Message) {
+        let (Value::Encrypted(payload), Some(key_pair)) = (message.payload(), &self.key_pair)
+        else {
+            return;
+        };
+
+        match key_pair.decrypt(payload) {
+            Ok(text) => message.set_payload(Value::String(format!("(encrypted) {text}"))),
+            Err(error) => log::warn!("failed to decrypt message from {}: {error}", message.from()),
+        }
+    }
+    /// The message with the id shortened to a single line, used to show what a reply is replying to
+    pub fn quote(&self, id: MessageId) -> String {
+        match self.message(id) {
+            Some(parent) if parent.is_deleted() => "(message deleted)".to_owned(),
+            Some(parent) => {
+                let text = parent.payload().to_string();
+                let mut quote = text.lines().next().unwrap_or_default().to_owned();
+                if quote.chars().count() > QUOTE_LENGTH {
+                    quote = quote.chars().take(QUOTE_LENGTH).collect::<String>() + "...";
+                } else if quote.len() < text.len() {
+                    quote.push_str("...");
+                }
+                format!("{}: {quote}", parent.from())
+            }
+            None => format!("message #{id}"),
+        }
+    }
+}
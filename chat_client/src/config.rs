@@ -0,0 +1,91 @@
+use chat_core::{
+    config::{Config, Validate, Validator},
+    message::Message,
+};
+use chrono::{
+    format::{Item, StrftimeItems},
+    Local,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    pub username: Username,
+    pub display: DisplayConfig,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Username {
+    name: Option<String>,
+}
+
+impl Username {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    pub fn set_name(&mut self, name: Option<String>) {
+        self.name = name;
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DisplayConfig {
+    /// strftime style format of the time shown next to messages
+    time_format: String,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            time_format: "%H:%M:%S".to_owned(),
+        }
+    }
+}
+
+impl DisplayConfig {
+    pub fn time_format(&self) -> &str {
+        &self.time_format
+    }
+    /// The message with the time it was sent in front, in the local time zone
+    pub fn format_message(&self, message: &Message) -> String {
+        match message.timestamp() {
+            Some(timestamp) => format!(
+                "[{}] {message}",
+                timestamp.with_timezone(&Local).format(&self.time_format)
+            ),
+            None => format!("{message}"),
+        }
+    }
+}
+
+impl Validate for DisplayConfig {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            !StrftimeItems::new(&self.time_format).any(|item| item == Item::Error),
+            "time_format",
+            format!(
+                "{:?} is not a valid time format, e.g. \"%H:%M\"",
+                self.time_format
+            ),
+        );
+    }
+}
+
+impl Config for ClientConfig {
+    const NAME: &'static str = "client";
+    const TEMPLATE: &'static str = r#"[username]
+# Name used when connecting, the client asks for one if this is not set
+# name = "joey"
+
+[display]
+# How the time a message was sent is shown, see https://docs.rs/chrono/latest/chrono/format/strftime
+time_format = "%H:%M:%S"
+"#;
+
+    fn validate(&self, validator: &mut Validator) {
+        validator.section("display", &self.display);
+    }
+}
@@ -0,0 +1,218 @@
+//! End-to-end encryption of direct messages. Both ends derive the same XChaCha20-Poly1305 key from their X25519 key
+//! pairs, so only the sender and the recipient can read a message. Keys are published to and looked up in the
+//! directory of the server, which means the server has to be trusted to hand out the right keys but can never read
+//! the messages themselves.
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use chat_core::encryption::{EncryptedPayload, PublicKey};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::StaticSecret;
+
+/// Mixed into the derived key so it is only ever used for direct messages
+const KEY_INFO: &[u8] = b"chat e2e direct message v1";
+
+#[derive(Debug, Error)]
+pub enum E2eError {
+    #[error("the other public key is not a usable x25519 key")]
+    BadKey,
+    #[error("the message is not for this key pair")]
+    NotForUs,
+    #[error("the message could not be decrypted, it was changed or not encrypted for us")]
+    Decrypt,
+}
+
+/// The key pair of a client, a new one is generated for every connection since the server keeps keys by user id
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey(x25519_dalek::PublicKey::from(&secret).to_bytes());
+
+        Self { secret, public }
+    }
+    /// The key to publish with `Request::PublishKey`
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+    /// Encrypt text for the user with the public key `recipient`
+    pub fn encrypt(&self, recipient: PublicKey, text: &str) -> Result<EncryptedPayload, E2eError> {
+        let cipher = self.cipher(recipient)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: text.as_bytes(),
+                    aad: &associated_data(self.public, recipient),
+                },
+            )
+            .expect("messages are far below the most the cipher can encrypt");
+
+        Ok(EncryptedPayload {
+            sender_key: self.public,
+            recipient_key: recipient,
+            nonce: nonce.into(),
+            ciphertext,
+        })
+    }
+    /// Decrypt a message sent to us, or one we sent since both ends derive the same key
+    pub fn decrypt(&self, payload: &EncryptedPayload) -> Result<String, E2eError> {
+        let other = if payload.recipient_key == self.public {
+            payload.sender_key
+        } else if payload.sender_key == self.public {
+            payload.recipient_key
+        } else {
+            return Err(E2eError::NotForUs);
+        };
+
+        let text = self
+            .cipher(other)?
+            .decrypt(
+                XNonce::from_slice(&payload.nonce),
+                Payload {
+                    msg: &payload.ciphertext,
+                    aad: &associated_data(payload.sender_key, payload.recipient_key),
+                },
+            )
+            .map_err(|_| E2eError::Decrypt)?;
+        String::from_utf8(text).map_err(|_| E2eError::Decrypt)
+    }
+    /// The cipher for messages between us and the owner of `other`
+    fn cipher(&self, other: PublicKey) -> Result<XChaCha20Poly1305, E2eError> {
+        let shared = self
+            .secret
+            .diffie_hellman(&x25519_dalek::PublicKey::from(other.0));
+        // A low order key would give a shared secret anyone can work out
+        if !shared.was_contributory() {
+            return Err(E2eError::BadKey);
+        }
+
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(KEY_INFO, &mut key)
+            .expect("32 bytes is a valid length for sha256");
+        Ok(XChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+/// Both keys are authenticated along with the message, so a message cannot be passed off as going the other way
+fn associated_data(sender: PublicKey, recipient: PublicKey) -> [u8; 64] {
+    let mut data = [0; 64];
+    data[..32].copy_from_slice(&sender.0);
+    data[32..].copy_from_slice(&recipient.0);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message from a new key pair to another, along with both key pairs
+    fn sent(text: &str) -> (KeyPair, KeyPair, EncryptedPayload) {
+        let sender = KeyPair::generate();
+        let recipient = KeyPair::generate();
+        let payload = sender.encrypt(recipient.public_key(), text).unwrap();
+        (sender, recipient, payload)
+    }
+
+    #[test]
+    fn both_ends_can_read_a_message() {
+        let (sender, recipient, payload) = sent("meet at noon");
+
+        assert_eq!(payload.sender_key, sender.public_key());
+        assert_eq!(payload.recipient_key, recipient.public_key());
+        assert_ne!(payload.ciphertext, b"meet at noon");
+        assert_eq!(recipient.decrypt(&payload).unwrap(), "meet at noon");
+        assert_eq!(sender.decrypt(&payload).unwrap(), "meet at noon");
+    }
+
+    #[test]
+    fn others_cannot_read_a_message() {
+        let (_, recipient, mut payload) = sent("meet at noon");
+        let other = KeyPair::generate();
+
+        assert!(matches!(other.decrypt(&payload), Err(E2eError::NotForUs)));
+
+        // Claiming the message was for them does not help, they do not have the key it was encrypted with
+        payload.recipient_key = other.public_key();
+        assert!(matches!(other.decrypt(&payload), Err(E2eError::Decrypt)));
+        assert!(matches!(
+            recipient.decrypt(&payload),
+            Err(E2eError::NotForUs)
+        ));
+    }
+
+    #[test]
+    fn changed_messages_are_rejected() {
+        let (sender, recipient, payload) = sent("meet at noon");
+
+        let mut changed = payload.clone();
+        changed.ciphertext[0] ^= 1;
+        assert!(matches!(
+            recipient.decrypt(&changed),
+            Err(E2eError::Decrypt)
+        ));
+
+        let mut changed = payload.clone();
+        changed.ciphertext.pop();
+        assert!(matches!(
+            recipient.decrypt(&changed),
+            Err(E2eError::Decrypt)
+        ));
+
+        let mut changed = payload.clone();
+        changed.nonce[0] ^= 1;
+        assert!(matches!(
+            recipient.decrypt(&changed),
+            Err(E2eError::Decrypt)
+        ));
+
+        // Both ends derive the same key, so only the associated data stops a message being passed off as going the
+        // other way
+        let mut changed = payload;
+        std::mem::swap(&mut changed.sender_key, &mut changed.recipient_key);
+        assert!(matches!(
+            recipient.decrypt(&changed),
+            Err(E2eError::Decrypt)
+        ));
+        assert!(matches!(sender.decrypt(&changed), Err(E2eError::Decrypt)));
+    }
+
+    #[test]
+    fn low_order_keys_are_refused() {
+        let key_pair = KeyPair::generate();
+        // Points of small order (u = 0, u = 1 and a point of order 8), the shared secret with them is known to anyone
+        let low_order = [
+            [0; 32],
+            {
+                let mut key = [0; 32];
+                key[0] = 1;
+                key
+            },
+            [
+                0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f,
+                0xc4, 0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16,
+                0x5f, 0x49, 0xb8, 0x00,
+            ],
+        ];
+
+        for key in low_order {
+            let key = PublicKey(key);
+            assert!(matches!(key_pair.encrypt(key, "hi"), Err(E2eError::BadKey)));
+
+            // A message claiming to be from a low order key is refused the same way
+            let (_, recipient, mut payload) = sent("hi");
+            payload.sender_key = key;
+            assert!(matches!(recipient.decrypt(&payload), Err(E2eError::BadKey)));
+        }
+    }
+}
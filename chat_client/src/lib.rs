@@ -0,0 +1,301 @@
+//! Everything a client needs to talk to the server without a gui, connecting to the server, sending requests and
+//! receiving events. The gui client is built on this, bots and other frontends should be too.
+
+use std::{
+    io::{self, ErrorKind},
+    net::{
+        Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+    },
+    sync::{
+        mpsc::{self, Receiver, TryRecvError},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use chat_core::{
+    encryption::{EncryptedPayload, PublicKey},
+    message::MessageId,
+    read::ChatReader,
+    read_write_streams::ReadWriteStreams,
+    request::{Request, RequestError},
+    response::Response,
+    value::Value,
+    write::ChatWriter,
+};
+use thiserror::Error;
+
+pub mod chat_log;
+pub mod config;
+pub mod e2e;
+
+/// Address the server listens on by default
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:1234";
+/// Port the server connects back to by default, it is `read_port` in the config of the server
+pub const DEFAULT_READ_PORT: u16 = 4321;
+/// How often the connection is checked while waiting for the server to connect back
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("the server refused the connection: {0}")]
+    Refused(RequestError),
+    #[error("the server closed the connection before connecting back")]
+    Closed,
+    #[error("the server did not connect back within {0:?}")]
+    Timeout(Duration),
+    #[error("could not read the response of the server: {0}")]
+    Bincode(#[from] bincode::Error),
+}
+
+/// A connection to the server, cloning it gives another handle to the same connection
+#[derive(Debug, Clone)]
+pub struct ChatClient {
+    streams: ReadWriteStreams,
+    /// Clone of the read stream, the thread reading `Events` holds the lock on `streams.read` while it waits so this is
+    /// used to shut the stream down instead
+    read_handle: Arc<TcpStream>,
+}
+
+impl ChatClient {
+    pub fn builder() -> ChatClientBuilder {
+        ChatClientBuilder::default()
+    }
+    /// Connect to the server at `address` with the default settings, see `ChatClientBuilder::connect`
+    pub fn connect(address: impl ToSocketAddrs) -> Result<(Self, Events), ClientError> {
+        Self::builder().connect(address)
+    }
+    /// Send any request, the other methods are shorthands for this
+    pub fn request(&mut self, request: &Request) -> Result<(), bincode::Error> {
+        self.streams.write_data(request)
+    }
+    pub fn send_message(&mut self, payload: impl Into<Value>) -> Result<(), bincode::Error> {
+        self.request(&Request::SendMessage(payload.into()))
+    }
+    pub fn reply(
+        &mut self,
+        parent: MessageId,
+        payload: impl Into<Value>,
+    ) -> Result<(), bincode::Error> {
+        self.request(&Request::SendReply {
+            parent,
+            payload: payload.into(),
+        })
+    }
+    pub fn rename(&mut self, username: impl Into<Value>) -> Result<(), bincode::Error> {
+        self.request(&Request::ChangeUserName(username.into()))
+    }
+    /// The server answers with `Event::UserList`, the users in our room
+    pub fn list_users(&mut self) -> Result<(), bincode::Error> {
+        self.request(&Request::UserList)
+    }
+    /// Publish the public key others encrypt direct messages to us with
+    pub fn publish_key(&mut self, key: PublicKey) -> Result<(), bincode::Error> {
+        self.request(&Request::PublishKey(key))
+    }
+    /// Ask for the public key of a user, the server answers with `Event::PublicKey`
+    pub fn request_key(&mut self, user: usize) -> Result<(), bincode::Error> {
+        self.request(&Request::GetKey { user })
+    }
+    /// Send an encrypted direct message to the user with the id, see `e2e::KeyPair::encrypt`
+    pub fn send_encrypted(
+        &mut self,
+        to: usize,
+        payload: EncryptedPayload,
+    ) -> Result<(), bincode::Error> {
+        self.request(&Request::SendDirect {
+            to,
+            payload: Value::Encrypted(payload),
+        })
+    }
+    /// Move to another room, the server answers with the new room list
+    pub fn join_room(&mut self, room: &str) -> Result<(), bincode::Error> {
+        self.send_message(format!("/join {room}"))
+    }
+    /// Ask the server to link back to ours, see `Request::LinkBack`
+    pub fn link_back(
+        &mut self,
+        name: &str,
+        port: u16,
+        read_port: u16,
+        room: &str,
+    ) -> Result<(), bincode::Error> {
+        self.request(&Request::LinkBack {
+            name: name.to_owned(),
+            port,
+            read_port,
+            room: room.to_owned(),
+        })
+    }
+    /// Close the connection, `Events` ends once the server notices
+    pub fn disconnect(&self) {
+        let _ = self.read_handle.shutdown(Shutdown::Both);
+        let _ = self
+            .streams
+            .write
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .shutdown(Shutdown::Both);
+    }
+}
+
+pub struct ChatClientBuilder {
+    read_port: u16,
+    timeout: Duration,
+}
+
+impl Default for ChatClientBuilder {
+    fn default() -> Self {
+        Self {
+            read_port: DEFAULT_READ_PORT,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ChatClientBuilder {
+    /// The port the server connects back to, has to match `read_port` in the config of the server
+    pub fn read_port(mut self, read_port: u16) -> Self {
+        self.read_port = read_port;
+        self
+    }
+    /// How long to wait for the server to connect back
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Connect to the server at `address` and wait for it to connect back to `read_port`. Returns the client along
+    /// with the events the server sends, which are read on their own thread.
+    /// # Errors
+    /// Besides io errors, an error is returned if the server refuses the connection (for example because it is full),
+    /// closes it, or does not connect back in time.
+    pub fn connect(self, address: impl ToSocketAddrs) -> Result<(ChatClient, Events), ClientError> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no address to connect to"))?;
+
+        // The server connects back as soon as it accepts the connection, so the listener has to be bound first
+        let mut read_address = local_address(address)?;
+        read_address.set_port(self.read_port);
+        let listener = TcpListener::bind(read_address)?;
+
+        let stream = TcpStream::connect(address)?;
+        log::info!("connected to {address}");
+        let write = self.accept(&stream, listener)?;
+        log::info!("the server connected back to {read_address}");
+
+        let read_handle = Arc::new(stream.try_clone()?);
+        // The server writes to the stream we connected with and reads from the one it connected back to
+        let streams = ReadWriteStreams {
+            read: Arc::new(Mutex::new(stream)),
+            write: Arc::new(Mutex::new(write)),
+        };
+
+        Ok((
+            ChatClient {
+                streams: streams.clone(),
+                read_handle,
+            },
+            Events::start(streams),
+        ))
+    }
+    /// Wait for the server to connect back. A server that refuses the connection writes an error to `stream` instead
+    /// of connecting back, so `stream` is watched as well.
+    fn accept(&self, stream: &TcpStream, listener: TcpListener) -> Result<TcpStream, ClientError> {
+        listener.set_nonblocking(true)?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let deadline = Instant::now() + self.timeout;
+
+        let accepted = loop {
+            match listener.accept() {
+                Ok((accepted, _)) => break accepted,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                Err(error) => return Err(error.into()),
+            }
+
+            match stream.peek(&mut [0]) {
+                Ok(0) => return Err(ClientError::Closed),
+                // The server only writes before connecting back when it refuses the connection, but accept once more
+                // in case it connected back since the last try
+                Ok(_) => {
+                    if let Ok((accepted, _)) = listener.accept() {
+                        break accepted;
+                    }
+                    stream.set_read_timeout(None)?;
+                    return Err(match stream.try_clone()?.read_data::<Response>()? {
+                        Err(error) => ClientError::Refused(error),
+                        Ok(_) => ClientError::Closed,
+                    });
+                }
+                Err(error)
+                    if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(error) => return Err(error.into()),
+            }
+
+            if Instant::now() >= deadline {
+                return Err(ClientError::Timeout(self.timeout));
+            }
+        };
+
+        stream.set_read_timeout(None)?;
+        accepted.set_nonblocking(false)?;
+        Ok(accepted)
+    }
+}
+
+/// The local address a connection to `address` would come from. Connecting a udp socket sends nothing, it only picks
+/// the route.
+fn local_address(address: SocketAddr) -> Result<SocketAddr, io::Error> {
+    let unspecified = match address {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(unspecified)?;
+    socket.connect(address)?;
+    socket.local_addr()
+}
+
+/// The responses the server sends, in the order they were sent. Iterating blocks until the next response, the
+/// iterator ends once the connection is closed.
+pub struct Events {
+    rx: Receiver<Response>,
+}
+
+impl Events {
+    fn start(mut streams: ReadWriteStreams) -> Self {
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || loop {
+            match streams.read_data::<Response>() {
+                Ok(response) => {
+                    if tx.send(response).is_err() {
+                        break;
+                    }
+                }
+                Err(error) => {
+                    log::info!("connection to the server closed: {error}");
+                    break;
+                }
+            }
+        });
+
+        Self { rx }
+    }
+    /// The next response if there is one waiting, for frontends that cannot block. `TryRecvError::Disconnected`
+    /// means the connection is closed.
+    pub fn try_next(&self) -> Result<Response, TryRecvError> {
+        self.rx.try_recv()
+    }
+}
+
+impl Iterator for Events {
+    type Item = Response;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum CommandError {
+    #[error("unknown command: /{0}, try /help")]
+    Unknown(String),
+    #[error("usage: {0}")]
+    Usage(String),
+    #[error("you do not have permission to use /{0}")]
+    PermissionDenied(String),
+    #[error("no user named {0} is connected")]
+    UnknownUser(String),
+    #[error("bad room name: {0}")]
+    BadRoom(String),
+}
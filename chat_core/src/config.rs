@@ -1,16 +1,31 @@
 use std::{
-    fmt,
+    env, fmt,
     fs::{self, File},
     io::Write,
+    path::{Path, PathBuf},
 };
 
 use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+
+/// Environment variables starting with this override config values, levels are separated by `__` so
+/// `CHAT_NET__READ_PORT=4000` sets `read_port` in the `[net]` table.
+pub const ENV_PREFIX: &str = "CHAT_";
+/// Name of the config file that was used before each application had its own file name. Every application used the
+/// same name, so it is only looked for next to the binary where it cannot be the file of another application.
+pub const LEGACY_FILE_NAME: &str = "Config.toml";
+/// Key holding the version of a config file, files without it are version 0
+pub const VERSION_KEY: &str = "config_version";
 
 #[derive(Debug)]
 pub enum ConfigError {
     SerError(toml::ser::Error),
     DeError(toml::de::Error),
     IoError(std::io::Error),
+    /// Bad command line arguments
+    Args(String),
+    /// The config was loaded but some values do not make sense
+    Invalid(Vec<ValidationError>),
 }
 
 impl fmt::Display for ConfigError {
@@ -19,10 +34,72 @@ impl fmt::Display for ConfigError {
             ConfigError::DeError(error) => write!(f, "{error}"),
             ConfigError::IoError(error) => write!(f, "{error}"),
             ConfigError::SerError(error) => write!(f, "{error}"),
+            ConfigError::Args(error) => write!(f, "{error}"),
+            ConfigError::Invalid(errors) => {
+                write!(f, "invalid config:")?;
+                for error in errors {
+                    write!(f, "\n    {error}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A single problem found while validating a config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    /// TOML key path of the value, e.g. `username_guidelines.min_length`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Collects every problem in a config instead of stopping at the first one
+#[derive(Debug, Default)]
+pub struct Validator {
+    /// Key path of the table being validated
+    section: Vec<String>,
+    errors: Vec<ValidationError>,
+}
+
+impl Validator {
+    /// Record a problem with `key` (in the current section) if `valid` is false
+    pub fn check(&mut self, valid: bool, key: &str, message: impl Into<String>) {
+        if !valid {
+            let mut path = self.section.clone();
+            path.push(key.to_owned());
+            self.errors.push(ValidationError {
+                path: path.join("."),
+                message: message.into(),
+            });
+        }
+    }
+    /// Validate a table, keys checked inside it are prefixed with `name`
+    pub fn section(&mut self, name: &str, value: &impl Validate) {
+        self.section.push(name.to_owned());
+        value.validate(self);
+        self.section.pop();
+    }
+    pub fn finish(self) -> Result<(), ConfigError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(self.errors))
         }
     }
 }
 
+/// Implemented by config values that can check themselves, use `Validator::section()` to validate nested tables
+pub trait Validate {
+    fn validate(&self, validator: &mut Validator);
+}
+
 impl From<toml::ser::Error> for ConfigError {
     fn from(value: toml::ser::Error) -> Self {
         ConfigError::SerError(value)
@@ -39,19 +116,502 @@ impl From<std::io::Error> for ConfigError {
     }
 }
 
+/// Changes needed to bring a config file up to `version`
+pub struct Migration {
+    /// The version of the file after this migration
+    pub version: u32,
+    /// `(old, new)` key paths of values that were moved, e.g. `("net_config", "net")`
+    pub renames: &'static [(&'static str, &'static str)],
+}
+
 pub trait Config
 where
-    Self: Sized + for<'a> Deserialize<'a> + Serialize,
+    Self: Sized + Default + for<'a> Deserialize<'a> + Serialize,
 {
+    /// Name used for the default config locations, e.g. `server` is looked for at `./server.toml` and
+    /// `$XDG_CONFIG_HOME/chat/server.toml`
+    const NAME: &'static str;
+    /// The current version of the config, stored in files as `config_version`
+    const VERSION: u32 = 0;
+    /// Every migration in order of version, files older than `VERSION` are migrated when they are loaded
+    const MIGRATIONS: &'static [Migration] = &[];
+    /// The default config with every value explained, written by `--dump-default-config`
+    const TEMPLATE: &'static str;
+
+    /// Load the config from the first default location that exists, see `load_layered()`
     fn load() -> Result<Self, ConfigError> {
-        Ok(toml::from_str(&fs::read_to_string("Config.toml")?)?)
+        Self::load_layered(None)
+    }
+    /// Load the config from a single file, values missing from the file are the defaults
+    fn load_from_path(path: &Path) -> Result<Self, ConfigError> {
+        Self::validated(Self::read_file(path)?.try_into()?)
+    }
+    /// Read a config file as a table, migrating it to `VERSION` if it is older
+    fn read_file(path: &Path) -> Result<Table, ConfigError> {
+        let mut config = toml::from_str(&fs::read_to_string(path)?)?;
+        migrate(&mut config, Self::VERSION, Self::MIGRATIONS);
+        Ok(config)
+    }
+    /// Load the config in layers, starting with `Self::default()`, then the values in the config file and then the
+    /// values set with `CHAT_` environment variables. The config file is `path` if it is given (it has to exist),
+    /// otherwise the first of `default_paths()` that exists. If there is no config file only the defaults and
+    /// environment are used.
+    fn load_layered(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = Table::try_from(Self::default())?;
+
+        let path = match path {
+            Some(path) => Some(path.to_owned()),
+            None => Self::find(),
+        };
+        match path {
+            Some(path) => {
+                log::info!("loading {} config from {}", Self::NAME, path.display());
+                merge(&mut config, Self::read_file(&path)?);
+            }
+            None => log::info!("no {} config file found, using the defaults", Self::NAME),
+        }
+
+        apply_env(&mut config, env::vars());
+
+        Self::validated(config.try_into()?)
+    }
+    /// Check the values of the config, the default does not check anything. Every problem should be recorded so they
+    /// can all be fixed at once.
+    fn validate(&self, _validator: &mut Validator) {}
+    /// Run `validate()` and return the config if there were no problems
+    fn validated(self) -> Result<Self, ConfigError> {
+        let mut validator = Validator::default();
+        self.validate(&mut validator);
+        validator.finish()?;
+
+        Ok(self)
+    }
+    /// Where the config is looked for, in order
+    fn default_paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(format!("{}.toml", Self::NAME))];
+        if let Some(dir) = env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(Path::to_owned))
+        {
+            paths.push(dir.join(LEGACY_FILE_NAME));
+        }
+        paths.extend(xdg_config_dirs().into_iter().map(|dir| Self::path_in(&dir)));
+        paths
+    }
+    /// The first of `default_paths()` that exists
+    fn find() -> Option<PathBuf> {
+        Self::default_paths()
+            .into_iter()
+            .find(|path| path.is_file())
+    }
+    /// Where `write()` puts the config, the file that was found by `find()` or the user's config directory if there
+    /// is no config yet
+    fn write_path() -> PathBuf {
+        Self::find()
+            .or_else(|| xdg_config_dirs().first().map(|dir| Self::path_in(dir)))
+            .unwrap_or_else(|| PathBuf::from(format!("{}.toml", Self::NAME)))
+    }
+    fn path_in(dir: &Path) -> PathBuf {
+        dir.join("chat").join(format!("{}.toml", Self::NAME))
     }
     fn write(&self) -> Result<(), ConfigError> {
+        self.write_to_path(&Self::write_path())
+    }
+    fn write_to_path(&self, path: &Path) -> Result<(), ConfigError> {
         let s = toml::to_string(&self)?;
 
-        let mut f = File::create("Config.toml")?;
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let mut f = File::create(path)?;
         write!(f, "{s}")?;
 
         Ok(())
     }
 }
+
+/// The XDG config directories, the user's directory (`$XDG_CONFIG_HOME` or `~/.config`) first followed by
+/// `$XDG_CONFIG_DIRS` (or `/etc/xdg`)
+pub fn xdg_config_dirs() -> Vec<PathBuf> {
+    let non_empty = |var: &str| env::var_os(var).filter(|value| !value.is_empty());
+
+    let mut dirs = Vec::new();
+    if let Some(home) = non_empty("XDG_CONFIG_HOME") {
+        dirs.push(PathBuf::from(home));
+    } else if let Some(home) = non_empty("HOME") {
+        dirs.push(Path::new(&home).join(".config"));
+    }
+    match non_empty("XDG_CONFIG_DIRS") {
+        Some(system) => dirs.extend(env::split_paths(&system)),
+        None => dirs.push(PathBuf::from("/etc/xdg")),
+    }
+    dirs
+}
+
+/// Merge `other` into `base`, tables are merged key by key and every other value in `other` replaces the one in `base`
+pub fn merge(base: &mut Table, other: Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(other)) => merge(base, other),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Apply every migration newer than the `config_version` of `config`, then set its version to `version`. Moved values
+/// do not replace values that are already at the new path.
+pub fn migrate(config: &mut Table, version: u32, migrations: &[Migration]) {
+    let from = config
+        .get(VERSION_KEY)
+        .and_then(Value::as_integer)
+        .and_then(|version| u32::try_from(version).ok())
+        .unwrap_or(0);
+
+    if from > version {
+        log::warn!(
+            "config version {from} is newer than {version}, some values might not be understood"
+        );
+        return;
+    }
+
+    for migration in migrations
+        .iter()
+        .filter(|migration| migration.version > from)
+    {
+        log::info!("migrating config to version {}", migration.version);
+        for (old, new) in migration.renames {
+            let Some(value) = remove_path(config, old) else {
+                continue;
+            };
+            if get_path(config, new).is_some() {
+                log::warn!("{old} was renamed to {new} and both are set, ignoring {old}");
+            } else {
+                log::info!("{old} was renamed to {new}");
+                insert_path(config, new, value);
+            }
+        }
+    }
+
+    config.insert(VERSION_KEY.to_owned(), Value::Integer(version.into()));
+}
+
+fn get_path<'a>(config: &'a Table, path: &str) -> Option<&'a Value> {
+    match path.split_once('.') {
+        Some((key, rest)) => get_path(config.get(key)?.as_table()?, rest),
+        None => config.get(path),
+    }
+}
+
+fn remove_path(config: &mut Table, path: &str) -> Option<Value> {
+    match path.split_once('.') {
+        Some((key, rest)) => match config.get_mut(key)? {
+            Value::Table(table) => remove_path(table, rest),
+            _ => None,
+        },
+        None => config.remove(path),
+    }
+}
+
+/// Insert `value` at `path`, creating the tables on the way that do not exist
+fn insert_path(config: &mut Table, path: &str, value: Value) {
+    match path.split_once('.') {
+        Some((key, rest)) => {
+            let table = config
+                .entry(key)
+                .or_insert_with(|| Value::Table(Table::new()));
+            if let Value::Table(table) = table {
+                insert_path(table, rest, value);
+            }
+        }
+        None => {
+            config.insert(path.to_owned(), value);
+        }
+    }
+}
+
+/// Set the values from `CHAT_` prefixed environment variables in `config`. The value of a variable is parsed as TOML
+/// (so `4000`, `true` and `["a", "b"]` work) and is used as a string if it is not valid TOML.
+pub fn apply_env(config: &mut Table, vars: impl Iterator<Item = (String, String)>) {
+    for (name, raw) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let path = path.to_lowercase();
+        let keys = path.split("__").collect::<Vec<_>>();
+
+        let value = toml::from_str::<Table>(&format!("value = {raw}"))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or(Value::String(raw));
+
+        // Values can only be set in tables that exist, and tables cannot be replaced by values
+        let Some((last, tables)) = keys.split_last().filter(|_| !keys.contains(&"")) else {
+            log::debug!("ignoring {name}, it has an empty key");
+            continue;
+        };
+        let mut table = Some(&mut *config);
+        for key in tables {
+            table = table.and_then(|table| match table.get_mut(*key) {
+                Some(Value::Table(inner)) => Some(inner),
+                _ => None,
+            });
+        }
+        match table {
+            Some(table) if !table.get(*last).is_some_and(Value::is_table) => {
+                log::debug!("{name} overrides {}", keys.join("."));
+                table.insert((*last).to_owned(), value);
+            }
+            _ => log::debug!("ignoring {name}, it does not match a config value"),
+        }
+    }
+}
+
+/// The config options given on the command line
+#[derive(Debug, Default)]
+pub struct ConfigArgs {
+    /// The path given with `--config <path>` (or `--config=<path>`)
+    pub path: Option<PathBuf>,
+    /// `--dump-default-config` was given, the default config template should be printed instead of running
+    pub dump_default: bool,
+}
+
+impl ConfigArgs {
+    /// Parse the command line arguments, the first argument should be the program name like with `std::env::args()`
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut args = args.skip(1);
+        let mut parsed = Self::default();
+
+        while let Some(arg) = args.next() {
+            if arg == "--config" {
+                parsed.path = Some(PathBuf::from(args.next().ok_or_else(|| {
+                    ConfigError::Args("--config needs a path, e.g. --config server.toml".to_owned())
+                })?));
+            } else if let Some(value) = arg.strip_prefix("--config=") {
+                parsed.path = Some(PathBuf::from(value));
+            } else if arg == "--dump-default-config" {
+                parsed.dump_default = true;
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(toml: &str) -> Table {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn merge_replaces_values_and_keeps_the_rest_of_tables() {
+        let mut base = table("a = 1\n[net]\nip = \"0.0.0.0:1\"\nread_port = 1");
+        merge(
+            &mut base,
+            table("a = 2\n[net]\nread_port = 2\n[new]\nb = true"),
+        );
+
+        assert_eq!(
+            base,
+            table("a = 2\n[net]\nip = \"0.0.0.0:1\"\nread_port = 2\n[new]\nb = true")
+        );
+    }
+
+    #[test]
+    fn merge_lets_a_value_replace_a_table() {
+        let mut base = table("[a]\nb = 1");
+        merge(&mut base, table("a = 1"));
+        assert_eq!(base, table("a = 1"));
+    }
+
+    #[test]
+    fn env_sets_nested_values_as_toml() {
+        let mut config = table("[net]\nread_port = 1\n[system]\nthreads = 1\nname = \"a\"");
+        apply_env(
+            &mut config,
+            vars(&[
+                ("CHAT_NET__READ_PORT", "4000"),
+                ("CHAT_SYSTEM__THREADS", "[1, 2]"),
+                ("CHAT_SYSTEM__NAME", "not toml"),
+                ("PATH", "/bin"),
+            ]),
+        );
+
+        assert_eq!(
+            config,
+            table("[net]\nread_port = 4000\n[system]\nthreads = [1, 2]\nname = \"not toml\"")
+        );
+    }
+
+    #[test]
+    fn env_cannot_create_or_replace_tables() {
+        let mut config = table("[net]\nread_port = 1");
+        apply_env(
+            &mut config,
+            vars(&[("CHAT_NET", "1"), ("CHAT_MISSING__KEY", "1")]),
+        );
+        assert_eq!(config, table("[net]\nread_port = 1"));
+    }
+
+    #[test]
+    fn env_with_empty_keys_is_ignored() {
+        let mut config = table("[net]\nread_port = 1");
+        apply_env(
+            &mut config,
+            vars(&[("CHAT_", "1"), ("CHAT_NET__", "1"), ("CHAT___NET", "1")]),
+        );
+        assert_eq!(config, table("[net]\nread_port = 1"));
+    }
+
+    #[test]
+    fn args_take_the_config_path_either_way() {
+        let args = |args: &[&str]| ConfigArgs::parse(args.iter().map(|arg| arg.to_string()));
+
+        assert_eq!(
+            args(&["server", "--config", "a.toml"]).unwrap().path,
+            Some(PathBuf::from("a.toml"))
+        );
+        assert_eq!(
+            args(&["server", "--config=b.toml"]).unwrap().path,
+            Some(PathBuf::from("b.toml"))
+        );
+        assert!(
+            args(&["server", "--dump-default-config"])
+                .unwrap()
+                .dump_default
+        );
+        assert!(matches!(
+            args(&["server", "--config"]),
+            Err(ConfigError::Args(_))
+        ));
+    }
+
+    #[test]
+    fn legacy_file_is_only_looked_for_next_to_the_binary() {
+        let paths = <TestConfig as Config>::default_paths();
+
+        assert_eq!(paths[0], PathBuf::from("test.toml"));
+        assert!(!paths.contains(&PathBuf::from(LEGACY_FILE_NAME)));
+        assert_eq!(
+            paths[1],
+            env::current_exe()
+                .unwrap()
+                .parent()
+                .unwrap()
+                .join(LEGACY_FILE_NAME)
+        );
+    }
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            renames: &[("net_config", "net")],
+        },
+        Migration {
+            version: 2,
+            renames: &[("net.port", "net.read_port")],
+        },
+    ];
+
+    #[test]
+    fn files_without_a_version_get_every_migration() {
+        let mut config = table(
+            "[net_config]
+port = 1
+ip = \"a\"",
+        );
+        migrate(&mut config, 2, MIGRATIONS);
+        assert_eq!(
+            config,
+            table("config_version = 2\n[net]\nread_port = 1\nip = \"a\"")
+        );
+    }
+
+    #[test]
+    fn only_newer_migrations_are_applied() {
+        // Version 1 already uses `net`, so a leftover `net_config` is not moved
+        let mut config = table("config_version = 1\nnet_config = 1\n[net]\nport = 1");
+        migrate(&mut config, 2, MIGRATIONS);
+        assert_eq!(
+            config,
+            table("config_version = 2\nnet_config = 1\n[net]\nread_port = 1")
+        );
+    }
+
+    #[test]
+    fn renames_do_not_replace_values_at_the_new_path() {
+        let mut config = table("[net]\nport = 1\nread_port = 2");
+        migrate(&mut config, 2, MIGRATIONS);
+        assert_eq!(config, table("config_version = 2\n[net]\nread_port = 2"));
+    }
+
+    #[test]
+    fn newer_versions_are_left_alone() {
+        let mut config = table("config_version = 7\n[net_config]\nport = 1");
+        let unchanged = config.clone();
+        migrate(&mut config, 2, MIGRATIONS);
+        assert_eq!(config, unchanged);
+    }
+
+    #[test]
+    fn versions_that_are_not_a_number_count_as_no_version() {
+        for version in ["\"two\"", "-1", "1.5"] {
+            let mut config = table(&format!(
+                "config_version = {version}\n[net_config]\nport = 1"
+            ));
+            migrate(&mut config, 2, MIGRATIONS);
+            assert_eq!(
+                config,
+                table("config_version = 2\n[net]\nread_port = 1"),
+                "config_version = {version}"
+            );
+        }
+    }
+
+    #[test]
+    fn missing_values_are_the_defaults() {
+        let path = env::temp_dir().join(format!("chat-config-test-{}.toml", std::process::id()));
+        fs::write(&path, "name = \"b\"\nunknown = 1").unwrap();
+        let config = TestConfig::load_from_path(&path);
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.name, "b");
+        assert_eq!(config.port, 1234);
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(default)]
+    struct TestConfig {
+        name: String,
+        port: u16,
+    }
+
+    impl Default for TestConfig {
+        fn default() -> Self {
+            Self {
+                name: "a".to_owned(),
+                port: 1234,
+            }
+        }
+    }
+
+    impl Config for TestConfig {
+        const NAME: &'static str = "test";
+        const TEMPLATE: &'static str = "";
+    }
+}
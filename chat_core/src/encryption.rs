@@ -0,0 +1,30 @@
+//! What the server sees of end-to-end encrypted direct messages. Clients encrypt with XChaCha20-Poly1305 using a key
+//! both ends derive from their X25519 key pairs (see `chat_client::e2e`), the server only stores and routes the bytes
+//! along with the public keys users publish.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// An X25519 public key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PublicKey(pub [u8; 32]);
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A message only the sender and the recipient can read. Both public keys are included so either end can derive the
+/// key, which also lets the sender read back what it sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedPayload {
+    pub sender_key: PublicKey,
+    pub recipient_key: PublicKey,
+    pub nonce: [u8; 24],
+    pub ciphertext: Vec<u8>,
+}
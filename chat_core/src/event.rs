@@ -0,0 +1,69 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encryption::PublicKey,
+    message::{Message, MessageId},
+    user::User,
+    value::Value,
+};
+
+/// Something that happened on the server, every successful `Response` carries one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    /// A new message was sent
+    Message(Message),
+    /// The message mentions the user this is sent to, sent even if the user is in another room
+    Mention(Message),
+    /// The payload of an earlier message was replaced
+    MessageEdited {
+        id: MessageId,
+        payload: Value,
+        edited_at: DateTime<Utc>,
+    },
+    /// An earlier message was deleted, clients should show a tombstone in its place
+    MessageDeleted { id: MessageId },
+    /// `from` reacted to a message with `emoji`, the count of the reaction goes up by one
+    ReactionAdded {
+        id: MessageId,
+        emoji: String,
+        from: User,
+    },
+    /// `from` took back their reaction, the count of the reaction goes down by one
+    ReactionRemoved {
+        id: MessageId,
+        emoji: String,
+        from: User,
+    },
+    /// The rooms the user is in or has been in, `current` is the room the user is in
+    RoomList {
+        current: String,
+        rooms: Vec<RoomInfo>,
+    },
+    /// The amount of unread messages in a room the user is not in changed
+    RoomUnread { room: String, unread: usize },
+    /// The users in `room`, sorted by username
+    UserList { room: String, users: Vec<User> },
+    /// `user` started typing in the room
+    TypingStarted { user: User },
+    /// `user` stopped typing, also sent when the server has not heard from them for a while
+    TypingStopped { user: User },
+    /// The public key `user` published for encrypted direct messages, `None` if it has not published one
+    PublicKey { user: usize, key: Option<PublicKey> },
+}
+
+/// A room as shown in the room list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    /// How many users are in the room
+    pub users: usize,
+    /// How many messages in the room the user has not read
+    pub unread: usize,
+}
+
+impl From<Message> for Event {
+    fn from(value: Message) -> Self {
+        Event::Message(value)
+    }
+}
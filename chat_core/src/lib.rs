@@ -1,4 +1,7 @@
+pub mod command;
 pub mod config;
+pub mod encryption;
+pub mod event;
 pub mod guidelines;
 pub mod message;
 pub mod read;
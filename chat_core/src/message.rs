@@ -1,9 +1,15 @@
 use std::fmt;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{guidelines::AgainstGuidelines, user::User, value::Value};
+use crate::{
+    config::{Validate, Validator},
+    guidelines::AgainstGuidelines,
+    user::User,
+    value::Value,
+};
 
 #[derive(Debug, Error, Serialize, Deserialize)]
 pub enum MessageError {
@@ -17,9 +23,21 @@ pub enum MessageError {
     TrailingWhitespace,
     #[error("messages can only be text")]
     TextOnly,
+    #[error("a reaction has to be a single word of at most {MAX_REACTION_LENGTH} bytes")]
+    BadReaction,
+    #[error("message already has the most reactions allowed")]
+    TooManyReactions,
+    #[error("encrypted messages are not allowed on this server")]
+    Encrypted,
+    #[error("encrypted messages can only be sent as direct messages")]
+    EncryptedNotDirect,
 }
 
+/// Max length of a reaction in bytes, enough for an emoji with modifiers or a short name like `:thumbsup:`
+pub const MAX_REACTION_LENGTH: usize = 32;
+
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct MessageGuidelines {
     message_size: usize,
     just_whitespace: bool,
@@ -27,6 +45,10 @@ pub struct MessageGuidelines {
     leading_whitespace: bool,
     empty: bool,
     text_only: bool,
+    /// Max amount of different reactions a single message can have
+    max_reactions: usize,
+    /// Allow end-to-end encrypted direct messages, the checks that need the text are skipped for them
+    allow_encrypted: bool,
 }
 
 impl Default for MessageGuidelines {
@@ -38,6 +60,8 @@ impl Default for MessageGuidelines {
             leading_whitespace: false,
             empty: false,
             text_only: true,
+            max_reactions: 20,
+            allow_encrypted: true,
         }
     }
 }
@@ -61,18 +85,98 @@ impl MessageGuidelines {
     pub fn text_only(&self) -> bool {
         self.text_only
     }
+    pub fn max_reactions(&self) -> usize {
+        self.max_reactions
+    }
+    pub fn allow_encrypted(&self) -> bool {
+        self.allow_encrypted
+    }
+    /// Check that a reaction is not empty, has no whitespace and is at most `MAX_REACTION_LENGTH` bytes
+    pub fn check_reaction(&self, reaction: &str) -> Result<(), MessageError> {
+        if reaction.is_empty()
+            || reaction.len() > MAX_REACTION_LENGTH
+            || reaction.chars().any(char::is_whitespace)
+        {
+            return Err(MessageError::BadReaction);
+        }
+
+        Ok(())
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Validate for MessageGuidelines {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            self.message_size > 0,
+            "message_size",
+            "must be more than 0 or no message could be sent",
+        );
+    }
+}
+
+/// How a message should be presented to the clients that receive it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageKind {
+    /// A regular chat message
+    #[default]
+    Text,
+    /// An action describing the sender, e.g. `/me waves`
+    Action,
+    /// A message that was only sent to specific users
+    Direct,
+}
+
+/// An emoji (or any short word) and how many users reacted with it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+}
+
+/// Id the server gives every message it accepts, ids only ever go up so a higher id is a newer message
+pub type MessageId = u64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    /// `None` until the server accepts the message
+    id: Option<MessageId>,
+    /// When the server accepted the message, `None` until then
+    timestamp: Option<DateTime<Utc>>,
     from: User,
     // to: Vec<User>,
     payload: Value,
+    kind: MessageKind,
+    /// The room this message was sent in, `None` means it is for every room
+    room: Option<String>,
+    /// The message this is a reply to
+    reply_to: Option<MessageId>,
+    /// Reactions in the order they were first added
+    reactions: Vec<Reaction>,
+    /// Ids of the users mentioned with `@username`, filled in by the server
+    mentions: Vec<usize>,
+    /// When the payload was last edited
+    edited_at: Option<DateTime<Utc>>,
+    /// A deleted message is kept as a tombstone without its payload
+    deleted: bool,
 }
 
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}: {}", self.from, self.payload)
+        if let Some(room) = &self.room {
+            write!(f, "[#{room}] ")?;
+        }
+        if self.deleted {
+            return write!(f, "{}: (message deleted)", self.from);
+        }
+        match self.kind {
+            MessageKind::Text => write!(f, "{}: {}", self.from, self.payload),
+            MessageKind::Action => write!(f, "* {} {}", self.from, self.payload),
+            MessageKind::Direct => write!(f, "{} (whisper): {}", self.from, self.payload),
+        }?;
+        if self.edited_at.is_some() {
+            write!(f, " (edited)")?;
+        }
+        Ok(())
     }
 }
 
@@ -80,12 +184,104 @@ impl Message {
     pub fn builder() -> MessageBuilder {
         MessageBuilder::default()
     }
+    pub fn id(&self) -> Option<MessageId> {
+        self.id
+    }
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        self.timestamp
+    }
+    /// Give the message its id and timestamp, done by the server when it accepts the message
+    pub fn stamp(&mut self, id: MessageId, timestamp: DateTime<Utc>) {
+        self.id = Some(id);
+        self.timestamp = Some(timestamp);
+    }
+    pub fn from(&self) -> &User {
+        &self.from
+    }
+    pub fn payload(&self) -> &Value {
+        &self.payload
+    }
+    pub fn kind(&self) -> MessageKind {
+        self.kind
+    }
+    pub fn room(&self) -> Option<&str> {
+        self.room.as_deref()
+    }
+    pub fn reply_to(&self) -> Option<MessageId> {
+        self.reply_to
+    }
+    pub fn mentions(&self) -> &[usize] {
+        &self.mentions
+    }
+    /// Set the ids of the users mentioned in the message, done by the server when it accepts the message
+    pub fn set_mentions(&mut self, mentions: Vec<usize>) {
+        self.mentions = mentions;
+    }
+    pub fn reactions(&self) -> &[Reaction] {
+        &self.reactions
+    }
+    /// Count one more of `emoji`
+    pub fn add_reaction(&mut self, emoji: &str) {
+        match self
+            .reactions
+            .iter_mut()
+            .find(|reaction| reaction.emoji == emoji)
+        {
+            Some(reaction) => reaction.count += 1,
+            None => self.reactions.push(Reaction {
+                emoji: emoji.to_owned(),
+                count: 1,
+            }),
+        }
+    }
+    /// Count one less of `emoji`, the reaction is removed once nobody has reacted with it
+    pub fn remove_reaction(&mut self, emoji: &str) {
+        if let Some(reaction) = self
+            .reactions
+            .iter_mut()
+            .find(|reaction| reaction.emoji == emoji)
+        {
+            reaction.count = reaction.count.saturating_sub(1);
+        }
+        self.reactions.retain(|reaction| reaction.count > 0);
+    }
+    pub fn edited_at(&self) -> Option<DateTime<Utc>> {
+        self.edited_at
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+    /// Replace the payload of a message that has not been sent yet, use `edit()` for messages that have been
+    pub fn set_payload(&mut self, payload: Value) {
+        self.payload = payload;
+    }
+    /// Replace the payload, the guidelines should be checked on the new payload first
+    pub fn edit(&mut self, payload: Value, edited_at: DateTime<Utc>) {
+        self.payload = payload;
+        self.edited_at = Some(edited_at);
+    }
+    /// Turn the message into a tombstone, the payload is thrown away
+    pub fn delete(&mut self) {
+        self.payload = Value::String(String::new());
+        self.reactions.clear();
+        self.deleted = true;
+    }
 }
 
 impl AgainstGuidelines<MessageGuidelines> for Message {
     type Error = MessageError;
 
     fn against_guidelines(self, guidelines: &MessageGuidelines) -> Result<Self, Self::Error> {
+        // The server cannot read encrypted messages, so only what it can see is checked
+        if let Value::Encrypted(_) = &self.payload {
+            if !guidelines.allow_encrypted() {
+                return Err(MessageError::Encrypted);
+            } else if self.kind != MessageKind::Direct {
+                return Err(MessageError::EncryptedNotDirect);
+            }
+            return Ok(self);
+        }
+
         if let Value::String(text_message) = &self.payload {
             // A message cannot be empty but the message is empty
             if !guidelines.empty() && text_message.is_empty() {
@@ -101,7 +297,7 @@ impl AgainstGuidelines<MessageGuidelines> for Message {
             }
             // A message cannot have trailing whitespace but the last character is whitespace
             else if !guidelines.trailing_whitespace()
-                && text_message.chars().rev().next().unwrap().is_whitespace()
+                && text_message.chars().last().unwrap().is_whitespace()
             {
                 log::debug!("message cannot have trailing whitespace but has trailing whitespace");
                 return Err(MessageError::TrailingWhitespace);
@@ -128,6 +324,9 @@ pub struct MessageBuilder {
     from: Option<User>,
     // to: Option<Vec<User>>,
     payload: Option<Value>,
+    kind: MessageKind,
+    room: Option<String>,
+    reply_to: Option<MessageId>,
 }
 
 impl MessageBuilder {
@@ -143,11 +342,32 @@ impl MessageBuilder {
         self.payload = Some(payload);
         self
     }
+    pub fn kind(mut self, kind: MessageKind) -> Self {
+        self.kind = kind;
+        self
+    }
+    pub fn room(mut self, room: Option<String>) -> Self {
+        self.room = room;
+        self
+    }
+    pub fn reply_to(mut self, parent: Option<MessageId>) -> Self {
+        self.reply_to = parent;
+        self
+    }
     /// Will panic if you did not set all values
     pub fn build(self) -> Message {
         Message {
+            id: None,
+            timestamp: None,
             from: self.from.unwrap(),
             /* to: self.to.unwrap(), */ payload: self.payload.unwrap(),
+            kind: self.kind,
+            room: self.room,
+            reply_to: self.reply_to,
+            reactions: Vec::new(),
+            mentions: Vec::new(),
+            edited_at: None,
+            deleted: false,
         }
     }
 }
@@ -1,6 +1,7 @@
 use std::{
+    io,
     net::TcpStream,
-    sync::{Arc, Mutex}, io,
+    sync::{Arc, Mutex},
 };
 
 use bincode::{DefaultOptions, Options};
@@ -26,7 +27,7 @@ impl ConnectPeerStream for TcpStream {
     /// Create a `ReadWriteStream` from a given `TcpStream`. The given stream will be used as the writing port, using that streams address
     /// (with the port changed to `port`) this method attempts to connect to a `TcpStream` for reading.
     /// # Errors
-    /// This method will return an error under two circumstances, first if it fails to get the address of `self`, and two if it fails to 
+    /// This method will return an error under two circumstances, first if it fails to get the address of `self`, and two if it fails to
     /// connect to the new address created.
     fn connect_peer_stream(self, port: u16) -> Result<ReadWriteStreams, io::Error> {
         log::info!("creating a new stream");
@@ -1,7 +1,15 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{message::MessageError, user::UsernameError, value::Value};
+use crate::{
+    command::CommandError,
+    encryption::PublicKey,
+    message::{MessageError, MessageId},
+    user::UsernameError,
+    value::Value,
+};
 
 #[derive(Error, Debug, Serialize, Deserialize)]
 pub enum RequestError {
@@ -15,14 +23,108 @@ pub enum RequestError {
     Username(UsernameError),
     #[error("bad message: {0}")]
     Message(MessageError),
+    #[error("{0}")]
+    Command(CommandError),
+    #[error("sending messages too fast, retry in {retry_after_ms}ms")]
+    RateLimited { retry_after_ms: u64 },
+    #[error("connection refused: {0}")]
+    ConnectionRefused(ConnectionRefused),
+    #[error("{0}")]
+    ServerShutdown(ShutdownNotice),
+    #[error("there is no message #{0}")]
+    UnknownMessage(MessageId),
+    #[error("message #{0} is not yours")]
+    NotAuthor(MessageId),
+    /// A plugin on the server would not let the message through, the reason comes from the plugin
+    #[error("message rejected: {0}")]
+    Rejected(String),
+    #[error("no user with the id {0} is connected")]
+    UnknownUserId(usize),
+}
+
+/// Why the server would not accept a connection
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum ConnectionRefused {
+    #[error("the server is full")]
+    ServerFull,
+    #[error("too many connections from your ip address")]
+    TooManyFromIp,
+    #[error("your ip address is not allowed to connect")]
+    Blocked,
+}
+
+impl From<CommandError> for RequestError {
+    fn from(value: CommandError) -> Self {
+        RequestError::Command(value)
+    }
+}
+
+/// Sent to every client when the server is shutting down
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ShutdownNotice {
+    pub reason: Option<String>,
+    /// Roughly how many seconds until the server is back up
+    pub restart_eta_secs: Option<u64>,
+}
+
+impl fmt::Display for ShutdownNotice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the server is shutting down")?;
+        if let Some(reason) = &self.reason {
+            write!(f, ": {reason}")?;
+        }
+        if let Some(eta) = self.restart_eta_secs {
+            write!(f, " (back in about {eta} seconds)")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Request {
     /// Treat the payload as a message
     SendMessage(Value),
+    /// Send the payload as a reply to the message with the id `parent`, which has to be in the same room
+    SendReply { parent: MessageId, payload: Value },
     /// Treat the payload as a new username
     ChangeUserName(Value),
-    /// Give the client a List of users connected to server
+    /// Give the client a List of users in its room
     UserList,
+    /// Replace the payload of a message, only the author or a moderator can do this
+    EditMessage { id: MessageId, new_payload: Value },
+    /// Delete a message, only the author or a moderator can do this
+    DeleteMessage { id: MessageId },
+    /// React to a message with an emoji, reacting twice with the same emoji does nothing
+    React {
+        message_id: MessageId,
+        emoji: String,
+    },
+    /// Take back a reaction
+    Unreact {
+        message_id: MessageId,
+        emoji: String,
+    },
+    /// Every message in `room` up to and including `up_to` has been read
+    MarkRead { room: String, up_to: MessageId },
+    /// Give the client the rooms it has been in with their unread counts
+    RoomList,
+    /// The user is typing, should be sent again every few seconds while they keep typing or it will expire
+    TypingStarted,
+    /// The user stopped typing (or cleared what they typed)
+    TypingStopped,
+    /// Publish the public key others encrypt direct messages to this user with, replaces the one published before
+    PublishKey(PublicKey),
+    /// Ask for the public key of the user with the id, answered with `Event::PublicKey`
+    GetKey { user: usize },
+    /// Send the payload as a direct message to the user with the id. Encrypted messages are sent this way since a key
+    /// belongs to one user, usernames do not have to be unique.
+    SendDirect { to: usize, payload: Value },
+    /// Sent by the federation link of the server named `name` once it is in `room`, asks this server to link back to
+    /// it at `port` and `read_port` of the address the link connected from so the room is relayed both ways
+    LinkBack {
+        name: String,
+        port: u16,
+        read_port: u16,
+        room: String,
+    },
 }
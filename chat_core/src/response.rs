@@ -1,3 +1,3 @@
-use crate::{message::Message, request::RequestError};
+use crate::{event::Event, request::RequestError};
 
-pub type Response = Result<Message, RequestError>;
+pub type Response = Result<Event, RequestError>;
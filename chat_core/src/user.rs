@@ -9,7 +9,11 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use toml::value::Array;
 
-use crate::{guidelines::AgainstGuidelines, value::Value};
+use crate::{
+    config::{Validate, Validator},
+    guidelines::AgainstGuidelines,
+    value::Value,
+};
 
 // To have the names loaded at runtime since (A) there is not need to edit them, and (B) there is not a good place to
 // load them in.
@@ -25,6 +29,7 @@ lazy_static! {
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct UsernameGuidelines {
     max_length: usize,
     min_length: usize,
@@ -58,6 +63,24 @@ impl UsernameGuidelines {
     }
 }
 
+impl Validate for UsernameGuidelines {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            self.max_length > 0,
+            "max_length",
+            "must be more than 0 or no username would be allowed",
+        );
+        validator.check(
+            self.min_length <= self.max_length,
+            "min_length",
+            format!(
+                "is more than max_length ({} > {}), no username would be allowed",
+                self.min_length, self.max_length
+            ),
+        );
+    }
+}
+
 #[derive(Debug, Error, Serialize, Deserialize)]
 pub enum UsernameError {
     #[error("tried to change username to non-text data")]
@@ -120,11 +143,26 @@ impl AgainstGuidelines<UsernameGuidelines> for Username {
     }
 }
 
+/// Who a user is sent by, only messages from clients have an id that is a client key
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Origin {
+    /// A connected client, the id of the user is its key
+    #[default]
+    Client,
+    /// The server itself, e.g. telling a room that someone joined
+    Server,
+    /// A bot posting through the http api or a plugin
+    Integration,
+    /// A user of the linked server with the name, relayed by federation
+    Federated(String),
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
     username: Username,
     id: usize,
     addresses: Option<(SocketAddr, SocketAddr)>,
+    origin: Origin,
 }
 
 impl fmt::Display for User {
@@ -163,6 +201,13 @@ impl User {
     pub fn addrs(&self) -> &Option<(SocketAddr, SocketAddr)> {
         &self.addresses
     }
+    pub fn origin(&self) -> &Origin {
+        &self.origin
+    }
+    /// The client key of the user, `None` if it is not sent by a client
+    pub fn key(&self) -> Option<usize> {
+        (self.origin == Origin::Client).then_some(self.id)
+    }
 }
 
 #[derive(Default)]
@@ -170,6 +215,7 @@ pub struct UserBuilder {
     username: Username,
     id: usize,
     addresses: Option<(SocketAddr, SocketAddr)>,
+    origin: Origin,
 }
 
 impl UserBuilder {
@@ -185,11 +231,16 @@ impl UserBuilder {
         self.addresses = addresses;
         self
     }
+    pub fn origin(mut self, origin: Origin) -> UserBuilder {
+        self.origin = origin;
+        self
+    }
     pub fn build(self) -> User {
         User {
             username: self.username,
             id: self.id,
             addresses: self.addresses,
+            origin: self.origin,
         }
     }
 }
@@ -3,6 +3,8 @@ use std::fmt;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::encryption::EncryptedPayload;
+
 #[derive(Error, Debug, Serialize, Deserialize)]
 pub enum ValueError {
     #[error("cannot convert value to {0}")]
@@ -17,6 +19,23 @@ pub enum Value {
     Boolean(bool),
     Image(Vec<u8>),
     File(Vec<u8>),
+    /// An end-to-end encrypted direct message, the server routes it without being able to read it
+    Encrypted(EncryptedPayload),
+}
+
+impl Value {
+    /// What kind of value this is, e.g. `"string"` or `"image"`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "int",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "bool",
+            Value::Image(_) => "image",
+            Value::File(_) => "file",
+            Value::Encrypted(_) => "encrypted",
+        }
+    }
 }
 
 impl fmt::Display for Value {
@@ -28,6 +47,7 @@ impl fmt::Display for Value {
             Value::Boolean(bool) => write!(f, "{bool}"),
             Value::Image(_) => todo!(),
             Value::File(_) => todo!(),
+            Value::Encrypted(_) => write!(f, "<encrypted message>"),
         }
     }
 }
@@ -73,6 +93,7 @@ impl TryFrom<Value> for String {
             Value::Boolean(_) => Err(ValueError::CannotConvertValue("bool".to_owned())),
             Value::Image(_) => Err(ValueError::CannotConvertValue("image".to_owned())),
             Value::File(_) => Err(ValueError::CannotConvertValue("file".to_owned())),
+            Value::Encrypted(_) => Err(ValueError::CannotConvertValue("encrypted".to_owned())),
         }
     }
 }
@@ -1,9 +1,6 @@
-use std::{
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
-};
+use std::{path::PathBuf, process};
 
-use chat_core::read_write_streams::ReadWriteStreams;
+use chat_client::{ChatClient, DEFAULT_ADDRESS};
 use egui::CentralPanel;
 
 use crate::{chat::Chat, config::gui::ConfigGui};
@@ -15,22 +12,33 @@ pub struct App {
 }
 
 impl App {
-    pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
-        let read = TcpStream::connect("127.0.0.1:1234").unwrap();
-
-        let mut write_port = read.local_addr().unwrap();
-        write_port.set_port(4321);
-
-        let write = TcpListener::bind(write_port).unwrap().accept().unwrap().0;
-
-        let client_streams =
-            ReadWriteStreams { read: Arc::new(Mutex::new(read)), write: Arc::new(Mutex::new(write)) };
-
-        eprintln!("Estabilished Connection: {client_streams:#?}");
+    /// `config_path` is the path given with `--config`
+    pub fn new(_cc: &eframe::CreationContext<'_>, config_path: Option<PathBuf>) -> Self {
+        let (client, events) = match ChatClient::connect(DEFAULT_ADDRESS) {
+            Ok(connection) => connection,
+            Err(error) => {
+                eprintln!("Could not connect to the server: {error}");
+                process::exit(1);
+            }
+        };
+
+        eprintln!("Estabilished Connection: {client:#?}");
+
+        let config = match ConfigGui::new(client.clone(), config_path) {
+            Ok(config) => config,
+            Err(error) => {
+                eprintln!("Could not load config: {error}");
+                process::exit(1);
+            }
+        };
+        let display = config
+            .config()
+            .map(|config| config.display.clone())
+            .unwrap_or_default();
 
         Self {
-            chat: Chat::new(client_streams.clone()),
-            config: ConfigGui::new(client_streams).unwrap(),
+            chat: Chat::new(client, events, display),
+            config,
         }
     }
 }
@@ -41,9 +49,15 @@ impl eframe::App for App {
         ctx.request_repaint();
 
         CentralPanel::default().show(ctx, |_ui| {
-            self.config.update_gui(ctx).unwrap();
-
-            self.chat.update_gui(ctx).unwrap();
+            // A request could not be sent because the connection was dropped
+            let result = self
+                .config
+                .update_gui(ctx)
+                .and_then(|()| self.chat.update_gui(ctx));
+            if let Err(error) = result {
+                eprintln!("Could not send to the server: {error}");
+                process::exit(1);
+            }
         });
     }
 }
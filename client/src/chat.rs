@@ -1,58 +1,408 @@
+use chat_client::{
+    chat_log::{ChatLine, ChatLog},
+    e2e::KeyPair,
+    ChatClient, Events,
+};
 use chat_core::{
-    read_write_streams::ReadWriteStreams, message::Message, read::ChatReader, request::Request,
-    response::Response, value::Value, write::ChatWriter,
+    event::Event,
+    message::{Message, MessageId},
+    read_write_streams::ReadWriteStreams,
+    request::Request,
+    value::Value,
+    write::ChatWriter,
 };
-use egui::{Key, Modifiers, ScrollArea, TextEdit, Window};
+use egui::{Key, Modifiers, RichText, ScrollArea, TextEdit, Window};
 use std::{
+    collections::HashSet,
     process,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
     thread,
+    time::{Duration, Instant},
 };
 
+use crate::config::DisplayConfig;
+
+/// What the text in the message box is sent as
+#[derive(Default)]
+enum Composing {
+    #[default]
+    Message,
+    /// Replaces the payload of the message with the id
+    Edit(MessageId),
+    /// A reply to the message with the id
+    Reply(MessageId),
+}
+
+/// Something picked from the context menu of a message
+enum MessageAction {
+    /// React with the emoji, or take the reaction back if we already reacted with it
+    ToggleReaction(MessageId, String),
+    Edit(MessageId, String),
+    Delete(MessageId),
+    Reply(MessageId),
+    OpenThread(MessageId),
+}
+
+/// While typing, `TypingStarted` is sent again after this long so the server does not expire it
+const TYPING_RESEND: Duration = Duration::from_secs(3);
+/// `TypingStopped` is sent when nothing has been typed for this long
+const TYPING_IDLE: Duration = Duration::from_secs(5);
+/// Reactions offered in the context menu of a message
+const QUICK_REACTIONS: [&str; 6] = ["👍", "❤", "😂", "🎉", "😮", "😢"];
+/// Sends the rest of the message encrypted so only the user can read it, handled here since the server cannot
+const ENCRYPTED_MESSAGE: &str = "/emsg ";
+
 pub struct Chat {
-    client_streams: ReadWriteStreams,
+    client: ChatClient,
+    display: DisplayConfig,
     message_text: String,
-    messages: Arc<Mutex<Vec<Message>>>,
+    composing: Composing,
+    /// The message whose thread window is open
+    thread: Option<MessageId>,
+    /// The reactions we have added, the server does not tell us which reactions are ours
+    reacted: HashSet<(MessageId, String)>,
+    /// When `TypingStarted` was last sent, `None` if we are not typing
+    typing_sent: Option<Instant>,
+    /// When `message_text` was last changed
+    last_edit: Instant,
+    /// The room `read_up_to` is for
+    read_room: Option<String>,
+    /// The newest message in our room we told the server we have read
+    read_up_to: Option<MessageId>,
+    /// Messages newer than this arrived while the window was not focused, a "new messages" divider is drawn above them
+    divider: Option<MessageId>,
+    log: Arc<Mutex<ChatLog>>,
+    /// Encrypted messages waiting for the public key of the user they are for, by user id. Shared with the events
+    /// thread, which drops the ones for users without a key. Always locked after `log`.
+    pending: Arc<Mutex<Vec<(usize, String)>>>,
 }
 
 impl Chat {
     /// Create a new ChatGui, and start message checking thread
-    pub fn new(client_streams: ReadWriteStreams) -> Self {
-        let chat_gui = Self {
-            client_streams,
+    pub fn new(client: ChatClient, events: Events, display: DisplayConfig) -> Self {
+        let mut chat_gui = Self {
+            client,
+            display,
             message_text: String::new(),
-            messages: Arc::new(Mutex::new(Vec::new())),
+            composing: Composing::Message,
+            thread: None,
+            reacted: HashSet::new(),
+            typing_sent: None,
+            last_edit: Instant::now(),
+            read_room: None,
+            read_up_to: None,
+            divider: None,
+            log: Arc::new(Mutex::new(ChatLog::default())),
+            pending: Arc::default(),
         };
 
-        chat_gui.start();
+        chat_gui.start(events);
 
         chat_gui
     }
-    /// Start a new thread that will check for new messages, and start a thread that will check for responses
-    fn start(&self) {
+    /// The chat log, still usable if the events thread panicked while holding it
+    fn log(&self) -> MutexGuard<'_, ChatLog> {
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Start a new thread that will apply the events from the server to the chat log
+    fn start(&mut self, events: Events) {
+        // The room tabs need the room list, after this the server sends it whenever we change rooms
+        if let Err(error) = self.client.request(&Request::RoomList) {
+            eprintln!("Error requesting the room list: {error}");
+            process::exit(1);
+        }
+        // Publish a key so others can send us encrypted direct messages
+        let key_pair = KeyPair::generate();
+        if let Err(error) = self.client.publish_key(key_pair.public_key()) {
+            eprintln!("Error publishing our key: {error}");
+            process::exit(1);
+        }
+        self.log().set_key_pair(key_pair);
+
         thread::spawn({
-            let mut client_streams = self.client_streams.clone();
-            let messages = self.messages.clone();
-            move || loop {
-                match client_streams.read_data::<Response>() {
-                    Ok(response) => match response {
-                        Ok(message) => messages.lock().unwrap().push(message),
+            let log = self.log.clone();
+            let pending = self.pending.clone();
+            move || {
+                for response in events {
+                    let mut log = log.lock().unwrap_or_else(PoisonError::into_inner);
+                    match response {
+                        Ok(Event::PublicKey { user, key: None }) => {
+                            let mut pending =
+                                pending.lock().unwrap_or_else(PoisonError::into_inner);
+                            if pending.iter().any(|(to, _)| *to == user) {
+                                pending.retain(|(to, _)| *to != user);
+                                log.push_notice(
+                                    "the user has no key, the encrypted message was not sent",
+                                );
+                            }
+                        }
+                        Ok(event) => log.apply(event),
+                        // The server closes the connection after errors that are fatal, so the events will end
                         Err(error) => {
                             eprintln!("Server returned error: {error}");
-                            process::exit(1);
+                            log.push_error(error);
                         }
-                    },
-                    Err(error) => {
-                        eprintln!("Error reading new message: {error}");
-                        process::exit(1);
                     }
                 }
+                eprintln!("Connection to the server closed");
+                process::exit(1);
+            }
+        });
+    }
+    /// Show a message, with the message it replies to quoted above it and highlighted if it mentions us. What is
+    /// picked in its context menu is put in `action`.
+    fn show_message(
+        &self,
+        ui: &mut egui::Ui,
+        log: &ChatLog,
+        message: &Message,
+        action: &mut Option<MessageAction>,
+    ) {
+        if let Some(parent) = message.reply_to() {
+            ui.weak(format!("↪ {}", log.quote(parent)));
+        }
+
+        let mut text = RichText::new(self.display.format_message(message));
+        if message.id().is_some_and(|id| log.is_mentioned(id)) {
+            text = text.strong().color(ui.visuals().warn_fg_color);
+        }
+        let label = ui.label(text);
+        let Some(id) = message.id().filter(|_| !message.is_deleted()) else {
+            return;
+        };
+        label
+            .on_hover_text(format!("message #{id}"))
+            .context_menu(|ui| {
+                let mut pick = |ui: &mut egui::Ui, text: &str, picked: MessageAction| {
+                    if ui.button(text).clicked() {
+                        *action = Some(picked);
+                        ui.close_menu();
+                    }
+                };
+
+                ui.horizontal(|ui| {
+                    for emoji in QUICK_REACTIONS {
+                        pick(
+                            ui,
+                            emoji,
+                            MessageAction::ToggleReaction(id, emoji.to_owned()),
+                        );
+                    }
+                });
+                pick(ui, "Reply", MessageAction::Reply(id));
+                let replies = log.replies(id).count();
+                pick(
+                    ui,
+                    &format!("View thread ({replies})"),
+                    MessageAction::OpenThread(id),
+                );
+                if let Value::String(text) = message.payload() {
+                    pick(ui, "Edit", MessageAction::Edit(id, text.clone()));
+                }
+                pick(ui, "Delete", MessageAction::Delete(id));
+            });
+
+        if !message.reactions().is_empty() {
+            ui.horizontal(|ui| {
+                for reaction in message.reactions() {
+                    let ours = self.reacted.contains(&(id, reaction.emoji.clone()));
+                    if ui
+                        .selectable_label(ours, format!("{} {}", reaction.emoji, reaction.count))
+                        .clicked()
+                    {
+                        *action = Some(MessageAction::ToggleReaction(id, reaction.emoji.clone()));
+                    }
+                }
+            });
+        }
+    }
+    /// Tell the server when we start and stop typing, `changed` is true if `message_text` was changed this frame.
+    /// `TypingStarted` is only sent every `TYPING_RESEND` while typing.
+    fn update_typing(&mut self, changed: bool) -> Result<(), bincode::Error> {
+        let now = Instant::now();
+        if changed {
+            self.last_edit = now;
+        }
+
+        let typing = !self.message_text.trim().is_empty() && now - self.last_edit < TYPING_IDLE;
+        match self.typing_sent {
+            Some(_) if !typing => {
+                self.client.request(&Request::TypingStopped)?;
+                self.typing_sent = None;
+            }
+            Some(sent) if changed && now - sent >= TYPING_RESEND => {
+                self.client.request(&Request::TypingStarted)?;
+                self.typing_sent = Some(now);
+            }
+            None if typing && changed => {
+                self.client.request(&Request::TypingStarted)?;
+                self.typing_sent = Some(now);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+    /// Tell the server we have read the newest message in our room once the window is focused. Messages that arrive
+    /// while the window is not focused get a divider above them.
+    fn update_read(&mut self, focused: bool) -> Result<(), bincode::Error> {
+        let (room, latest) = {
+            let log = self.log();
+            let Some(room) = log.current_room().map(str::to_owned) else {
+                return Ok(());
+            };
+            let latest = log.latest_in(&room);
+            (room, latest)
+        };
+
+        if self.read_room.as_ref() != Some(&room) {
+            self.read_room = Some(room.clone());
+            self.read_up_to = None;
+            self.divider = None;
+            // The member list is for the room we were in
+            self.client.list_users()?;
+        }
+
+        let Some(latest) = latest.filter(|latest| Some(*latest) > self.read_up_to) else {
+            return Ok(());
+        };
+        if focused {
+            self.client.request(&Request::MarkRead {
+                room,
+                up_to: latest,
+            })?;
+            self.read_up_to = Some(latest);
+        } else if self.divider.is_none() {
+            self.divider = Some(self.read_up_to.unwrap_or_default());
+        }
+
+        Ok(())
+    }
+    /// Handle `/emsg <username> <message>`, the key of the user is asked for first if we do not have it yet
+    fn send_encrypted(&mut self, args: &str) -> Result<(), bincode::Error> {
+        let user = {
+            let mut log = self.log();
+            let Some((name, text)) = args
+                .trim_start()
+                .split_once(' ')
+                .filter(|(_, text)| !text.trim().is_empty())
+            else {
+                log.push_notice("usage: /emsg <username> <message>");
+                return Ok(());
+            };
+            let Some(user) = log
+                .members()
+                .iter()
+                .find(|user| user.username().to_string() == name)
+                .map(|user| user.id())
+            else {
+                log.push_notice(format!(
+                    "{name} is not in this room, encrypted messages need their key"
+                ));
+                return Ok(());
+            };
+
+            self.pending
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push((user, text.trim().to_owned()));
+            log.public_key(user).is_none().then_some(user)
+        };
+
+        if let Some(user) = user {
+            self.client.request_key(user)?;
+        }
+        self.send_pending()
+    }
+    /// Encrypt and send the pending messages for users whose key we have
+    fn send_pending(&mut self) -> Result<(), bincode::Error> {
+        let mut log = self.log.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut waiting = Vec::new();
+
+        for (to, text) in std::mem::take(&mut *pending) {
+            let (Some(key), Some(key_pair)) = (log.public_key(to), log.key_pair()) else {
+                waiting.push((to, text));
+                continue;
+            };
+            match key_pair.encrypt(key, &text) {
+                Ok(payload) => self.client.send_encrypted(to, payload)?,
+                Err(error) => {
+                    log.push_notice(format!("the encrypted message was not sent: {error}"))
+                }
+            }
+        }
+        *pending = waiting;
+
+        Ok(())
+    }
+    /// A tab for every room we have been in with how many unread messages it has, picking one joins the room
+    fn room_tabs(&mut self, ui: &mut egui::Ui) -> Result<(), bincode::Error> {
+        let mut join = None;
+        ui.horizontal_wrapped(|ui| {
+            let log = self.log();
+            for room in log.rooms() {
+                let current = log.current_room() == Some(room.name.as_str());
+                let text = match room.unread {
+                    0 => format!("#{}", room.name),
+                    unread => format!("#{} ({unread})", room.name),
+                };
+                let mut text = RichText::new(text);
+                if room.unread > 0 && !current {
+                    text = text.strong();
+                }
+                if ui
+                    .selectable_label(current, text)
+                    .on_hover_text(format!("{} user(s)", room.users))
+                    .clicked()
+                    && !current
+                {
+                    join = Some(room.name.clone());
+                }
+            }
+        });
+
+        if let Some(room) = join {
+            self.client.join_room(&room)?;
+        }
+
+        Ok(())
+    }
+    /// The users in our room, updated when we change rooms or use `/who`
+    fn members(&self, ui: &mut egui::Ui) {
+        let log = self.log();
+        ui.collapsing(format!("Members ({})", log.members().len()), |ui| {
+            for user in log.members() {
+                ui.label(user.to_string());
             }
         });
     }
     /// Update gui
     pub fn update_gui(&mut self, ctx: &egui::Context) -> Result<(), bincode::Error> {
+        // What was picked in the context menu of a message, handled once the messages are no longer locked
+        let mut action = None;
+        let mut typing_changed = false;
+        let mut tabs_result = Ok(());
+        let mut send_result = Ok(());
+
+        self.update_read(ctx.input(|i| i.raw.has_focus))?;
+        self.send_pending()?;
+
         Window::new("Chat").show(ctx, |ui| {
+            tabs_result = self.room_tabs(ui);
+            self.members(ui);
+            ui.separator();
+
+            let unread_mentions = self.log().unread_mentions();
+            if unread_mentions > 0
+                && ui
+                    .button(format!("🔔 {unread_mentions} unread mention(s)"))
+                    .on_hover_text("mark as read")
+                    .clicked()
+            {
+                self.log().clear_unread_mentions();
+            }
+
             // Messages scroll area
             ScrollArea::vertical()
                 .id_source("messages")
@@ -60,15 +410,54 @@ impl Chat {
                 .max_height(ui.available_height() / 1.5)
                 .max_width(f32::INFINITY)
                 .show(ui, |ui| {
-                    for message in &*self.messages.lock().unwrap() {
-                        ui.label(format!("{message}"));
+                    let log = self.log();
+                    let mut divider = self.divider;
+                    for line in log.lines() {
+                        match line {
+                            ChatLine::Message(message) => {
+                                // Only drawn once, above the first message newer than the divider
+                                if message
+                                    .id()
+                                    .is_some_and(|id| divider.is_some_and(|divider| id > divider))
+                                {
+                                    divider = None;
+                                    ui.add(egui::Separator::default().horizontal());
+                                    ui.colored_label(ui.visuals().warn_fg_color, "new messages");
+                                }
+                                self.show_message(ui, &log, message, &mut action)
+                            }
+                            ChatLine::Error(error) => {
+                                ui.colored_label(ui.visuals().error_fg_color, format!("{error}"));
+                            }
+                            ChatLine::Notice(notice) => {
+                                ui.weak(notice);
+                            }
+                        };
                     }
                 });
 
+            if let Some(typing) = self.log().typing() {
+                ui.weak(typing);
+            }
+
             ui.separator();
 
-            if self.message_text.bytes().len() as u64 > <ReadWriteStreams as ChatWriter>::byte_limit()
-            {
+            let composing = match self.composing {
+                Composing::Message => None,
+                Composing::Edit(id) => Some(format!("Editing message #{id}")),
+                Composing::Reply(id) => Some(format!("Replying to {}", self.log().quote(id))),
+            };
+            if let Some(composing) = composing {
+                ui.horizontal(|ui| {
+                    ui.label(composing);
+                    if ui.button("Cancel").clicked() {
+                        self.composing = Composing::Message;
+                        self.message_text.clear();
+                    }
+                });
+            }
+
+            if self.message_text.len() as u64 > <ReadWriteStreams as ChatWriter>::byte_limit() {
                 ui.label("Message Too Long!");
                 ui.separator();
             }
@@ -90,15 +479,77 @@ impl Chat {
                     && response.has_focus()
                     && !i.modifiers.matches(Modifiers::SHIFT)
             }) {
-                self.client_streams
-                    .write_data(&Request::SendMessage(Value::from(
-                        self.message_text.trim_end(),
-                    )))
-                    .unwrap();
+                let text = self.message_text.trim_end().to_owned();
+                send_result = match (&self.composing, text.strip_prefix(ENCRYPTED_MESSAGE)) {
+                    (Composing::Message, Some(args)) => self.send_encrypted(args),
+                    _ => {
+                        let payload = Value::from(text);
+                        let request = match std::mem::take(&mut self.composing) {
+                            Composing::Message => Request::SendMessage(payload),
+                            Composing::Edit(id) => Request::EditMessage {
+                                id,
+                                new_payload: payload,
+                            },
+                            Composing::Reply(parent) => Request::SendReply { parent, payload },
+                        };
+                        self.client.request(&request)
+                    }
+                };
 
                 self.message_text.clear();
+                // Anything new has been seen by now
+                self.divider = None;
             }
+
+            typing_changed = response.changed();
         });
+        tabs_result?;
+        send_result?;
+        self.update_typing(typing_changed)?;
+
+        if let Some(parent) = self.thread {
+            let mut open = true;
+            Window::new(format!("Thread #{parent}"))
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    let log = self.log();
+                    ScrollArea::vertical().show(ui, |ui| {
+                        for message in log.message(parent).into_iter().chain(log.replies(parent)) {
+                            self.show_message(ui, &log, message, &mut action);
+                        }
+                    });
+
+                    ui.separator();
+                    if ui.button("Reply in thread").clicked() {
+                        action = Some(MessageAction::Reply(parent));
+                    }
+                });
+            if !open {
+                self.thread = None;
+            }
+        }
+
+        match action {
+            Some(MessageAction::ToggleReaction(message_id, emoji)) => {
+                let request = if self.reacted.remove(&(message_id, emoji.clone())) {
+                    Request::Unreact { message_id, emoji }
+                } else {
+                    self.reacted.insert((message_id, emoji.clone()));
+                    Request::React { message_id, emoji }
+                };
+                self.client.request(&request)?;
+            }
+            Some(MessageAction::Edit(id, text)) => {
+                self.composing = Composing::Edit(id);
+                self.message_text = text;
+            }
+            Some(MessageAction::Delete(id)) => {
+                self.client.request(&Request::DeleteMessage { id })?;
+            }
+            Some(MessageAction::Reply(id)) => self.composing = Composing::Reply(id),
+            Some(MessageAction::OpenThread(id)) => self.thread = Some(id),
+            None => {}
+        }
 
         Ok(())
     }
@@ -1,19 +1,20 @@
+use chat_client::ChatClient;
 use chat_core::{
-    read_write_streams::ReadWriteStreams,
     config::{Config, ConfigError},
     request::Request,
     value::Value,
-    write::ChatWriter,
 };
 use egui::Window;
-use std::{io, process};
+use std::{path::PathBuf, process};
 
-use super::ClientConfig;
+use chat_client::config::ClientConfig;
 
 pub struct ConfigGui {
     config: Option<ClientConfig>,
+    /// Where the config is written if it has to be created
+    config_path: PathBuf,
     config_handled: bool,
-    client_streams: ReadWriteStreams,
+    client: ChatClient,
 
     create_config_data: CreateConfigData,
 }
@@ -25,20 +26,23 @@ pub struct CreateConfigData {
 }
 
 impl ConfigGui {
-    /// Returns a new `ConfigGui`, this will open a config gui with the Config::load() function. If the config cannot be
-    /// found an error is not returned since creating a config will be handled in the update_gui() method. However an
-    /// error will be returned if something other than `io::ErrorKind::NotFound` is returned from Config::load().
-    pub fn new(client_streams: ReadWriteStreams) -> Result<Self, ConfigError> {
+    /// Returns a new `ConfigGui`, this will load the config at `path` (or the first of the default config locations
+    /// if there is no path). If there is no config file an error is not returned since creating a config will be
+    /// handled in the update_gui() method. However an error will be returned if the config file cannot be loaded.
+    pub fn new(client: ChatClient, path: Option<PathBuf>) -> Result<Self, ConfigError> {
+        let found = match &path {
+            Some(path) => Some(path.clone()).filter(|path| path.is_file()),
+            None => ClientConfig::find(),
+        };
+
         let config = ConfigGui {
-            config: match ClientConfig::load() {
-                Ok(config) => Some(config),
-                Err(error) => match error {
-                    ConfigError::IoError(error) if error.kind() == io::ErrorKind::NotFound => None,
-                    _ => return Err(error),
-                },
+            config: match &found {
+                Some(found) => Some(ClientConfig::load_layered(Some(found))?),
+                None => None,
             },
+            config_path: path.unwrap_or_else(ClientConfig::write_path),
             config_handled: false,
-            client_streams,
+            client,
 
             create_config_data: CreateConfigData {
                 random_username: false,
@@ -49,6 +53,10 @@ impl ConfigGui {
 
         Ok(config)
     }
+    /// The loaded config, `None` if there is no config file yet
+    pub fn config(&self) -> Option<&ClientConfig> {
+        self.config.as_ref()
+    }
     /// This function will handle checking if the config has already been checked, so no need to wrap it in a check
     pub fn update_gui(&mut self, ctx: &egui::Context) -> Result<(), bincode::Error> {
         // if the config has not been handled
@@ -58,10 +66,9 @@ impl ConfigGui {
                 Some(config) => {
                     config
                         .username
-                        .name
-                        .as_ref()
-                        // Convert Option<String> to Option<Request>
-                        .map(|name| Request::ChangeUserName(Value::from(name.clone())))
+                        .name()
+                        // Convert Option<&str> to Option<Request>
+                        .map(|name| Request::ChangeUserName(Value::from(name)))
                 }
                 // Config file does not exist, so we will create one
                 None => {
@@ -101,8 +108,12 @@ impl ConfigGui {
                                         Some(self.create_config_data.username.take().unwrap())
                                     });
 
-                                if let Err(error) =
-                                    self.create_config_data.config.as_ref().unwrap().write()
+                                if let Err(error) = self
+                                    .create_config_data
+                                    .config
+                                    .as_ref()
+                                    .unwrap()
+                                    .write_to_path(&self.config_path)
                                 {
                                     eprintln!("Could not write config!: {error}");
                                     process::exit(1);
@@ -116,10 +127,9 @@ impl ConfigGui {
                     if let Some(config) = &self.create_config_data.config {
                         config
                             .username
-                            .name
-                            .as_ref()
-                            // Convert Option<String> to Option<Request>
-                            .map(|name| Request::ChangeUserName(Value::from(name.clone())))
+                            .name()
+                            // Convert Option<&str> to Option<Request>
+                            .map(|name| Request::ChangeUserName(Value::from(name)))
                     } else {
                         None
                     }
@@ -127,7 +137,7 @@ impl ConfigGui {
             };
 
             if let Some(request) = request {
-                self.client_streams.write_data(&request)?;
+                self.client.request(&request)?;
                 self.config_handled = true;
             }
         }
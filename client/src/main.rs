@@ -1,12 +1,28 @@
-use client::app::App;
+use std::{env, process};
+
+use chat_core::config::{Config, ConfigArgs};
+use client::{app::App, config::ClientConfig};
 use eframe::{run_native, NativeOptions};
 
 fn main() {
+    let args = match ConfigArgs::parse(env::args()) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{error}");
+            process::exit(2);
+        }
+    };
+    if args.dump_default {
+        print!("{}", ClientConfig::TEMPLATE);
+        return;
+    }
+    let config_path = args.path;
+
     let native_options = NativeOptions::default();
     run_native(
         "chat",
         native_options,
-        Box::new(|cc| Box::new(App::new(cc))),
+        Box::new(|cc| Box::new(App::new(cc, config_path))),
     )
     .unwrap();
 }
@@ -1,82 +1,1010 @@
 use std::{
     collections::HashMap,
-    sync::mpsc::{self, Receiver, Sender},
-    thread,
+    fmt, io,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use chat_core::{
-    message::Message, read_write_streams::ReadWriteStreams, response::Response, write::ChatWriter,
+    command::CommandError,
+    encryption::PublicKey,
+    event::{Event, RoomInfo},
+    message::{Message, MessageId},
+    request::{RequestError, ShutdownNotice},
+    response::Response,
+    user::User,
+    value::Value,
 };
+use chrono::Utc;
+
+use crate::{
+    client::SERVER_USER,
+    command::{Args, Role},
+    config::WebhookEventKind,
+    connection::SharedWriter,
+    history::History,
+    key_directory::KeyDirectory,
+    mention::mentioned_names,
+    metrics::Metrics,
+    plugin::{Plugin, PluginContext, Verdict},
+    read_marker::ReadMarkers,
+    reload::SharedConfig,
+    webhook::{ModerationAction, WebhookEvent, Webhooks},
+};
+
+/// The room every client is placed in when they connect
+pub const DEFAULT_ROOM: &str = "general";
+/// How long a client is shown as typing after its last `TypingStarted`
+const TYPING_EXPIRY: Duration = Duration::from_secs(6);
 
 #[derive(Debug)]
 pub enum BroadcastMessage {
-    /// Broadcast a message to all connected clients
+    /// Broadcast a message to all clients in the room of the message (or every client if the message has no room), if
+    /// the message is a reply its parent has to be in the history
     ChatMessage(Message),
     /// Add client along with a corresponding key
-    AddClient(ReadWriteStreams, usize),
+    AddClient(ClientEntry, usize),
     /// Remove client with id
     RemoveClient(usize),
+    /// Update the user stored for the client with the same key as the users id
+    UpdateUser(User),
+    /// Move the client with the key into a room
+    JoinRoom(usize, String),
+    /// Send a message only to the users with the given username, the sender is told if nobody has that name
+    DirectMessage { to: String, message: Message },
+    /// Send a message only to the client with the key `to`, the sender is told if there is no such client
+    DirectMessageTo { to: usize, message: Message },
+    /// Send the client with the key a list of the users in its room
+    UserList(usize),
+    /// Disconnect every client with the given username, `by` is the key of the client who asked
+    Kick { target: String, by: usize },
+    /// Send the notice to every client, disconnect them all and stop the broadcaster thread
+    Shutdown(ShutdownNotice),
+    /// Replace the payload of a message in the history, `by` is the key of the client who asked and `role` its role.
+    /// The payload should already be checked against the message guidelines.
+    EditMessage {
+        id: MessageId,
+        payload: Value,
+        by: usize,
+        role: Role,
+    },
+    /// Turn a message in the history into a tombstone
+    DeleteMessage {
+        id: MessageId,
+        by: usize,
+        role: Role,
+    },
+    /// Add a reaction from the client with the key `by`, the emoji should already be checked against the message
+    /// guidelines
+    React {
+        id: MessageId,
+        emoji: String,
+        by: usize,
+    },
+    /// Take back a reaction from the client with the key `by`
+    Unreact {
+        id: MessageId,
+        emoji: String,
+        by: usize,
+    },
+    /// The client with the key started (or stopped) typing
+    Typing { key: usize, typing: bool },
+    /// The client with the key has read every message in `room` up to and including `up_to`
+    MarkRead {
+        key: usize,
+        room: String,
+        up_to: MessageId,
+    },
+    /// Send the client with the key the rooms it has been in along with their unread counts
+    RoomList(usize),
+    /// The client with the key published its public key for encrypted direct messages
+    PublishKey { key: usize, public_key: PublicKey },
+    /// Send the client with the key the public key of `user`
+    GetKey { key: usize, user: usize },
+    /// The client with the key ran a command of the plugin at index `plugin`, `args` is everything after the name
+    PluginCommand {
+        key: usize,
+        plugin: usize,
+        name: String,
+        args: String,
+    },
+}
+
+/// Everything the broadcaster knows about a connected client
+pub struct ClientEntry {
+    writer: SharedWriter,
+    /// `None` until the client handler has created the user
+    user: Option<User>,
+    room: String,
+}
+
+impl fmt::Debug for ClientEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientEntry")
+            .field("user", &self.user)
+            .field("room", &self.room)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientEntry {
+    pub fn new(writer: SharedWriter) -> Self {
+        Self {
+            writer,
+            user: None,
+            room: DEFAULT_ROOM.to_owned(),
+        }
+    }
+    fn write_data(&self, response: &Response) -> Result<(), io::Error> {
+        self.writer.lock().unwrap().write_response(response)
+    }
+    /// Closing the connection makes the client handler's read fail, which ends its thread
+    fn disconnect(&self) {
+        self.writer.lock().unwrap().disconnect();
+    }
+    fn has_username(&self, name: &str) -> bool {
+        self.user
+            .as_ref()
+            .is_some_and(|user| user.username().to_string() == name)
+    }
 }
 
 /// Recieves messages through the Sender<BroadcastMessage>
 /// returned from Broadcaster::run(). Handles stuff involving all
 /// clients such as, broadcasting messages to all clients.
-#[derive(Default)]
 pub struct Broadcaster {
-    clients: HashMap<usize, ReadWriteStreams>,
+    config: Arc<SharedConfig>,
+    clients: HashMap<usize, ClientEntry>,
+    /// Id of the last message that was accepted
+    last_id: MessageId,
+    /// Messages sent to rooms, so they can be edited and deleted
+    history: History,
+    /// When the typing indicator of each client that is typing expires
+    typing: HashMap<usize, Instant>,
+    /// The last message each user has read in each room
+    read: ReadMarkers,
+    /// Public keys for encrypted direct messages
+    keys: KeyDirectory,
+    /// Called in order for every hook
+    plugins: Vec<Box<dyn Plugin>>,
+    webhooks: Webhooks,
+    metrics: Arc<Metrics>,
 }
 
 impl Broadcaster {
-    /// Start the broadcaster thread, returns a `Sender<BroadcastMessage>` to send data to its thread
-    pub fn run(mut self) -> Sender<BroadcastMessage> {
+    pub fn new(
+        config: Arc<SharedConfig>,
+        plugins: Vec<Box<dyn Plugin>>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let history = History::new(config.get().history.max_messages());
+        let webhooks = Webhooks::start(&config.get().webhooks);
+
+        Self {
+            config,
+            clients: HashMap::new(),
+            last_id: 0,
+            history,
+            typing: HashMap::new(),
+            read: ReadMarkers::default(),
+            keys: KeyDirectory::default(),
+            plugins,
+            webhooks,
+            metrics,
+        }
+    }
+    /// Start the broadcaster thread, returns a `Sender<BroadcastMessage>` to send data to its thread along with the
+    /// handle of the thread. The thread stops after a `BroadcastMessage::Shutdown` or once every sender is dropped.
+    pub fn run(mut self) -> (Sender<BroadcastMessage>, JoinHandle<()>) {
         log::info!("running broadcaster");
 
         let (tx, rx): (Sender<BroadcastMessage>, Receiver<BroadcastMessage>) = mpsc::channel();
 
-        thread::spawn(move || loop {
-            let message = rx.recv().unwrap();
-            log::info!("recieved a BroadcastMessage");
-            log::debug!("{message:?}");
+        let handle = thread::spawn(move || {
+            loop {
+                // Wake up when the next typing indicator expires even if there are no messages
+                let message = match rx.recv_timeout(self.next_typing_expiry()) {
+                    Ok(message) => message,
+                    Err(RecvTimeoutError::Timeout) => {
+                        self.expire_typing();
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                log::info!("recieved a BroadcastMessage");
+                log::debug!("{message:?}");
+                let started = Instant::now();
+
+                match message {
+                    BroadcastMessage::ChatMessage(message) => {
+                        log::debug!("chat message broadcast recieved");
+                        self.chat_message(message);
+                    }
+                    BroadcastMessage::AddClient(client, key) => {
+                        log::debug!("add client broadcast recieved");
+                        self.clients.insert(key, client);
+                    }
+                    BroadcastMessage::RemoveClient(key) => {
+                        log::debug!("remove client broadcast recieved");
+                        self.remove_client(key);
+                    }
+                    BroadcastMessage::UpdateUser(user) => {
+                        log::debug!("update user broadcast recieved");
+                        if let Some(entry) = self.clients.get_mut(&user.id()) {
+                            // Messages sent before the user got here are not unread
+                            self.read.track(user.id(), &entry.room, self.last_id);
+                            let connected = entry.user.is_none();
+                            let room = entry.room.clone();
+                            entry.user = Some(user.clone());
 
-            match message {
-                BroadcastMessage::ChatMessage(message) => {
-                    log::debug!("chat message broadcast recieved");
-                    self.clients.broadcast(message);
+                            if connected {
+                                self.webhooks.send(WebhookEvent::Join {
+                                    user: user.username().to_string(),
+                                    room: room.clone(),
+                                });
+                                self.call_plugins(|plugin, ctx| {
+                                    plugin.on_connect(ctx, &user, &room)
+                                });
+                            }
+                        }
+                    }
+                    BroadcastMessage::JoinRoom(key, room) => {
+                        log::debug!("join room broadcast recieved");
+                        self.set_typing(key, false);
+                        if let Some(entry) = self.clients.get_mut(&key) {
+                            entry.room = room;
+                        }
+                        if let Some(username) = self.username(key) {
+                            let room = self.clients[&key].room.clone();
+                            self.read.track(key, &room, self.last_id);
+                            self.webhooks.send(WebhookEvent::Join {
+                                user: username,
+                                room,
+                            });
+                        }
+                        self.room_list(key);
+                    }
+                    BroadcastMessage::DirectMessage { to, message } => {
+                        log::debug!("direct message broadcast recieved");
+                        self.direct_message(&to, message);
+                    }
+                    BroadcastMessage::DirectMessageTo { to, message } => {
+                        log::debug!("direct message to key broadcast recieved");
+                        let recipients = match self.clients.contains_key(&to) {
+                            true => vec![to],
+                            false => Vec::new(),
+                        };
+                        self.deliver_direct(recipients, message, RequestError::UnknownUserId(to));
+                    }
+                    BroadcastMessage::PublishKey { key, public_key } => {
+                        log::debug!("publish key broadcast recieved");
+                        self.keys.publish(key, public_key);
+                    }
+                    BroadcastMessage::GetKey { key, user } => {
+                        log::debug!("get key broadcast recieved");
+                        let event = Event::PublicKey {
+                            user,
+                            key: self.keys.get(user),
+                        };
+                        self.respond(key, &Response::Ok(event));
+                    }
+                    BroadcastMessage::UserList(key) => {
+                        log::debug!("user list broadcast recieved");
+                        self.user_list(key);
+                    }
+                    BroadcastMessage::Kick { target, by } => {
+                        log::debug!("kick broadcast recieved");
+                        self.kick(&target, by);
+                    }
+                    BroadcastMessage::Shutdown(notice) => {
+                        log::debug!("shutdown broadcast recieved");
+                        self.shutdown(notice);
+                        break;
+                    }
+                    BroadcastMessage::EditMessage {
+                        id,
+                        payload,
+                        by,
+                        role,
+                    } => {
+                        log::debug!("edit message broadcast recieved");
+                        let edited_at = Utc::now();
+                        self.change_message(id, by, role, ModerationAction::Edit, |message| {
+                            message.edit(payload.clone(), edited_at);
+                            Event::MessageEdited {
+                                id,
+                                payload,
+                                edited_at,
+                            }
+                        });
+                    }
+                    BroadcastMessage::DeleteMessage { id, by, role } => {
+                        log::debug!("delete message broadcast recieved");
+                        self.change_message(id, by, role, ModerationAction::Delete, |message| {
+                            message.delete();
+                            Event::MessageDeleted { id }
+                        });
+                    }
+                    BroadcastMessage::React { id, emoji, by } => {
+                        log::debug!("react broadcast recieved");
+                        self.react(id, emoji, by, true);
+                    }
+                    BroadcastMessage::Unreact { id, emoji, by } => {
+                        log::debug!("unreact broadcast recieved");
+                        self.react(id, emoji, by, false);
+                    }
+                    BroadcastMessage::Typing { key, typing } => {
+                        log::debug!("typing broadcast recieved");
+                        self.set_typing(key, typing);
+                    }
+                    BroadcastMessage::MarkRead { key, room, up_to } => {
+                        log::debug!("mark read broadcast recieved");
+                        self.mark_read(key, room, up_to);
+                    }
+                    BroadcastMessage::RoomList(key) => {
+                        log::debug!("room list broadcast recieved");
+                        self.room_list(key);
+                    }
+                    BroadcastMessage::PluginCommand {
+                        key,
+                        plugin,
+                        name,
+                        args,
+                    } => {
+                        log::debug!("plugin command broadcast recieved");
+                        self.plugin_command(key, plugin, &name, &args);
+                    }
+                }
+
+                self.metrics.broadcast_handled(started.elapsed());
+                self.metrics.set_connected_clients(self.clients.len());
+            }
+
+            log::info!("broadcaster thread stopped");
+        });
+
+        log::info!("broadcaster thread started");
+
+        (tx, handle)
+    }
+    /// Give the message the next id and the current time, every message sent out by the broadcaster goes through here
+    fn accept(&mut self, mut message: Message) -> Message {
+        self.last_id += 1;
+        self.metrics.message_sent();
+        message.stamp(self.last_id, Utc::now());
+        message
+    }
+    /// Mark the client with the key as typing (or not) and tell the rest of its room if that changed. A client that is
+    /// already typing only has its expiry pushed back, so clients sending `TypingStarted` often do not flood the room.
+    fn set_typing(&mut self, key: usize, typing: bool) {
+        let was_typing = if typing {
+            self.typing
+                .insert(key, Instant::now() + TYPING_EXPIRY)
+                .is_some()
+        } else {
+            self.typing.remove(&key).is_some()
+        };
+        if was_typing == typing {
+            return;
+        }
+
+        let Some(entry) = self.clients.get(&key) else {
+            return;
+        };
+        let Some(user) = entry.user.clone() else {
+            return;
+        };
+        let room = entry.room.clone();
+        let response = Response::Ok(if typing {
+            Event::TypingStarted { user }
+        } else {
+            Event::TypingStopped { user }
+        });
+
+        for (_, entry) in self
+            .clients
+            .iter_mut()
+            .filter(|(other, entry)| **other != key && entry.room == room)
+        {
+            let _ = entry.write_data(&response);
+        }
+    }
+    /// Stop the typing indicators that have not been renewed in time
+    fn expire_typing(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .typing
+            .iter()
+            .filter(|(_, expiry)| **expiry <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            log::debug!("typing indicator of client {key} expired");
+            self.set_typing(key, false);
+        }
+    }
+    /// How long until the next typing indicator expires
+    fn next_typing_expiry(&self) -> Duration {
+        self.typing.values().min().map_or(Duration::MAX, |expiry| {
+            expiry.saturating_duration_since(Instant::now())
+        })
+    }
+    /// Accept a message and send it to its room, a reply is only sent if its parent is in the history and in the
+    /// same room. Messages from clients go through the plugins first.
+    fn chat_message(&mut self, mut message: Message) {
+        // Only messages from clients have a sender to answer, bots and the server are not clients
+        let sender = message.from().key();
+        if let Some(parent) = message.reply_to() {
+            let parent_found = self
+                .history
+                .get(parent)
+                .is_some_and(|parent| !parent.is_deleted() && parent.room() == message.room());
+            if !parent_found {
+                if let Some(sender) = sender {
+                    self.respond(sender, &Response::Err(RequestError::UnknownMessage(parent)));
                 }
-                BroadcastMessage::AddClient(client, key) => {
-                    log::debug!("add client broadcast recieved");
-                    self.clients.insert(key, client);
+                return;
+            }
+        }
+
+        // Sending a message means the sender is done typing it
+        if let Some(sender) = sender {
+            self.set_typing(sender, false);
+        }
+
+        // Messages from the server itself (joins, renames, ...) and from bots are not shown to the plugins
+        let mut replies = Vec::new();
+        if let Some(sender) = sender.filter(|sender| self.clients.contains_key(sender)) {
+            for index in 0..self.plugins.len() {
+                let (verdict, messages) =
+                    self.call_plugin(index, |plugin, ctx| plugin.on_message(ctx, &mut message));
+                replies.extend(messages);
+
+                if let Verdict::Veto(reason) = verdict {
+                    log::info!(
+                        "plugin {} vetoed a message from {}",
+                        self.plugins[index].name(),
+                        message.from()
+                    );
+                    self.respond(sender, &Response::Err(RequestError::Rejected(reason)));
+                    self.send_plugin_messages(replies);
+                    return;
                 }
-                BroadcastMessage::RemoveClient(key) => {
-                    log::debug!("remove client broadcast recieved");
-                    self.clients.remove(&key);
+            }
+        }
+
+        self.publish(message);
+        self.send_plugin_messages(replies);
+    }
+    /// Accept a message that is allowed to be sent and send it to its room, mentioned users are told about it
+    fn publish(&mut self, message: Message) {
+        let mut message = self.accept(message);
+        let mentioned = self.mentioned(&message);
+        message.set_mentions(mentioned.clone());
+        if self.webhooks.selected(WebhookEventKind::Message) {
+            self.webhooks.send(WebhookEvent::message(&message));
+        }
+        self.history.push(message.clone());
+        self.clients.broadcast(message.clone());
+        if let Some(room) = message.room() {
+            self.unread_changed(message.from().key(), room, message.id().unwrap());
+        }
+
+        // Mentioned users are told even if they are in another room, but not about mentioning themselves
+        let mention = Response::Ok(Event::Mention(message.clone()));
+        for key in mentioned {
+            if Some(key) != message.from().key() {
+                self.respond(key, &mention);
+            }
+        }
+    }
+    /// Call a hook of the plugin at `index`, returns what the hook returned along with the messages the plugin asked
+    /// to send as `(recipient, message)`, messages without a recipient are for their room
+    fn call_plugin<T>(
+        &mut self,
+        index: usize,
+        hook: impl FnOnce(&mut dyn Plugin, &mut PluginContext) -> T,
+    ) -> (T, Vec<(Option<usize>, Message)>) {
+        let plugin = self.plugins[index].as_mut();
+        let mut ctx = PluginContext::new(plugin);
+        let result = hook(plugin, &mut ctx);
+
+        (result, ctx.into_messages())
+    }
+    /// Call a hook of every plugin in order, the messages of each plugin are sent before the next one is called
+    fn call_plugins(&mut self, mut hook: impl FnMut(&mut dyn Plugin, &mut PluginContext)) {
+        for index in 0..self.plugins.len() {
+            let ((), messages) = self.call_plugin(index, &mut hook);
+            self.send_plugin_messages(messages);
+        }
+    }
+    /// Send the messages plugins asked for, they do not go through the plugins again so plugins cannot set each other
+    /// off
+    fn send_plugin_messages(&mut self, messages: Vec<(Option<usize>, Message)>) {
+        for (to, message) in messages {
+            match to {
+                Some(key) => {
+                    let message = self.accept(message);
+                    self.respond(key, &Response::Ok(Event::Message(message)));
                 }
+                None => self.publish(message),
             }
+        }
+    }
+    /// Run a command of the plugin at index `plugin` for the client with the key, errors are sent to the client
+    fn plugin_command(&mut self, key: usize, plugin: usize, name: &str, args: &str) {
+        let Some(entry) = self.clients.get(&key) else {
+            return;
+        };
+        let Some(user) = entry.user.clone() else {
+            return;
+        };
+        let room = entry.room.clone();
+
+        let (result, messages) = self.call_plugin(plugin, |plugin, ctx| {
+            plugin.on_command(ctx, &user, &room, name, Args::new(args))
         });
+        if let Err(error) = result {
+            self.respond(key, &Response::Err(error));
+        }
+        self.send_plugin_messages(messages);
+    }
+    /// Forget the client with the key and tell the plugins it is gone, the client is not disconnected
+    fn remove_client(&mut self, key: usize) -> Option<ClientEntry> {
+        self.set_typing(key, false);
+        self.keys.remove(key);
+        self.read.remove(key);
+        self.metrics.client_gone(key);
+        let entry = self.clients.remove(&key)?;
 
-        log::info!("broadcaster thread started");
+        if let Some(user) = entry.user.clone() {
+            self.webhooks.send(WebhookEvent::Leave {
+                user: user.username().to_string(),
+                room: entry.room.clone(),
+            });
+            self.call_plugins(|plugin, ctx| plugin.on_disconnect(ctx, &user));
+        }
+        Some(entry)
+    }
+    /// A client sender has read its own message, everyone who has been in the room but is somewhere else is told the new
+    /// unread count of the room
+    fn unread_changed(&mut self, sender: Option<usize>, room: &str, id: MessageId) {
+        if let Some(sender) = sender.filter(|sender| self.username(*sender).is_some()) {
+            self.read.mark(sender, room, id);
+        }
+
+        let mut updates = Vec::new();
+        for (key, entry) in &self.clients {
+            if entry.room == room {
+                continue;
+            }
+            if entry.user.is_none() {
+                continue;
+            }
+            if let Some(read) = self.read.get(*key, room) {
+                updates.push((*key, self.history.unread(room, read)));
+            }
+        }
+
+        for (key, unread) in updates {
+            let response = Response::Ok(Event::RoomUnread {
+                room: room.to_owned(),
+                unread,
+            });
+            self.respond(key, &response);
+        }
+    }
+    /// Move the read marker of the user of the client with the key forward and send it the unread count of the room,
+    /// ids that have not been given out yet are treated as the last id
+    fn mark_read(&mut self, key: usize, room: String, up_to: MessageId) {
+        if self.username(key).is_none() {
+            return;
+        }
+        self.read.mark(key, &room, up_to.min(self.last_id));
+
+        let unread = self
+            .read
+            .get(key, &room)
+            .map_or(0, |read| self.history.unread(&room, read));
+        self.respond(key, &Response::Ok(Event::RoomUnread { room, unread }));
+    }
+    /// Send the client with the key every room that has users in it or that its user has been in
+    fn room_list(&mut self, key: usize) {
+        let Some(current) = self.clients.get(&key).map(|entry| entry.room.clone()) else {
+            return;
+        };
+        let mut names = self.read.rooms(key);
+        names.extend(self.clients.values().map(|entry| entry.room.as_str()));
+        names.insert(&current);
+
+        let rooms = names
+            .into_iter()
+            .map(|name| RoomInfo {
+                name: name.to_owned(),
+                users: self
+                    .clients
+                    .values()
+                    .filter(|entry| entry.room == name)
+                    .count(),
+                unread: self
+                    .read
+                    .get(key, name)
+                    .map_or(0, |read| self.history.unread(name, read)),
+            })
+            .collect();
+
+        self.respond(key, &Response::Ok(Event::RoomList { current, rooms }));
+    }
+    /// The username of the client with the key, `None` until the client handler has created the user
+    fn username(&self, key: usize) -> Option<String> {
+        self.clients
+            .get(&key)?
+            .user
+            .as_ref()
+            .map(|user| user.username().to_string())
+    }
+    /// The keys of the clients whose username is mentioned in the text of the message
+    fn mentioned(&self, message: &Message) -> Vec<usize> {
+        let Value::String(text) = message.payload() else {
+            return Vec::new();
+        };
+        let names = mentioned_names(text);
+        if names.is_empty() {
+            return Vec::new();
+        }
+
+        let mut keys = self
+            .clients
+            .iter()
+            .filter(|(_, entry)| names.iter().any(|name| entry.has_username(name)))
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+
+        keys
+    }
+    /// Change a message in the history if the client with the key `by` is allowed to, then tell the room of the message
+    /// about the change. Only the author of a message or a moderator can change it.
+    fn change_message(
+        &mut self,
+        id: MessageId,
+        by: usize,
+        role: Role,
+        action: ModerationAction,
+        change: impl FnOnce(&mut Message) -> Event,
+    ) {
+        let message = match self.history.get_mut(id) {
+            Some(message) if !message.is_deleted() => message,
+            _ => {
+                self.respond(by, &Response::Err(RequestError::UnknownMessage(id)));
+                return;
+            }
+        };
+        if message.from().key() != Some(by) && role < Role::Moderator {
+            log::info!("client {by} tried to change message #{id} without permission");
+            self.respond(by, &Response::Err(RequestError::NotAuthor(id)));
+            return;
+        }
+
+        let author =
+            (message.from().key() != Some(by)).then(|| message.from().username().to_string());
+        let event = change(message);
+        let room = message.room().map(str::to_owned);
+        self.clients.broadcast_event(room.as_deref(), event);
+
+        // Changing someone else's message is only allowed for moderators
+        if let Some(target) = author {
+            self.webhooks.send(WebhookEvent::Moderation {
+                action,
+                by: self.username(by).unwrap_or_default(),
+                target,
+                room,
+                message: Some(id),
+            });
+        }
+    }
+    /// Add (or take back if `add` is false) a reaction and tell the room of the message, nothing is sent if the
+    /// reaction did not change
+    fn react(&mut self, id: MessageId, emoji: String, by: usize, add: bool) {
+        let Some(from) = self.clients.get(&by).and_then(|entry| entry.user.clone()) else {
+            return;
+        };
+
+        let changed = if add {
+            let max_reactions = self.config.get().message_guidelines.max_reactions();
+            self.history.react(id, &emoji, by, max_reactions)
+        } else {
+            self.history.unreact(id, &emoji, by)
+        }
+        .map(|message| message.map(|message| message.room().map(str::to_owned)));
+
+        match changed {
+            Ok(Some(room)) => {
+                let event = if add {
+                    Event::ReactionAdded { id, emoji, from }
+                } else {
+                    Event::ReactionRemoved { id, emoji, from }
+                };
+                self.clients.broadcast_event(room.as_deref(), event);
+            }
+            Ok(None) => log::debug!("reaction on message #{id} did not change"),
+            Err(error) => {
+                if let RequestError::Message(error) = &error {
+                    self.metrics.rejected(error);
+                }
+                self.respond(by, &Response::Err(error));
+            }
+        }
+    }
+    fn shutdown(&mut self, notice: ShutdownNotice) {
+        log::info!("disconnecting {} client(s)", self.clients.len());
+        let response = Response::Err(RequestError::ServerShutdown(notice));
+
+        for (key, entry) in self.clients.drain() {
+            let _ = entry.write_data(&response);
+            log::debug!("disconnecting client {key}");
+            entry.disconnect();
+        }
+    }
+    /// Write a response to a single client, errors are ignored like in `Broadcast::broadcast`
+    fn respond(&mut self, key: usize, response: &Response) {
+        if let Some(entry) = self.clients.get_mut(&key) {
+            let _ = entry.write_data(response);
+        }
+    }
+    fn direct_message(&mut self, to: &str, message: Message) {
+        let recipients = self
+            .clients
+            .iter()
+            .filter(|(_, entry)| entry.has_username(to))
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        self.deliver_direct(
+            recipients,
+            message,
+            RequestError::Command(CommandError::UnknownUser(to.to_owned())),
+        );
+    }
+    /// Send a direct message to the clients with the keys and back to its sender, the sender gets `unknown` instead if
+    /// there are no recipients
+    fn deliver_direct(&mut self, recipients: Vec<usize>, message: Message, unknown: RequestError) {
+        let sender = message.from().key();
+        if recipients.is_empty() {
+            if let Some(sender) = sender {
+                self.respond(sender, &Response::Err(unknown));
+            }
+            return;
+        }
+
+        let message = self.accept(message);
+        let response = Response::Ok(Event::Message(message));
+
+        for key in &recipients {
+            self.respond(*key, &response);
+        }
+        // Echo the message back so the sender can see what they sent
+        if let Some(sender) = sender.filter(|sender| !recipients.contains(sender)) {
+            self.respond(sender, &response);
+        }
+    }
+    fn user_list(&mut self, key: usize) {
+        let Some(room) = self.clients.get(&key).map(|entry| entry.room.clone()) else {
+            return;
+        };
+
+        let mut users = self
+            .clients
+            .values()
+            .filter(|entry| entry.room == room)
+            .filter_map(|entry| entry.user.clone())
+            .collect::<Vec<_>>();
+        users.sort_by_cached_key(|user| (user.username().to_string(), user.id()));
 
-        tx
+        self.respond(key, &Response::Ok(Event::UserList { room, users }));
+    }
+    fn kick(&mut self, target: &str, by: usize) {
+        let keys = self
+            .clients
+            .iter()
+            .filter(|(_, entry)| entry.has_username(target))
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        if keys.is_empty() {
+            self.respond(
+                by,
+                &Response::Err(RequestError::Command(CommandError::UnknownUser(
+                    target.to_owned(),
+                ))),
+            );
+            return;
+        }
+
+        for key in keys {
+            if let Some(entry) = self.remove_client(key) {
+                log::info!("kicking client {key}");
+                entry.disconnect();
+            }
+        }
+
+        self.webhooks.send(WebhookEvent::Moderation {
+            action: ModerationAction::Kick,
+            by: self.username(by).unwrap_or_default(),
+            target: target.to_owned(),
+            room: None,
+            message: None,
+        });
+
+        let message = self.accept(
+            Message::builder()
+                .from_who(SERVER_USER.clone())
+                .payload(Value::String(format!("{target} was kicked")))
+                .build(),
+        );
+        self.history.push(message.clone());
+        self.clients.broadcast(message);
     }
 }
 
 pub trait Broadcast {
     /// Broadcast a `Message` to all clients, cannot error (error should be handled inside)
-    fn broadcast(&mut self, message: Message);
+    fn broadcast(&mut self, message: Message) {
+        let room = message.room().map(str::to_owned);
+        self.broadcast_event(room.as_deref(), Event::Message(message));
+    }
+    /// Broadcast an `Event` to all clients in `room` (or every client if there is no room), cannot error
+    fn broadcast_event(&mut self, room: Option<&str>, event: Event);
 }
 
-impl Broadcast for HashMap<usize, ReadWriteStreams> {
-    /// Broadcast a `Message` to all clients, if writing data to a client fails, the message will not be broadcasted to that
-    /// client (the client is not removed).
-    fn broadcast(&mut self, message: Message) {
-        log::info!("broadcasting message");
-        let response = Response::Ok(message);
+impl Broadcast for HashMap<usize, ClientEntry> {
+    /// Broadcast an `Event` to all clients in the room, if writing data to a client fails, the event will not be
+    /// broadcasted to that client (the client is not removed).
+    fn broadcast_event(&mut self, room: Option<&str>, event: Event) {
+        log::info!("broadcasting event");
+        let response = Response::Ok(event);
         log::debug!("created response: {response:?}");
 
-        for client in self.values_mut() {
+        for client in self
+            .values_mut()
+            .filter(|client| room.is_none_or(|room| client.room == room))
+        {
             log::debug!("broadcasting to: {client:?}");
             // Error is ignored since the client handler should handle what happens if a client fails
             let _ = client.write_data(&response);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use chat_core::user::{Origin, Username};
+
+    use super::*;
+    use crate::{config::ServerConfig, connection::ResponseWriter};
+
+    /// Keeps the events sent to a client
+    #[derive(Default)]
+    struct Sent(Vec<Event>);
+
+    impl ResponseWriter for Sent {
+        fn write_response(&mut self, response: &Response) -> Result<(), io::Error> {
+            if let Ok(event) = response {
+                self.0.push(event.clone());
+            }
+            Ok(())
+        }
+        fn disconnect(&mut self) {}
+    }
+
+    fn user(key: usize, name: &str) -> User {
+        User::builder()
+            .id(key)
+            .username(Username::new(name))
+            .build()
+    }
+
+    /// Handle the messages in order and wait for the broadcaster to be done with them
+    fn broadcast(messages: impl IntoIterator<Item = BroadcastMessage>) {
+        let config = Arc::new(SharedConfig::new(ServerConfig::default(), None));
+        let (tx, thread) = Broadcaster::new(config, Vec::new(), Arc::default()).run();
+        for message in messages {
+            tx.send(message).unwrap();
+        }
+        drop(tx);
+        thread.join().unwrap();
+    }
+
+    /// The unread counts of the last room list the client was sent
+    fn unread(sent: &Mutex<Sent>) -> Vec<(String, usize)> {
+        let sent = sent.lock().unwrap();
+        let Some(Event::RoomList { rooms, .. }) = sent
+            .0
+            .iter()
+            .rev()
+            .find(|event| matches!(event, Event::RoomList { .. }))
+        else {
+            panic!("no room list in {:?}", sent.0);
+        };
+        rooms
+            .iter()
+            .map(|room| (room.name.clone(), room.unread))
+            .collect()
+    }
+
+    #[test]
+    fn read_markers_follow_the_user_not_the_name() {
+        let amy = Arc::new(Mutex::new(Sent::default()));
+        let newcomer = Arc::new(Mutex::new(Sent::default()));
+        let message = Message::builder()
+            .from_who(user(2, "bob"))
+            .payload(Value::from("hello"))
+            .room(Some(DEFAULT_ROOM.to_owned()))
+            .build();
+
+        broadcast([
+            BroadcastMessage::AddClient(ClientEntry::new(amy.clone()), 1),
+            BroadcastMessage::UpdateUser(user(1, "amy")),
+            BroadcastMessage::AddClient(ClientEntry::new(Arc::new(Mutex::new(Sent::default()))), 2),
+            BroadcastMessage::UpdateUser(user(2, "bob")),
+            BroadcastMessage::JoinRoom(1, "dev".to_owned()),
+            BroadcastMessage::ChatMessage(message),
+            // Renaming keeps the unread message in general
+            BroadcastMessage::UpdateUser(user(1, "amelia")),
+            BroadcastMessage::RoomList(1),
+            // Someone else taking the old name later does not get the markers of amy
+            BroadcastMessage::RemoveClient(1),
+            BroadcastMessage::AddClient(ClientEntry::new(newcomer.clone()), 3),
+            BroadcastMessage::UpdateUser(user(3, "amy")),
+            BroadcastMessage::RoomList(3),
+        ]);
+
+        assert_eq!(
+            unread(&amy),
+            [("dev".to_owned(), 0), (DEFAULT_ROOM.to_owned(), 1)]
+        );
+        assert_eq!(unread(&newcomer), [(DEFAULT_ROOM.to_owned(), 0)]);
+    }
+
+    #[test]
+    fn bots_are_not_taken_for_the_client_with_their_id() {
+        let amy = Arc::new(Mutex::new(Sent::default()));
+        let bot = User::builder()
+            .id(1)
+            .username(Username::new("deploys"))
+            .origin(Origin::Integration)
+            .build();
+        let message = Message::builder()
+            .from_who(bot)
+            .payload(Value::from("deployed, thanks @amy"))
+            .room(Some(DEFAULT_ROOM.to_owned()))
+            .build();
+
+        broadcast([
+            BroadcastMessage::AddClient(ClientEntry::new(amy.clone()), 1),
+            BroadcastMessage::UpdateUser(user(1, "amy")),
+            BroadcastMessage::JoinRoom(1, "dev".to_owned()),
+            BroadcastMessage::ChatMessage(message),
+            BroadcastMessage::RoomList(1),
+        ]);
+
+        // Amy is mentioned by someone else and has not read the message
+        let sent = amy.lock().unwrap();
+        assert!(sent
+            .0
+            .iter()
+            .any(|event| matches!(event, Event::Mention(_))));
+        drop(sent);
+        assert_eq!(
+            unread(&amy),
+            [("dev".to_owned(), 0), (DEFAULT_ROOM.to_owned(), 1)]
+        );
+    }
+}
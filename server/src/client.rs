@@ -1,108 +1,228 @@
-use std::{sync::{mpsc::Sender, Arc, Mutex}, net::TcpStream, io};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{mpsc::Sender, Arc, Mutex},
+};
 
 use chat_core::{
+    event::Event,
     guidelines::AgainstGuidelines,
-    message::Message,
-    read::ChatReader,
-    read_write_streams::{ReadWriteStreams, ConnectPeerStream},
+    message::{Message, MessageId, MessageKind},
     request::{Request, RequestError},
     response::Response,
-    user::{User, Username},
+    user::{Origin, User, Username},
     value::Value,
-    write::ChatWriter,
 };
 
-use crate::{broadcast::BroadcastMessage, config::ServerConfig};
+use crate::{
+    broadcast::{BroadcastMessage, ClientEntry, DEFAULT_ROOM},
+    command::{self, CommandRegistry, Role},
+    connection::{Connection, RequestReader, SharedWriter},
+    federation::Federation,
+    metrics::Metrics,
+    rate_limit::{IpRateLimiter, UserRateLimiter},
+    reload::SharedConfig,
+};
 
 use lazy_static::lazy_static;
 
 lazy_static! {
-    static ref SERVER_USER: User = User::builder()
+    pub static ref SERVER_USER: User = User::builder()
         .id(0)
         .username(Username::new("SERVER"))
+        .origin(Origin::Server)
         .build();
 }
 
+/// What every client handler shares with the rest of the server
+#[derive(Clone)]
+pub struct ClientServices {
+    pub broadcaster: Arc<Mutex<Sender<BroadcastMessage>>>,
+    pub config: Arc<SharedConfig>,
+    pub commands: Arc<CommandRegistry>,
+    pub ip_limiter: Arc<IpRateLimiter>,
+    pub metrics: Arc<Metrics>,
+}
+
 pub struct Client {
     pub key: usize,
-    pub streams: ReadWriteStreams,
+    reader: Box<dyn RequestReader>,
+    pub writer: SharedWriter,
+    /// Addresses of the client, see `Connection::addresses`
+    addresses: (SocketAddr, SocketAddr),
     pub broadcaster: Arc<Mutex<Sender<BroadcastMessage>>>,
-    pub config: Arc<ServerConfig>,
+    pub config: Arc<SharedConfig>,
+    pub commands: Arc<CommandRegistry>,
+    pub ip_limiter: Arc<IpRateLimiter>,
+    pub metrics: Arc<Metrics>,
+    ip: IpAddr,
+    role: Role,
+    room: String,
+    limiter: UserRateLimiter,
 }
 
 impl Client {
-    pub fn make_connection(
-        key: usize,
-        stream: TcpStream,
-        broadcaster: Arc<Mutex<Sender<BroadcastMessage>>>,
-        config: Arc<ServerConfig>,
-    ) -> Result<Self, io::Error> {
-        let streams = stream.connect_peer_stream(config.net.read_port())?;
-
-        Ok(Self {
+    /// Create a client for a connection, whatever protocol it speaks
+    pub fn new(key: usize, connection: Connection, services: ClientServices) -> Self {
+        let ip = connection.addresses.1.ip();
+        let current = services.config.get();
+        let role = if current.moderation.is_moderator(ip) {
+            Role::Moderator
+        } else {
+            Role::User
+        };
+        let limiter = UserRateLimiter::new(&current.rate_limit);
+
+        Self {
             key,
-            streams,
-            broadcaster,
-            config,
-        })
+            reader: connection.reader,
+            writer: services.metrics.metered_writer(key, connection.writer),
+            addresses: connection.addresses,
+            broadcaster: services.broadcaster,
+            config: services.config,
+            commands: services.commands,
+            ip_limiter: services.ip_limiter,
+            metrics: services.metrics,
+            ip,
+            role,
+            room: DEFAULT_ROOM.to_owned(),
+            limiter,
+        }
     }
     pub fn key(&self) -> usize {
         self.key
     }
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+    pub fn role(&self) -> Role {
+        self.role
+    }
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+    /// Send a message to the broadcaster thread, the message is dropped if the broadcaster has stopped (the server is
+    /// shutting down)
+    pub fn broadcast(&self, message: BroadcastMessage) {
+        if self.broadcaster.lock().unwrap().send(message).is_err() {
+            log::debug!("broadcaster has stopped, message dropped");
+        }
+    }
+    /// Write a response to only this client, errors are ignored since a failed write will also fail the next read
+    pub fn send(&self, response: &Response) {
+        self.writer.lock().unwrap().write_response(response).ok();
+    }
+    /// Send a message to only this client
+    pub fn respond(&mut self, message: Message) {
+        self.send(&Response::Ok(Event::Message(message)));
+    }
+    /// Build a message in the current room from `user` and check it against the message guidelines
+    pub fn check_message(
+        &self,
+        user: &User,
+        payload: Value,
+        kind: MessageKind,
+    ) -> Result<Message, RequestError> {
+        self.follow_guidelines(
+            Message::builder()
+                .from_who(user.hide_addr())
+                .payload(payload)
+                .kind(kind)
+                .room(Some(self.room.clone()))
+                .build(),
+        )
+    }
+    /// Build a reply to `parent` in the current room from `user` and check it against the message guidelines, the
+    /// broadcaster checks that the parent exists
+    pub fn check_reply(
+        &self,
+        user: &User,
+        parent: MessageId,
+        payload: Value,
+    ) -> Result<Message, RequestError> {
+        self.follow_guidelines(
+            Message::builder()
+                .from_who(user.hide_addr())
+                .payload(payload)
+                .room(Some(self.room.clone()))
+                .reply_to(Some(parent))
+                .build(),
+        )
+    }
+    fn follow_guidelines(&self, message: Message) -> Result<Message, RequestError> {
+        message
+            .against_guidelines(&self.config.get().message_guidelines)
+            .map_err(|error| {
+                log::info!("message did not follow guidelines");
+                self.metrics.rejected(&error);
+                RequestError::Message(error)
+            })
+    }
+    /// Check the new username against the username guidelines and set it if it follows them
+    pub fn change_username(
+        &mut self,
+        user: &mut User,
+        username: Value,
+    ) -> Result<(), RequestError> {
+        self.broadcast(BroadcastMessage::ChatMessage(
+            Message::builder()
+                .from_who(SERVER_USER.clone())
+                .payload(Value::String(format!(
+                    "Requesting change username. {user} -> {username}"
+                )))
+                .build(),
+        ));
+
+        user.set_username(
+            Username::new(username)
+                .against_guidelines(&self.config.get().username_guidelines)
+                .map_err(RequestError::Username)?,
+        );
+        self.broadcast(BroadcastMessage::UpdateUser(user.hide_addr()));
+
+        Ok(())
+    }
+    /// Move this client into another room and let the room know
+    pub fn join_room(&mut self, user: &User, room: String) {
+        log::info!("{user} is joining #{room}");
+        self.room = room.clone();
+        self.broadcast(BroadcastMessage::JoinRoom(self.key, room.clone()));
+        self.broadcast(BroadcastMessage::ChatMessage(
+            Message::builder()
+                .from_who(SERVER_USER.clone())
+                .payload(Value::String(format!("{user} has joined #{room}")))
+                .room(Some(room))
+                .build(),
+        ));
+    }
     /// Initial client code that is only ran once, very messy in how it works now but will be fixed later
-    pub fn initial_connect(&mut self) -> Result<User, ()> {
+    pub fn initial_connect(&mut self) -> Option<User> {
         log::debug!("broadcasting add client message");
-        self.broadcaster
-            .lock()
-            .unwrap()
-            .send(BroadcastMessage::AddClient(
-                self.streams.clone(),
-                self.key(),
-            ))
-            .unwrap();
+        let entry = ClientEntry::new(Arc::clone(&self.writer));
+        self.broadcast(BroadcastMessage::AddClient(entry, self.key()));
 
         log::info!("client added to chat broadcaster");
 
-        // Create a user
-        let user = {
-            // Get peer_addresses
-            let peer_addresses = self.streams.peer_addrs();
-            let peer_addresses = match peer_addresses {
-                (Ok(read), Ok(write)) => (read, write),
-                _ => {
-                    log::warn!("getting peer_addrs() of client failed");
-                    self.streams
-                        .write_data(&Response::Err(RequestError::Ip))
-                        .ok();
-                    return Err(());
-                }
-            };
-
-            // Build a user with a random name
-            User::builder()
-                .username(Username::new(Value::String(format!(
-                    "anonymous-{}",
-                    User::random_name()
-                ))))
-                .id(self.key)
-                .addresses(Some(peer_addresses))
-                .build()
-        };
+        // Create a user with a random name
+        let user = User::builder()
+            .username(Username::new(Value::String(format!(
+                "anonymous-{}",
+                User::random_name()
+            ))))
+            .id(self.key)
+            .addresses(Some(self.addresses))
+            .build();
 
         log::info!("new client connected: {user:?}");
 
-        self.broadcaster
-            .lock()
-            .unwrap()
-            .send(BroadcastMessage::ChatMessage(
-                Message::builder()
-                    .from_who(SERVER_USER.clone())
-                    .payload(Value::String(format!("{user} has joined")))
-                    .build(),
-            ))
-            .unwrap();
-
-        Ok(user)
+        self.broadcast(BroadcastMessage::UpdateUser(user.hide_addr()));
+        self.broadcast(BroadcastMessage::ChatMessage(
+            Message::builder()
+                .from_who(SERVER_USER.clone())
+                .payload(Value::String(format!("{user} has joined")))
+                .build(),
+        ));
+
+        Some(user)
     }
     pub fn run(&mut self) {
         /// This is strictly used only to make to sure that the
@@ -118,11 +238,12 @@ impl Client {
         impl Drop for Exit {
             fn drop(&mut self) {
                 log::info!("cleaning up client...  ");
+                // The broadcaster is already gone if the server is shutting down
                 self.message_broadcaster
                     .lock()
                     .unwrap()
                     .send(BroadcastMessage::RemoveClient(self.key))
-                    .unwrap();
+                    .ok();
                 log::info!("client removed");
             }
         }
@@ -137,81 +258,173 @@ impl Client {
         };
 
         let mut user = match self.initial_connect() {
-            Ok(user) => user,
+            Some(user) => user,
             // THIS METHOD HANDLES RETURNING AN ERROR RESPONSE!
-            Err(_) => return,
+            None => return,
         };
 
+        // Stopped once this client is gone, when it is the link of another server
+        let mut _link_back = None;
+
         loop {
             // Read request (Blocks thread until there is something to read)
-            let request = self.streams.read_data::<Request>();
+            let request = self.reader.read_request();
             log::debug!("got request: {request:?}");
 
             let request = match request {
                 Ok(request) => request,
                 Err(error) => {
                     log::debug!("bad request: {error}");
-                    self.streams
-                        .write_data(&Response::Err(RequestError::Bad(error.to_string())))
-                        .ok();
+                    self.send(&Response::Err(RequestError::Bad(error.to_string())));
                     return;
                 }
             };
 
+            if let Request::SendMessage(payload)
+            | Request::SendReply { payload, .. }
+            | Request::SendDirect { payload, .. }
+            | Request::EditMessage {
+                new_payload: payload,
+                ..
+            } = &request
+            {
+                // Being rate limited is not fatal, the client is told when it can send again
+                if let Err(error) = self.limiter.check(
+                    self.ip,
+                    &self.ip_limiter,
+                    payload,
+                    &self.config.get().rate_limit,
+                ) {
+                    self.send(&Response::Err(error));
+                    continue;
+                }
+            }
+
             match request {
+                Request::SendMessage(Value::String(text)) if command::is_command(&text) => {
+                    let commands = Arc::clone(&self.commands);
+                    // A failed command is not fatal, the client is just told what went wrong
+                    if let Err(error) = commands.execute(self, &mut user, &text) {
+                        log::info!("command failed: {error}");
+                        self.send(&Response::Err(error));
+                    }
+                }
                 Request::SendMessage(message) => {
-                    let message = match Message::builder()
-                        .from_who(user.hide_addr())
-                        .payload(message)
-                        .build()
-                        .against_guidelines(&self.config.message_guidelines)
-                    {
+                    // A doubled prefix escapes a message that should start with the prefix, e.g. "//shrug"
+                    let message = match message {
+                        Value::String(text) => Value::String(command::unescape(&text).to_owned()),
+                        message => message,
+                    };
+
+                    let message = match self.check_message(&user, message, MessageKind::Text) {
                         Ok(message) => message,
                         Err(error) => {
-                            log::info!("message did not follow guidelines");
-                            self.streams
-                                .write_data(&Response::Err(RequestError::Message(error)))
-                                .ok();
+                            self.send(&Response::Err(error));
                             return;
                         }
                     };
 
                     // Broadcast message to other clients
-                    self.broadcaster
-                        .lock()
-                        .unwrap()
-                        .send(BroadcastMessage::ChatMessage(message))
-                        .unwrap();
+                    self.broadcast(BroadcastMessage::ChatMessage(message));
+                }
+                Request::SendReply { parent, payload } => {
+                    let message = match self.check_reply(&user, parent, payload) {
+                        Ok(message) => message,
+                        Err(error) => {
+                            self.send(&Response::Err(error));
+                            return;
+                        }
+                    };
+
+                    self.broadcast(BroadcastMessage::ChatMessage(message));
                 }
                 Request::ChangeUserName(username) => {
-                    self.broadcaster
-                        .lock()
-                        .unwrap()
-                        .send(BroadcastMessage::ChatMessage(
-                            Message::builder()
-                                .from_who(SERVER_USER.clone())
-                                .payload(Value::String(format!(
-                                    "Requesting change username. {user} -> {username}"
-                                )))
-                                .build(),
-                        ))
-                        .unwrap();
-
-                    user.set_username(
-                        match Username::new(username)
-                            .against_guidelines(&self.config.username_guidelines)
-                        {
-                            Ok(name) => name,
-                            Err(error) => {
-                                self.streams
-                                    .write_data(&Response::Err(RequestError::Username(error)))
-                                    .ok();
-                                return;
-                            }
-                        },
-                    );
+                    if let Err(error) = self.change_username(&mut user, username) {
+                        self.send(&Response::Err(error));
+                        return;
+                    }
+                }
+                Request::UserList => self.broadcast(BroadcastMessage::UserList(self.key)),
+                Request::EditMessage { id, new_payload } => {
+                    // Unlike a new message, an edit that does not follow the guidelines is not fatal
+                    match self.check_message(&user, new_payload, MessageKind::Text) {
+                        Ok(message) => self.broadcast(BroadcastMessage::EditMessage {
+                            id,
+                            payload: message.payload().clone(),
+                            by: self.key,
+                            role: self.role,
+                        }),
+                        Err(error) => {
+                            self.send(&Response::Err(error));
+                        }
+                    }
+                }
+                Request::React { message_id, emoji } => {
+                    match self.config.get().message_guidelines.check_reaction(&emoji) {
+                        Ok(()) => self.broadcast(BroadcastMessage::React {
+                            id: message_id,
+                            emoji,
+                            by: self.key,
+                        }),
+                        Err(error) => {
+                            self.metrics.rejected(&error);
+                            self.send(&Response::Err(RequestError::Message(error)));
+                        }
+                    }
+                }
+                Request::TypingStarted => self.broadcast(BroadcastMessage::Typing {
+                    key: self.key,
+                    typing: true,
+                }),
+                Request::TypingStopped => self.broadcast(BroadcastMessage::Typing {
+                    key: self.key,
+                    typing: false,
+                }),
+                Request::Unreact { message_id, emoji } => {
+                    self.broadcast(BroadcastMessage::Unreact {
+                        id: message_id,
+                        emoji,
+                        by: self.key,
+                    })
+                }
+                Request::DeleteMessage { id } => self.broadcast(BroadcastMessage::DeleteMessage {
+                    id,
+                    by: self.key,
+                    role: self.role,
+                }),
+                Request::MarkRead { room, up_to } => self.broadcast(BroadcastMessage::MarkRead {
+                    key: self.key,
+                    room,
+                    up_to,
+                }),
+                Request::RoomList => self.broadcast(BroadcastMessage::RoomList(self.key)),
+                // Like /msg, a direct message that is not allowed is not fatal
+                Request::SendDirect { to, payload } => {
+                    match self.check_message(&user, payload, MessageKind::Direct) {
+                        Ok(message) => {
+                            self.broadcast(BroadcastMessage::DirectMessageTo { to, message })
+                        }
+                        Err(error) => self.send(&Response::Err(error)),
+                    }
+                }
+                Request::PublishKey(public_key) => self.broadcast(BroadcastMessage::PublishKey {
+                    key: self.key,
+                    public_key,
+                }),
+                Request::GetKey { user } => self.broadcast(BroadcastMessage::GetKey {
+                    key: self.key,
+                    user,
+                }),
+                Request::LinkBack {
+                    name,
+                    port,
+                    read_port,
+                    room,
+                } => {
+                    if let Some(link) = Federation::link_back(self, &name, port, read_port, &room) {
+                        _link_back = Some(link);
+                    }
                 }
-                Request::UserList => todo!(),
             }
         }
     }
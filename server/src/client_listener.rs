@@ -1,57 +1,179 @@
 use std::{
-    net::TcpListener,
-    sync::{Arc, Mutex},
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
 };
 
+use chat_core::{request::RequestError, response::Response, write::ChatWriter};
 use rayon::{ThreadPool, ThreadPoolBuilder};
 
-use crate::{broadcast::Broadcaster, client::Client, config::ServerConfig};
+use crate::{
+    broadcast::{BroadcastMessage, Broadcaster},
+    client::{Client, ClientServices},
+    command::CommandRegistry,
+    connection::Connection,
+    connection_limit::{ConnectionSlot, ConnectionTracker},
+    federation::Federation,
+    gateway::{irc::Irc, websocket::WebSocketProtocol, GatewayListener},
+    http_api::HttpApi,
+    metrics::{Metrics, MetricsServer},
+    plugin::{self, Plugin, PluginRegistry},
+    rate_limit::IpRateLimiter,
+    reload::SharedConfig,
+    shutdown::{wait_until, ShutdownHandle},
+};
 
 pub struct ClientListener {
     pool: ThreadPool,
     listener: TcpListener,
-    config: ServerConfig,
+    config: Arc<SharedConfig>,
+    shutdown: ShutdownHandle,
+    plugins: Vec<Box<dyn Plugin>>,
+    http_api: Option<HttpApi>,
+    metrics: Option<MetricsServer>,
+    /// Listeners for the protocols other than the native one that are enabled
+    gateways: Vec<GatewayListener>,
 }
 
 impl ClientListener {
     // Maybe later remove Box<dyn std::error::Error> for a custom error type, but is this even needed?
-    pub fn new(config: ServerConfig) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(config: SharedConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::with_plugins(config, &PluginRegistry::with_builtins())
+    }
+    /// Create a listener that takes the plugins enabled in the config from `plugins`, for binaries that register their
+    /// own plugins. The plugins are created here so a config naming a plugin that does not exist is an error.
+    pub fn with_plugins(
+        config: SharedConfig,
+        plugins: &PluginRegistry,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         log::info!("creating new client listener");
+        let current = config.get();
+        let plugins = plugins.create(&current.plugins)?;
+        let listener = TcpListener::bind(current.net.ip())?;
+        let http_api = match current.http_api.enabled() {
+            true => Some(HttpApi::bind(current.http_api.ip())?),
+            false => None,
+        };
+        let metrics = match current.metrics.enabled() {
+            true => Some(MetricsServer::bind(current.metrics.ip())?),
+            false => None,
+        };
+        let mut gateways = Vec::new();
+        if current.irc.enabled() {
+            gateways.push(GatewayListener::bind(current.irc.ip(), Irc)?);
+        }
+        if current.websocket.enabled() {
+            gateways.push(GatewayListener::bind(
+                current.websocket.ip(),
+                WebSocketProtocol,
+            )?);
+        }
         Ok(Self {
-            listener: TcpListener::bind("127.0.0.1:1234")?,
-            pool: ThreadPoolBuilder::new().num_threads(20).build()?,
-            config,
+            plugins,
+            http_api,
+            metrics,
+            gateways,
+            shutdown: ShutdownHandle::new(&listener)?,
+            listener,
+            pool: ThreadPoolBuilder::new()
+                .num_threads(current.system.threads())
+                .build()?,
+            config: Arc::new(config),
         })
     }
+    /// Returns the config used by the listener and its clients, it can be reloaded while the listener is running
+    pub fn config(&self) -> Arc<SharedConfig> {
+        Arc::clone(&self.config)
+    }
+    /// The address clients connect to, which has the port picked if the config asked for port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+    /// Returns a handle that can be used to stop `run()` from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+    /// Accept clients until a shutdown is requested, then disconnect every client and wait for them to stop
     pub fn run(self) {
         log::info!("listening for clients");
-        let message_broadcaster = Arc::new(Mutex::new(Broadcaster::default().run()));
+        let config = self.config;
+        let mut commands = CommandRegistry::with_builtins();
+        plugin::register_commands(&mut commands, &self.plugins);
+
+        // Measured even when the endpoint is off, it is only a few counters
+        let metrics = Arc::new(Metrics::default());
+        metrics.set_pool_threads(self.pool.current_num_threads());
+        let metrics_server = self.metrics.map(|server| server.run(Arc::clone(&metrics)));
+
+        let (message_broadcaster, broadcaster_thread) =
+            Broadcaster::new(Arc::clone(&config), self.plugins, Arc::clone(&metrics)).run();
+        let message_broadcaster = Arc::new(Mutex::new(message_broadcaster));
+        let http_api = self.http_api.map(|http_api| {
+            http_api.run(
+                Arc::clone(&config),
+                Arc::clone(&message_broadcaster),
+                Arc::clone(&metrics),
+            )
+        });
+        let port = self
+            .listener
+            .local_addr()
+            .ok()
+            .map(|address| address.port());
+        let federation = Federation::start(
+            Arc::clone(&config),
+            port,
+            Arc::clone(&message_broadcaster),
+            Arc::clone(&metrics),
+        );
+
+        let spawner = ClientSpawner {
+            pool: Arc::new(self.pool),
+            next_key: Arc::new(AtomicUsize::new(config.get().system.key_start() + 1)),
+            connections: Arc::new(ConnectionTracker::default()),
+            services: ClientServices {
+                broadcaster: Arc::clone(&message_broadcaster),
+                config: Arc::clone(&config),
+                commands: Arc::new(commands),
+                ip_limiter: Arc::new(IpRateLimiter::default()),
+                metrics: Arc::clone(&metrics),
+            },
+        };
+        let gateways = self
+            .gateways
+            .into_iter()
+            .filter_map(|gateway| {
+                gateway
+                    .run(spawner.clone(), self.shutdown.clone())
+                    .map_err(|error| log::error!("failed to start gateway: {error}"))
+                    .ok()
+            })
+            .collect::<Vec<_>>();
 
-        let config = Arc::new(self.config);
-        let mut key = config.system.key_start();
         for stream in self.listener.incoming() {
+            if self.shutdown.requested().is_some() {
+                break;
+            }
+
             log::info!("got connection");
-            key += 1;
             match stream {
-                Ok(stream) => {
-                    self.pool.spawn({
-                        let message_broadcaster = Arc::clone(&message_broadcaster);
-                        let config = Arc::clone(&config);
-                        move || {
-                            match Client::make_connection(
-                                key,
-                                stream,
-                                message_broadcaster,
-                                config,
-                            ) {
-                                Ok(client) => client,
-                                Err(error) => {
-                                    log::error!("failed to create client: {error}");
-                                    return;
-                                },
-                            }
-                            .run()
+                Ok(mut stream) => {
+                    // Refuse connections over the limits before they take up a thread in the pool
+                    let slot = match spawner.acquire_slot(&stream) {
+                        Ok(slot) => slot,
+                        Err(error) => {
+                            log::warn!("refusing connection: {error}");
+                            stream.write_data(&Response::Err(error)).ok();
+                            continue;
                         }
+                    };
+
+                    spawner.spawn(slot, move |_, services| {
+                        Connection::native(stream, services.config.get().net.read_port())
                     });
                 }
                 Err(error) => {
@@ -59,5 +181,81 @@ impl ClientListener {
                 }
             }
         }
+
+        let notice = self.shutdown.requested().unwrap_or_default();
+        log::info!("no longer accepting clients");
+        drop(self.listener);
+        if let Some(http_api) = http_api {
+            http_api.stop();
+        }
+        for gateway in gateways {
+            gateway.stop();
+        }
+        federation.stop();
+
+        let deadline = Instant::now() + config.get().shutdown.timeout();
+        message_broadcaster
+            .lock()
+            .unwrap()
+            .send(BroadcastMessage::Shutdown(notice))
+            .ok();
+
+        if !wait_until(deadline, || broadcaster_thread.is_finished()) {
+            log::warn!("broadcaster did not stop in time");
+        }
+        let connections = &spawner.connections;
+        if !wait_until(deadline, || connections.total() == 0) {
+            log::warn!(
+                "{} client(s) did not disconnect in time",
+                connections.total()
+            );
+        }
+
+        if let Some(metrics_server) = metrics_server {
+            metrics_server.stop();
+        }
+        log::info!("client listener stopped");
+    }
+}
+
+/// Starts client handlers on the thread pool, shared by the listener and the gateways so every client counts
+/// against the same limits and gets its own key
+#[derive(Clone)]
+pub struct ClientSpawner {
+    pool: Arc<ThreadPool>,
+    next_key: Arc<AtomicUsize>,
+    connections: Arc<ConnectionTracker>,
+    services: ClientServices,
+}
+
+impl ClientSpawner {
+    /// Take a connection slot for the client on the other end of `stream`, connections over the limits are refused
+    /// before they take up a thread in the pool
+    pub fn acquire_slot(&self, stream: &TcpStream) -> Result<ConnectionSlot, RequestError> {
+        let ip = stream.peer_addr().map_err(|_| RequestError::Ip)?.ip();
+
+        self.connections
+            .try_acquire(ip, &self.services.config.get().limits)
+            .map_err(RequestError::ConnectionRefused)
+    }
+    /// Run `connect` on the pool to create the connection of a new client, then handle the client on the same thread.
+    /// The slot is given back once the client is gone.
+    pub fn spawn<F>(&self, slot: ConnectionSlot, connect: F)
+    where
+        F: FnOnce(usize, &ClientServices) -> Result<Connection, io::Error> + Send + 'static,
+    {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        let services = self.services.clone();
+        let queued = self.services.metrics.client_queued();
+
+        self.pool.spawn(move || {
+            let _slot = slot;
+            let _busy = queued.start();
+
+            match connect(key, &services) {
+                Ok(connection) => Client::new(key, connection, services).run(),
+                Err(error) => log::error!("failed to create client: {error}"),
+            }
+        });
     }
 }
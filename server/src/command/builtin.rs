@@ -0,0 +1,224 @@
+use chat_core::{
+    command::CommandError,
+    message::{Message, MessageKind},
+    request::RequestError,
+    value::Value,
+};
+
+use crate::{broadcast::BroadcastMessage, client::SERVER_USER};
+
+use super::{Args, Command, CommandContext, CommandRegistry, Role};
+
+/// Register every built in command
+pub fn register_all(registry: &mut CommandRegistry) {
+    registry.register(Nick);
+    registry.register(Me);
+    registry.register(Who);
+    registry.register(Join);
+    registry.register(Msg);
+    registry.register(Help);
+    registry.register(Kick);
+}
+
+/// Max length of a room name
+const MAX_ROOM_LENGTH: usize = 32;
+
+/// Room names are short and made of letters, digits, `-` and `_`
+pub fn valid_room(room: &str) -> bool {
+    !room.is_empty()
+        && room.len() <= MAX_ROOM_LENGTH
+        && room
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+pub struct Nick;
+
+impl Command for Nick {
+    fn name(&self) -> &'static str {
+        "nick"
+    }
+    fn usage(&self) -> &'static str {
+        "/nick <username>"
+    }
+    fn help(&self) -> &'static str {
+        "change your username"
+    }
+    fn execute(&self, ctx: &mut CommandContext, mut args: Args) -> Result<(), RequestError> {
+        let name = args.rest().ok_or_else(|| self.usage_error())?;
+
+        ctx.client.change_username(ctx.user, Value::from(name))
+    }
+}
+
+pub struct Me;
+
+impl Command for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+    fn usage(&self) -> &'static str {
+        "/me <action>"
+    }
+    fn help(&self) -> &'static str {
+        "describe what you are doing, e.g. /me waves"
+    }
+    fn execute(&self, ctx: &mut CommandContext, mut args: Args) -> Result<(), RequestError> {
+        let action = args.rest().ok_or_else(|| self.usage_error())?;
+
+        let message =
+            ctx.client
+                .check_message(ctx.user, Value::from(action), MessageKind::Action)?;
+        ctx.client.broadcast(BroadcastMessage::ChatMessage(message));
+
+        Ok(())
+    }
+}
+
+pub struct Who;
+
+impl Command for Who {
+    fn name(&self) -> &'static str {
+        "who"
+    }
+    fn usage(&self) -> &'static str {
+        "/who"
+    }
+    fn help(&self) -> &'static str {
+        "list the users in your room"
+    }
+    fn execute(&self, ctx: &mut CommandContext, _args: Args) -> Result<(), RequestError> {
+        ctx.client
+            .broadcast(BroadcastMessage::UserList(ctx.client.key()));
+
+        Ok(())
+    }
+}
+
+pub struct Join;
+
+impl Command for Join {
+    fn name(&self) -> &'static str {
+        "join"
+    }
+    fn usage(&self) -> &'static str {
+        "/join <room>"
+    }
+    fn help(&self) -> &'static str {
+        "leave your current room and join another one"
+    }
+    fn execute(&self, ctx: &mut CommandContext, mut args: Args) -> Result<(), RequestError> {
+        let room = args.next_arg().ok_or_else(|| self.usage_error())?;
+        let room = room.strip_prefix('#').unwrap_or(room);
+
+        if !valid_room(room) {
+            return Err(CommandError::BadRoom(room.to_owned()).into());
+        }
+
+        ctx.client.join_room(ctx.user, room.to_owned());
+
+        Ok(())
+    }
+}
+
+pub struct Msg;
+
+impl Command for Msg {
+    fn name(&self) -> &'static str {
+        "msg"
+    }
+    fn usage(&self) -> &'static str {
+        "/msg <username> <message>"
+    }
+    fn help(&self) -> &'static str {
+        "send a message only the given user can see"
+    }
+    fn execute(&self, ctx: &mut CommandContext, mut args: Args) -> Result<(), RequestError> {
+        let (to, text) = args
+            .next_arg()
+            .zip(args.rest())
+            .ok_or_else(|| self.usage_error())?;
+
+        let message = ctx
+            .client
+            .check_message(ctx.user, Value::from(text), MessageKind::Direct)?;
+        ctx.client.broadcast(BroadcastMessage::DirectMessage {
+            to: to.to_owned(),
+            message,
+        });
+
+        Ok(())
+    }
+}
+
+pub struct Help;
+
+impl Command for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+    fn usage(&self) -> &'static str {
+        "/help [command]"
+    }
+    fn help(&self) -> &'static str {
+        "list commands, or show how to use one"
+    }
+    fn execute(&self, ctx: &mut CommandContext, mut args: Args) -> Result<(), RequestError> {
+        let role = ctx.client.role();
+
+        let text = match args.next_arg() {
+            Some(name) => {
+                let name = name.trim_start_matches(super::COMMAND_PREFIX);
+                let command = ctx
+                    .registry
+                    .get(name)
+                    .ok_or_else(|| CommandError::Unknown(name.to_owned()))?;
+                format!("{} - {}", command.usage(), command.help())
+            }
+            None => ctx
+                .registry
+                .commands()
+                .filter(|command| role >= command.permission())
+                .map(|command| format!("{} - {}", command.usage(), command.help()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+
+        ctx.client.respond(
+            Message::builder()
+                .from_who(SERVER_USER.clone())
+                .payload(Value::String(text))
+                .kind(MessageKind::Direct)
+                .build(),
+        );
+
+        Ok(())
+    }
+}
+
+pub struct Kick;
+
+impl Command for Kick {
+    fn name(&self) -> &'static str {
+        "kick"
+    }
+    fn usage(&self) -> &'static str {
+        "/kick <username>"
+    }
+    fn help(&self) -> &'static str {
+        "disconnect a user from the server"
+    }
+    fn permission(&self) -> Role {
+        Role::Moderator
+    }
+    fn execute(&self, ctx: &mut CommandContext, mut args: Args) -> Result<(), RequestError> {
+        let target = args.next_arg().ok_or_else(|| self.usage_error())?;
+
+        ctx.client.broadcast(BroadcastMessage::Kick {
+            target: target.to_owned(),
+            by: ctx.client.key(),
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+
+use chat_core::{command::CommandError, request::RequestError, user::User};
+
+use crate::client::Client;
+
+pub mod builtin;
+
+/// The character a `SendMessage` payload has to start with to be treated as a command
+pub const COMMAND_PREFIX: char = '/';
+
+/// Whether the text of a `SendMessage` is a command, a doubled prefix such as `//shrug` escapes text that should be
+/// sent as it is
+pub fn is_command(text: &str) -> bool {
+    text.strip_prefix(COMMAND_PREFIX)
+        .is_some_and(|rest| !rest.starts_with(COMMAND_PREFIX))
+}
+
+/// The text to send for a message that is not a command, with one prefix taken off if it was escaped
+pub fn unescape(text: &str) -> &str {
+    text.strip_prefix(COMMAND_PREFIX)
+        .filter(|rest| rest.starts_with(COMMAND_PREFIX))
+        .unwrap_or(text)
+}
+
+/// What a client is allowed to do, roles are ordered so a higher role can do everything a lower one can
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    #[default]
+    User,
+    Moderator,
+}
+
+/// Everything a command can touch while it is executing
+pub struct CommandContext<'a> {
+    pub client: &'a mut Client,
+    pub user: &'a mut User,
+    pub registry: &'a CommandRegistry,
+}
+
+/// The arguments given to a command (everything after the command name)
+pub struct Args<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(args: &'a str) -> Self {
+        Self {
+            rest: args.trim_start(),
+        }
+    }
+    /// Take the next whitespace separated argument
+    pub fn next_arg(&mut self) -> Option<&'a str> {
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        let (arg, rest) = self
+            .rest
+            .split_once(char::is_whitespace)
+            .unwrap_or((self.rest, ""));
+        self.rest = rest.trim_start();
+
+        Some(arg)
+    }
+    /// Take all of the remaining arguments as a single string
+    pub fn rest(&mut self) -> Option<&'a str> {
+        let rest = std::mem::take(&mut self.rest).trim_end();
+        (!rest.is_empty()).then_some(rest)
+    }
+}
+
+pub trait Command: Send + Sync {
+    /// Name of the command, used as `/name`
+    fn name(&self) -> &'static str;
+    /// How the command is used, shown in `/help` and when the arguments are wrong
+    fn usage(&self) -> &'static str;
+    /// Short description of what the command does
+    fn help(&self) -> &'static str;
+    /// The lowest role that can run this command
+    fn permission(&self) -> Role {
+        Role::User
+    }
+    fn execute(&self, ctx: &mut CommandContext, args: Args) -> Result<(), RequestError>;
+
+    /// Error to return when the arguments given do not match `usage()`
+    fn usage_error(&self) -> RequestError {
+        CommandError::Usage(self.usage().to_owned()).into()
+    }
+}
+
+/// Holds every command the server knows about, new commands can be added with `CommandRegistry::register()`
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: BTreeMap<&'static str, Box<dyn Command>>,
+}
+
+impl CommandRegistry {
+    /// Create a registry with all of the commands in `builtin`
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::default();
+        builtin::register_all(&mut registry);
+        registry
+    }
+    /// Add a command, replacing any command that has the same name
+    pub fn register<C>(&mut self, command: C)
+    where
+        C: Command + 'static,
+    {
+        log::debug!("registering command /{}", command.name());
+        self.commands.insert(command.name(), Box::new(command));
+    }
+    pub fn get(&self, name: &str) -> Option<&dyn Command> {
+        self.commands.get(name).map(|command| command.as_ref())
+    }
+    /// All commands sorted by name
+    pub fn commands(&self) -> impl Iterator<Item = &dyn Command> {
+        self.commands.values().map(|command| command.as_ref())
+    }
+    /// Parse a line such as `/nick joey` and run the command, `line` must start with `COMMAND_PREFIX`
+    pub fn execute(
+        &self,
+        client: &mut Client,
+        user: &mut User,
+        line: &str,
+    ) -> Result<(), RequestError> {
+        let line = line.strip_prefix(COMMAND_PREFIX).unwrap_or(line);
+        let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+        let command = self
+            .get(name)
+            .ok_or_else(|| CommandError::Unknown(name.to_owned()))?;
+
+        if client.role() < command.permission() {
+            log::info!("{user} tried to use /{name} without permission");
+            return Err(CommandError::PermissionDenied(name.to_owned()).into());
+        }
+
+        log::debug!("{user} is running /{name}");
+        command.execute(
+            &mut CommandContext {
+                client,
+                user,
+                registry: self,
+            },
+            Args::new(args),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{Ipv4Addr, SocketAddr},
+        sync::{mpsc, Arc, Mutex},
+    };
+
+    use chat_core::{
+        event::Event, message::Message, request::Request, response::Response, user::Username,
+        value::Value,
+    };
+
+    use super::*;
+    use crate::{
+        client::{ClientServices, SERVER_USER},
+        config::ServerConfig,
+        connection::{Connection, RequestReader, ResponseWriter},
+        metrics::Metrics,
+        rate_limit::IpRateLimiter,
+        reload::SharedConfig,
+    };
+
+    struct NoRequests;
+
+    impl RequestReader for NoRequests {
+        fn read_request(&mut self) -> Result<Request, io::Error> {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    /// Keeps the text of the messages sent to the client
+    #[derive(Default)]
+    struct Sent(Vec<String>);
+
+    impl ResponseWriter for Sent {
+        fn write_response(&mut self, response: &Response) -> Result<(), io::Error> {
+            if let Ok(Event::Message(message)) = response {
+                self.0.push(message.payload().to_string());
+            }
+            Ok(())
+        }
+        fn disconnect(&mut self) {}
+    }
+
+    /// Takes the arguments apart the way most commands do and sends them back
+    struct Echo;
+
+    impl Command for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+        fn usage(&self) -> &'static str {
+            "/echo <first> [rest]"
+        }
+        fn help(&self) -> &'static str {
+            "say the arguments back"
+        }
+        fn execute(&self, ctx: &mut CommandContext, mut args: Args) -> Result<(), RequestError> {
+            let first = args.next_arg().ok_or_else(|| self.usage_error())?;
+            let rest = args.rest().unwrap_or_default();
+            ctx.client.respond(
+                Message::builder()
+                    .from_who(SERVER_USER.clone())
+                    .payload(Value::String(format!("{first}|{rest}")))
+                    .build(),
+            );
+            Ok(())
+        }
+    }
+
+    struct Secret;
+
+    impl Command for Secret {
+        fn name(&self) -> &'static str {
+            "secret"
+        }
+        fn usage(&self) -> &'static str {
+            "/secret"
+        }
+        fn help(&self) -> &'static str {
+            "only for moderators"
+        }
+        fn permission(&self) -> Role {
+            Role::Moderator
+        }
+        fn execute(&self, _ctx: &mut CommandContext, _args: Args) -> Result<(), RequestError> {
+            Ok(())
+        }
+    }
+
+    fn registry() -> CommandRegistry {
+        let mut registry = CommandRegistry::with_builtins();
+        registry.register(Echo);
+        registry.register(Secret);
+        registry
+    }
+
+    /// Run `line` as a client connected from 127.0.0.1, a moderator if `moderator`. Returns the result along with the
+    /// text of the messages the client was sent.
+    fn run(line: &str, moderator: bool) -> (Result<(), RequestError>, Vec<String>) {
+        let moderators = match moderator {
+            true => r#"moderators = ["127.0.0.1"]"#,
+            false => "",
+        };
+        let config: ServerConfig = toml::from_str(&format!("[moderation]\n{moderators}")).unwrap();
+        let sent = Arc::new(Mutex::new(Sent::default()));
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, 4321));
+        let (broadcaster, _broadcasts) = mpsc::channel();
+        let registry = Arc::new(registry());
+
+        let mut client = Client::new(
+            1,
+            Connection {
+                reader: Box::new(NoRequests),
+                writer: sent.clone(),
+                addresses: (address, address),
+            },
+            ClientServices {
+                broadcaster: Arc::new(Mutex::new(broadcaster)),
+                config: Arc::new(SharedConfig::new(config, None)),
+                commands: Arc::clone(&registry),
+                ip_limiter: Arc::new(IpRateLimiter::default()),
+                metrics: Arc::new(Metrics::default()),
+            },
+        );
+        let mut user = User::builder().id(1).username(Username::new("amy")).build();
+
+        let result = registry.execute(&mut client, &mut user, line);
+        let sent = std::mem::take(&mut sent.lock().unwrap().0);
+        (result, sent)
+    }
+
+    #[test]
+    fn args_are_split_on_whitespace() {
+        let mut args = Args::new("  one \t two   three four  ");
+        assert_eq!(args.next_arg(), Some("one"));
+        assert_eq!(args.next_arg(), Some("two"));
+        assert_eq!(args.rest(), Some("three four"));
+        assert_eq!(args.next_arg(), None);
+        assert_eq!(args.rest(), None);
+
+        assert_eq!(Args::new("").next_arg(), None);
+        assert_eq!(Args::new("   ").rest(), None);
+    }
+
+    #[test]
+    fn commands_get_the_text_after_their_name() {
+        let (result, sent) = run("/echo  first  and the rest ", false);
+        assert!(result.is_ok());
+        assert_eq!(sent, ["first|and the rest"]);
+
+        let (result, _) = run("/echo", false);
+        assert!(matches!(
+            result,
+            Err(RequestError::Command(CommandError::Usage(usage))) if usage == "/echo <first> [rest]"
+        ));
+    }
+
+    #[test]
+    fn doubled_prefix_escapes_commands() {
+        assert!(is_command("/nick amy"));
+        assert!(is_command("/"));
+        assert!(!is_command("//shrug"));
+        assert!(!is_command("hello /nick"));
+        assert!(!is_command(""));
+
+        assert_eq!(unescape("//shrug"), "/shrug");
+        assert_eq!(unescape("///"), "//");
+        assert_eq!(unescape("hello"), "hello");
+    }
+
+    #[test]
+    fn unknown_commands_are_refused() {
+        let (result, sent) = run("/nope at all", false);
+        assert!(matches!(
+            result,
+            Err(RequestError::Command(CommandError::Unknown(name))) if name == "nope"
+        ));
+        assert!(sent.is_empty());
+        // Names are case sensitive
+        assert!(run("/ECHO a", false).0.is_err());
+    }
+
+    #[test]
+    fn commands_above_the_role_of_the_client_are_refused() {
+        let (result, _) = run("/secret", false);
+        assert!(matches!(
+            result,
+            Err(RequestError::Command(CommandError::PermissionDenied(name))) if name == "secret"
+        ));
+        assert!(run("/secret", true).0.is_ok());
+    }
+
+    #[test]
+    fn help_lists_the_commands_the_client_can_use() {
+        let (result, sent) = run("/help", false);
+        assert!(result.is_ok());
+        let [help] = sent.as_slice() else {
+            panic!("expected one message, got {sent:?}");
+        };
+        let lines = help.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"/echo <first> [rest] - say the arguments back"));
+        assert!(lines.contains(&"/help [command] - list commands, or show how to use one"));
+        assert!(!help.contains("/secret"));
+        assert!(!help.contains("/kick"));
+        // Sorted by name
+        let mut sorted = lines.clone();
+        sorted.sort();
+        assert_eq!(lines, sorted);
+
+        let (_, sent) = run("/help", true);
+        assert!(sent[0].contains("/secret - only for moderators"));
+        assert!(sent[0].contains("/kick <username>"));
+    }
+
+    #[test]
+    fn help_shows_one_command() {
+        let (_, sent) = run("/help echo", false);
+        assert_eq!(sent, ["/echo <first> [rest] - say the arguments back"]);
+        let (_, sent) = run("/help /echo", false);
+        assert_eq!(sent, ["/echo <first> [rest] - say the arguments back"]);
+
+        let (result, _) = run("/help nope", false);
+        assert!(matches!(
+            result,
+            Err(RequestError::Command(CommandError::Unknown(name))) if name == "nope"
+        ));
+    }
+}
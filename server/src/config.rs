@@ -1,15 +1,72 @@
-use chat_core::{config::Config, message::MessageGuidelines, user::UsernameGuidelines};
+use std::{
+    net::{IpAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use chat_core::{
+    config::{Config, Migration, Validate, Validator},
+    message::MessageGuidelines,
+    request::ShutdownNotice,
+    user::UsernameGuidelines,
+};
+use ipnet::IpNet;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-#[derive(Default, Deserialize, Serialize)]
+use crate::command::builtin::valid_room;
+
+/// Shortest token an integration of the http api can have
+const MIN_TOKEN_LENGTH: usize = 16;
+
+/// Every table and value can be left out of the config file, missing values are the defaults
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
 pub struct ServerConfig {
+    config_version: u32,
     pub net: NetConfig,
     pub system: SystemConfig,
     pub message_guidelines: MessageGuidelines,
     pub username_guidelines: UsernameGuidelines,
+    pub moderation: ModerationConfig,
+    pub rate_limit: RateLimitConfig,
+    pub limits: LimitsConfig,
+    pub shutdown: ShutdownConfig,
+    pub history: HistoryConfig,
+    pub plugins: PluginsConfig,
+    pub webhooks: Vec<WebhookConfig>,
+    pub http_api: HttpApiConfig,
+    pub irc: IrcConfig,
+    pub websocket: WebSocketConfig,
+    pub federation: FederationConfig,
+    pub metrics: MetricsConfig,
 }
 
-#[derive(Deserialize, Serialize)]
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            config_version: Self::VERSION,
+            net: NetConfig::default(),
+            system: SystemConfig::default(),
+            message_guidelines: MessageGuidelines::default(),
+            username_guidelines: UsernameGuidelines::default(),
+            moderation: ModerationConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            limits: LimitsConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            history: HistoryConfig::default(),
+            plugins: PluginsConfig::default(),
+            webhooks: Vec::new(),
+            http_api: HttpApiConfig::default(),
+            irc: IrcConfig::default(),
+            websocket: WebSocketConfig::default(),
+            federation: FederationConfig::default(),
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct NetConfig {
     ip: String,
     read_port: u16,
@@ -33,7 +90,8 @@ impl NetConfig {
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
 pub struct SystemConfig {
     threads: usize,
     key_start: usize,
@@ -62,4 +120,889 @@ impl SystemConfig {
     }
 }
 
-impl Config for ServerConfig {}
+impl ServerConfig {
+    /// Copy the settings that are only used when the server starts from `old`, returns the names of the settings that
+    /// were different
+    pub fn keep_unreloadable(&mut self, old: &ServerConfig) -> Vec<&'static str> {
+        let mut kept = Vec::new();
+
+        if self.net != old.net {
+            kept.push("net");
+            self.net = old.net.clone();
+        }
+        if self.system.threads != old.system.threads {
+            kept.push("system.threads");
+            self.system.threads = old.system.threads;
+        }
+        if self.system.key_start != old.system.key_start {
+            kept.push("system.key_start");
+            self.system.key_start = old.system.key_start;
+        }
+        if self.history.max_messages != old.history.max_messages {
+            kept.push("history.max_messages");
+            self.history.max_messages = old.history.max_messages;
+        }
+        if self.plugins != old.plugins {
+            kept.push("plugins");
+            self.plugins = old.plugins.clone();
+        }
+        if self.webhooks != old.webhooks {
+            kept.push("webhooks");
+            self.webhooks = old.webhooks.clone();
+        }
+        if self.http_api.enabled != old.http_api.enabled {
+            kept.push("http_api.enabled");
+            self.http_api.enabled = old.http_api.enabled;
+        }
+        if self.http_api.ip != old.http_api.ip {
+            kept.push("http_api.ip");
+            self.http_api.ip = old.http_api.ip.clone();
+        }
+        if self.irc.enabled != old.irc.enabled {
+            kept.push("irc.enabled");
+            self.irc.enabled = old.irc.enabled;
+        }
+        if self.irc.ip != old.irc.ip {
+            kept.push("irc.ip");
+            self.irc.ip = old.irc.ip.clone();
+        }
+        if self.websocket.enabled != old.websocket.enabled {
+            kept.push("websocket.enabled");
+            self.websocket.enabled = old.websocket.enabled;
+        }
+        if self.websocket.ip != old.websocket.ip {
+            kept.push("websocket.ip");
+            self.websocket.ip = old.websocket.ip.clone();
+        }
+        if self.federation != old.federation {
+            kept.push("federation");
+            self.federation = old.federation.clone();
+        }
+        if self.metrics != old.metrics {
+            kept.push("metrics");
+            self.metrics = old.metrics.clone();
+        }
+
+        kept
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ModerationConfig {
+    moderators: Vec<IpAddr>,
+}
+
+impl ModerationConfig {
+    pub fn moderators(&self) -> &[IpAddr] {
+        &self.moderators
+    }
+    pub fn is_moderator(&self, ip: IpAddr) -> bool {
+        self.moderators.contains(&ip)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    enabled: bool,
+    messages_per_second: f64,
+    burst: u32,
+    ip_messages_per_second: f64,
+    ip_burst: u32,
+    max_repeats: u32,
+    repeat_window_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            messages_per_second: 1.0,
+            burst: 5,
+            ip_messages_per_second: 4.0,
+            ip_burst: 20,
+            max_repeats: 3,
+            repeat_window_secs: 30,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+    pub fn messages_per_second(&self) -> f64 {
+        self.messages_per_second
+    }
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+    pub fn ip_messages_per_second(&self) -> f64 {
+        self.ip_messages_per_second
+    }
+    pub fn ip_burst(&self) -> u32 {
+        self.ip_burst
+    }
+    pub fn max_repeats(&self) -> u32 {
+        self.max_repeats
+    }
+    pub fn repeat_window(&self) -> Duration {
+        Duration::from_secs(self.repeat_window_secs)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 20,
+            max_connections_per_ip: 5,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+    pub fn max_connections_per_ip(&self) -> usize {
+        self.max_connections_per_ip
+    }
+    pub fn allow(&self) -> &[IpNet] {
+        &self.allow
+    }
+    pub fn deny(&self) -> &[IpNet] {
+        &self.deny
+    }
+    /// An ip is allowed if it is not in any deny range, and it is in an allow range (if there are any)
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    timeout_secs: u64,
+    reason: Option<String>,
+    restart_eta_secs: Option<u64>,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 10,
+            reason: None,
+            restart_eta_secs: None,
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+    /// The notice sent to clients when the server is stopped by a signal
+    pub fn notice(&self) -> ShutdownNotice {
+        ShutdownNotice {
+            reason: self.reason.clone(),
+            restart_eta_secs: self.restart_eta_secs,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    max_messages: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self { max_messages: 1000 }
+    }
+}
+
+impl HistoryConfig {
+    pub fn max_messages(&self) -> usize {
+        self.max_messages
+    }
+}
+
+/// Which plugins run and their settings, every table in `[plugins]` is the settings of the plugin it is named after
+#[derive(Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PluginsConfig {
+    enabled: Vec<String>,
+    #[serde(flatten)]
+    settings: toml::Table,
+}
+
+impl PluginsConfig {
+    /// Names of the plugins to run, in the order they are called
+    pub fn enabled(&self) -> &[String] {
+        &self.enabled
+    }
+    /// The settings of the plugin, empty if it has none
+    pub fn settings(&self, plugin: &str) -> toml::Table {
+        match self.settings.get(plugin) {
+            Some(toml::Value::Table(settings)) => settings.clone(),
+            _ => toml::Table::new(),
+        }
+    }
+}
+
+/// The kinds of events a webhook can be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    /// A message sent to a room or to everyone
+    Message,
+    /// A client connected or moved into a room
+    Join,
+    /// A client disconnected
+    Leave,
+    /// A moderator kicked someone, or edited or deleted a message that is not theirs
+    Moderation,
+}
+
+/// An endpoint that events are POSTed to as json
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    url: String,
+    events: Vec<WebhookEventKind>,
+    rooms: Vec<String>,
+    pattern: Option<String>,
+    queue_size: usize,
+    max_retries: u32,
+    retry_delay_ms: u64,
+    timeout_secs: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            events: vec![
+                WebhookEventKind::Message,
+                WebhookEventKind::Join,
+                WebhookEventKind::Leave,
+                WebhookEventKind::Moderation,
+            ],
+            rooms: Vec::new(),
+            pattern: None,
+            queue_size: 100,
+            max_retries: 5,
+            retry_delay_ms: 500,
+            timeout_secs: 5,
+        }
+    }
+}
+
+impl WebhookConfig {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+    pub fn events(&self) -> &[WebhookEventKind] {
+        &self.events
+    }
+    /// Only events in these rooms are sent, every room if it is empty. Events that are not in a room are always sent.
+    pub fn rooms(&self) -> &[String] {
+        &self.rooms
+    }
+    /// Only messages matching this regular expression are sent
+    pub fn pattern(&self) -> Option<&str> {
+        self.pattern.as_deref()
+    }
+    /// How many events can wait to be sent, events are dropped while the queue is full
+    pub fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+    /// How long to wait before the first retry, every retry after that waits twice as long as the one before
+    pub fn retry_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_delay_ms)
+    }
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+/// An http endpoint integrations such as CI can post messages to
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HttpApiConfig {
+    enabled: bool,
+    ip: String,
+    max_body_bytes: usize,
+    integrations: Vec<IntegrationConfig>,
+}
+
+impl Default for HttpApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ip: "127.0.0.1:8080".to_owned(),
+            max_body_bytes: 64 * 1024,
+            integrations: Vec::new(),
+        }
+    }
+}
+
+impl HttpApiConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+    pub fn ip(&self) -> &str {
+        &self.ip
+    }
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+    pub fn integrations(&self) -> &[IntegrationConfig] {
+        &self.integrations
+    }
+}
+
+/// Something allowed to post through the http api, its messages are sent as a bot user named after it
+#[derive(Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IntegrationConfig {
+    name: String,
+    token: String,
+}
+
+impl IntegrationConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+/// A listener for irc clients, see `gateway::irc`
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IrcConfig {
+    enabled: bool,
+    ip: String,
+    server_name: String,
+}
+
+impl Default for IrcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ip: "127.0.0.1:6667".to_owned(),
+            server_name: "chat".to_owned(),
+        }
+    }
+}
+
+impl IrcConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+    pub fn ip(&self) -> &str {
+        &self.ip
+    }
+    /// The name the server uses for itself in irc replies
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+}
+
+/// A listener for clients that speak json over websockets (such as browsers), see `gateway::websocket`
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    enabled: bool,
+    ip: String,
+    max_message_bytes: usize,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ip: "127.0.0.1:8081".to_owned(),
+            max_message_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl WebSocketConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+    pub fn ip(&self) -> &str {
+        &self.ip
+    }
+    /// Largest websocket message a client can send, bigger ones end the connection
+    pub fn max_message_bytes(&self) -> usize {
+        self.max_message_bytes
+    }
+}
+
+/// An http endpoint serving metrics in the prometheus text format, see `metrics`
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    enabled: bool,
+    ip: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ip: "127.0.0.1:9100".to_owned(),
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+    pub fn ip(&self) -> &str {
+        &self.ip
+    }
+}
+
+/// Links to other servers whose rooms are relayed here, see `federation`
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FederationConfig {
+    name: String,
+    accept_links: bool,
+    links: Vec<LinkConfig>,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            name: "chat".to_owned(),
+            accept_links: true,
+            links: Vec::new(),
+        }
+    }
+}
+
+impl FederationConfig {
+    /// Name of this server, the links show up as a user with this name in the rooms they relay
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Whether other servers that link here are linked back to, so their links relay both ways
+    pub fn accept_links(&self) -> bool {
+        self.accept_links
+    }
+    pub fn links(&self) -> &[LinkConfig] {
+        &self.links
+    }
+}
+
+/// Another server to relay rooms from
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LinkConfig {
+    name: String,
+    address: String,
+    read_port: u16,
+    rooms: Vec<String>,
+    retry_delay_ms: u64,
+    both_ways: bool,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            address: String::new(),
+            read_port: 4321,
+            rooms: vec!["general".to_owned()],
+            retry_delay_ms: 1000,
+            both_ways: true,
+        }
+    }
+}
+
+impl LinkConfig {
+    /// Name of the other server, its users show up here as `user@name`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+    /// The `read_port` of the other server, the port it connects back to
+    pub fn read_port(&self) -> u16 {
+        self.read_port
+    }
+    /// Rooms relayed from the other server to the rooms with the same names here
+    pub fn rooms(&self) -> &[String] {
+        &self.rooms
+    }
+    /// How long to wait before connecting again after the link fails, doubled after every failed attempt
+    pub fn retry_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_delay_ms)
+    }
+    /// Whether the other server is asked to link back, so messages from here show up there as well
+    pub fn both_ways(&self) -> bool {
+        self.both_ways
+    }
+    /// A link back to a server that linked here, relaying the one room
+    pub(crate) fn back(name: &str, address: String, read_port: u16, room: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            address,
+            read_port,
+            rooms: vec![room.to_owned()],
+            both_ways: false,
+            ..Self::default()
+        }
+    }
+}
+
+impl Validate for NetConfig {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            self.ip.to_socket_addrs().is_ok(),
+            "ip",
+            format!(
+                "{:?} is not an address to listen on, e.g. \"127.0.0.1:1234\"",
+                self.ip
+            ),
+        );
+        validator.check(self.read_port != 0, "read_port", "cannot be 0");
+    }
+}
+
+impl Validate for SystemConfig {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            self.threads > 0,
+            "threads",
+            "must be more than 0 or no clients could be served",
+        );
+    }
+}
+
+impl Validate for RateLimitConfig {
+    fn validate(&self, validator: &mut Validator) {
+        for (key, rate) in [
+            ("messages_per_second", self.messages_per_second),
+            ("ip_messages_per_second", self.ip_messages_per_second),
+        ] {
+            validator.check(
+                rate.is_finite() && rate > 0.0,
+                key,
+                format!("must be more than 0, got {rate}"),
+            );
+        }
+        validator.check(self.burst > 0, "burst", "must be at least 1");
+        validator.check(self.ip_burst > 0, "ip_burst", "must be at least 1");
+        validator.check(self.max_repeats > 0, "max_repeats", "must be at least 1");
+    }
+}
+
+impl Validate for PluginsConfig {
+    fn validate(&self, validator: &mut Validator) {
+        for (name, settings) in &self.settings {
+            validator.check(
+                settings.is_table(),
+                name,
+                "must be a table with the settings of the plugin",
+            );
+        }
+    }
+}
+
+impl Validate for WebhookConfig {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            self.url.starts_with("http://") || self.url.starts_with("https://"),
+            "url",
+            format!("{:?} is not an http or https url", self.url),
+        );
+        if let Some(pattern) = &self.pattern {
+            if let Err(error) = Regex::new(pattern) {
+                validator.check(false, "pattern", error.to_string());
+            }
+        }
+        validator.check(self.queue_size > 0, "queue_size", "must be at least 1");
+        validator.check(self.timeout_secs > 0, "timeout_secs", "must be more than 0");
+    }
+}
+
+impl Validate for HttpApiConfig {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            self.ip.to_socket_addrs().is_ok(),
+            "ip",
+            format!(
+                "{:?} is not an address to listen on, e.g. \"127.0.0.1:8080\"",
+                self.ip
+            ),
+        );
+        validator.check(
+            self.max_body_bytes > 0,
+            "max_body_bytes",
+            "must be more than 0",
+        );
+        for (index, integration) in self.integrations.iter().enumerate() {
+            let key = format!("integrations.{index}");
+            validator.check(
+                !integration.name.trim().is_empty(),
+                &format!("{key}.name"),
+                "cannot be empty, it is the username messages are sent as",
+            );
+            validator.check(
+                integration.token.len() >= MIN_TOKEN_LENGTH,
+                &format!("{key}.token"),
+                format!("must be at least {MIN_TOKEN_LENGTH} characters so it cannot be guessed"),
+            );
+            validator.check(
+                !self.integrations[..index]
+                    .iter()
+                    .any(|other| other.token == integration.token),
+                &format!("{key}.token"),
+                "is used by another integration",
+            );
+        }
+    }
+}
+
+impl Validate for IrcConfig {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            self.ip.to_socket_addrs().is_ok(),
+            "ip",
+            format!(
+                "{:?} is not an address to listen on, e.g. \"127.0.0.1:6667\"",
+                self.ip
+            ),
+        );
+        validator.check(
+            !self.server_name.is_empty() && !self.server_name.contains(char::is_whitespace),
+            "server_name",
+            "cannot be empty or contain whitespace",
+        );
+    }
+}
+
+impl Validate for WebSocketConfig {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            self.ip.to_socket_addrs().is_ok(),
+            "ip",
+            format!(
+                "{:?} is not an address to listen on, e.g. \"127.0.0.1:8081\"",
+                self.ip
+            ),
+        );
+        validator.check(
+            self.max_message_bytes > 0,
+            "max_message_bytes",
+            "must be more than 0 or no requests could be sent",
+        );
+    }
+}
+
+impl Validate for MetricsConfig {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            self.ip.to_socket_addrs().is_ok(),
+            "ip",
+            format!(
+                "{:?} is not an address to listen on, e.g. \"127.0.0.1:9100\"",
+                self.ip
+            ),
+        );
+    }
+}
+
+impl Validate for FederationConfig {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            valid_server_name(&self.name),
+            "name",
+            "cannot be empty or contain whitespace or '@'",
+        );
+
+        for (index, link) in self.links.iter().enumerate() {
+            let key = format!("links.{index}");
+            validator.check(
+                valid_server_name(&link.name),
+                &format!("{key}.name"),
+                "cannot be empty or contain whitespace or '@'",
+            );
+            validator.check(
+                !self.links[..index]
+                    .iter()
+                    .any(|other| other.name == link.name),
+                &format!("{key}.name"),
+                "is used by another link",
+            );
+            validator.check(
+                link.address
+                    .rsplit_once(':')
+                    .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()),
+                &format!("{key}.address"),
+                format!(
+                    "{:?} is not an address to connect to, e.g. \"127.0.0.1:1234\"",
+                    link.address
+                ),
+            );
+            validator.check(
+                !link.rooms.is_empty(),
+                &format!("{key}.rooms"),
+                "must have at least one room or the link would do nothing",
+            );
+            for room in &link.rooms {
+                validator.check(
+                    valid_room(room),
+                    &format!("{key}.rooms"),
+                    format!("{room:?} is not a valid room name"),
+                );
+            }
+            validator.check(
+                link.retry_delay_ms > 0,
+                &format!("{key}.retry_delay_ms"),
+                "must be more than 0",
+            );
+        }
+    }
+}
+
+/// Server names end up after the `@` in usernames
+pub(crate) fn valid_server_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| c.is_whitespace() || c == '@')
+}
+
+impl Validate for LimitsConfig {
+    fn validate(&self, validator: &mut Validator) {
+        validator.check(
+            self.max_connections > 0,
+            "max_connections",
+            "must be more than 0 or no clients could connect",
+        );
+        validator.check(
+            self.max_connections_per_ip > 0,
+            "max_connections_per_ip",
+            "must be more than 0 or no clients could connect",
+        );
+    }
+}
+
+impl Config for ServerConfig {
+    const NAME: &'static str = "server";
+    const VERSION: u32 = 1;
+    const MIGRATIONS: &'static [Migration] = &[Migration {
+        version: 1,
+        renames: &[("net_config", "net"), ("system_config", "system")],
+    }];
+    const TEMPLATE: &'static str = include_str!("../Config.toml");
+
+    fn validate(&self, validator: &mut Validator) {
+        validator.section("net", &self.net);
+        validator.section("system", &self.system);
+        validator.section("message_guidelines", &self.message_guidelines);
+        validator.section("username_guidelines", &self.username_guidelines);
+        validator.section("rate_limit", &self.rate_limit);
+        validator.section("limits", &self.limits);
+        validator.section("plugins", &self.plugins);
+        for (index, webhook) in self.webhooks.iter().enumerate() {
+            validator.section(&format!("webhooks.{index}"), webhook);
+        }
+        validator.section("http_api", &self.http_api);
+        validator.section("irc", &self.irc);
+        validator.section("websocket", &self.websocket);
+        validator.section("federation", &self.federation);
+        validator.section("metrics", &self.metrics);
+
+        // Every client takes up a thread for as long as it is connected
+        validator.check(
+            self.limits.max_connections <= self.system.threads,
+            "limits.max_connections",
+            format!(
+                "is more than system.threads ({} > {}), clients over the thread count would never be served",
+                self.limits.max_connections, self.system.threads
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(toml: &str) -> LimitsConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn every_ip_is_allowed_without_lists() {
+        let limits = limits("");
+        assert!(limits.is_allowed("10.0.0.1".parse().unwrap()));
+        assert!(limits.is_allowed("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn only_allowed_ranges_get_in() {
+        let limits = limits(r#"allow = ["192.168.0.0/16"]"#);
+        assert!(limits.is_allowed("192.168.4.2".parse().unwrap()));
+        assert!(!limits.is_allowed("10.0.0.1".parse().unwrap()));
+        // An ipv4 range does not cover ipv6 addresses
+        assert!(!limits.is_allowed("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn deny_wins_over_a_wider_allow() {
+        let limits = limits(
+            r#"
+            allow = ["10.0.0.0/8"]
+            deny = ["10.1.0.0/16"]
+            "#,
+        );
+        assert!(limits.is_allowed("10.2.0.1".parse().unwrap()));
+        assert!(!limits.is_allowed("10.1.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn deny_wins_over_a_narrower_allow() {
+        let limits = limits(
+            r#"
+            allow = ["10.1.0.0/16"]
+            deny = ["10.0.0.0/8"]
+            "#,
+        );
+        assert!(!limits.is_allowed("10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn single_addresses_can_be_denied() {
+        let limits = limits(r#"deny = ["203.0.113.7/32", "2001:db8::1/128"]"#);
+        assert!(!limits.is_allowed("203.0.113.7".parse().unwrap()));
+        assert!(limits.is_allowed("203.0.113.8".parse().unwrap()));
+        assert!(!limits.is_allowed("2001:db8::1".parse().unwrap()));
+        assert!(limits.is_allowed("2001:db8::2".parse().unwrap()));
+    }
+}
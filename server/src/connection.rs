@@ -0,0 +1,86 @@
+use std::{
+    io,
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+use chat_core::{
+    read::ChatReader, read_write_streams::ReadWriteStreams, request::Request, response::Response,
+    write::ChatWriter,
+};
+
+/// Reads the requests of a client, implemented by the native protocol and by every gateway
+pub trait RequestReader: Send {
+    /// Block until the client sends the next request, an error ends the connection
+    fn read_request(&mut self) -> Result<Request, io::Error>;
+}
+
+/// Writes responses to a client, shared by the client handler and the broadcaster
+pub trait ResponseWriter: Send {
+    fn write_response(&mut self, response: &Response) -> Result<(), io::Error>;
+    /// Close the connection, which makes the `RequestReader` of the client fail so its handler stops
+    fn disconnect(&mut self);
+}
+
+pub type SharedWriter = Arc<Mutex<dyn ResponseWriter>>;
+
+/// Everything a client handler needs to talk to its client, whatever protocol it speaks
+pub struct Connection {
+    pub reader: Box<dyn RequestReader>,
+    pub writer: SharedWriter,
+    /// Addresses the client reads from and writes to, the same address for protocols that use a single stream
+    pub addresses: (SocketAddr, SocketAddr),
+}
+
+impl Connection {
+    /// A client speaking the native protocol, `stream` is the one the client connected with and the server connects
+    /// back to `read_port` for the other one
+    pub fn native(stream: TcpStream, read_port: u16) -> Result<Self, io::Error> {
+        let write_address = stream.peer_addr()?;
+        let streams = chat_core::read_write_streams::ConnectPeerStream::connect_peer_stream(
+            stream, read_port,
+        )?;
+        let read_address = streams.read.lock().unwrap().peer_addr()?;
+        let read_handle = streams.read.lock().unwrap().try_clone()?;
+
+        Ok(Self {
+            reader: Box::new(NativeReader {
+                streams: streams.clone(),
+            }),
+            writer: Arc::new(Mutex::new(NativeWriter {
+                streams,
+                read_handle,
+            })),
+            addresses: (read_address, write_address),
+        })
+    }
+}
+
+/// Reads bincode requests
+struct NativeReader {
+    streams: ReadWriteStreams,
+}
+
+impl RequestReader for NativeReader {
+    fn read_request(&mut self) -> Result<Request, io::Error> {
+        self.streams.read_data().map_err(io::Error::other)
+    }
+}
+
+/// Writes bincode responses
+struct NativeWriter {
+    streams: ReadWriteStreams,
+    /// Clone of the read stream, the client handler holds the lock on `streams.read` while it waits for a request so
+    /// this is used to shut the stream down instead
+    read_handle: TcpStream,
+}
+
+impl ResponseWriter for NativeWriter {
+    fn write_response(&mut self, response: &Response) -> Result<(), io::Error> {
+        self.streams.write_data(response).map_err(io::Error::other)
+    }
+    fn disconnect(&mut self) {
+        let _ = self.read_handle.shutdown(Shutdown::Both);
+        let _ = self.streams.write.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
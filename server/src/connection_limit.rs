@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use chat_core::request::ConnectionRefused;
+
+use crate::config::LimitsConfig;
+
+/// Keeps count of the open connections, in total and per ip address
+#[derive(Default)]
+pub struct ConnectionTracker {
+    connections: Mutex<Connections>,
+}
+
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionTracker {
+    /// Check if a connection from `ip` is allowed, if it is a `ConnectionSlot` is returned that has to be kept alive
+    /// for as long as the connection is open.
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        config: &LimitsConfig,
+    ) -> Result<ConnectionSlot, ConnectionRefused> {
+        if !config.is_allowed(ip) {
+            return Err(ConnectionRefused::Blocked);
+        }
+
+        let mut connections = self.connections.lock().unwrap();
+
+        if connections.total >= config.max_connections() {
+            return Err(ConnectionRefused::ServerFull);
+        }
+
+        let from_ip = connections.per_ip.entry(ip).or_default();
+        if *from_ip >= config.max_connections_per_ip() {
+            return Err(ConnectionRefused::TooManyFromIp);
+        }
+        *from_ip += 1;
+        connections.total += 1;
+
+        log::debug!(
+            "connection from {ip} accepted, {} connection(s) open",
+            connections.total
+        );
+
+        Ok(ConnectionSlot {
+            tracker: Arc::clone(self),
+            ip,
+        })
+    }
+    /// Amount of connections open right now
+    pub fn total(&self) -> usize {
+        self.connections.lock().unwrap().total
+    }
+    fn release(&self, ip: IpAddr) {
+        let mut connections = self.connections.lock().unwrap();
+
+        connections.total -= 1;
+        if let Some(from_ip) = connections.per_ip.get_mut(&ip) {
+            *from_ip -= 1;
+            if *from_ip == 0 {
+                connections.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+/// An open connection counted by a `ConnectionTracker`, the connection is no longer counted once this is dropped
+pub struct ConnectionSlot {
+    tracker: Arc<ConnectionTracker>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        log::debug!("connection from {} closed", self.ip);
+        self.tracker.release(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(toml: &str) -> LimitsConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn connections_over_the_limits_are_refused() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        let limits = limits("max_connections = 3\nmax_connections_per_ip = 2");
+        let first = "10.0.0.1".parse().unwrap();
+        let second = "10.0.0.2".parse().unwrap();
+
+        let _a = tracker.try_acquire(first, &limits).unwrap();
+        let _b = tracker.try_acquire(first, &limits).unwrap();
+        assert!(matches!(
+            tracker.try_acquire(first, &limits),
+            Err(ConnectionRefused::TooManyFromIp)
+        ));
+        let _c = tracker.try_acquire(second, &limits).unwrap();
+        assert!(matches!(
+            tracker.try_acquire(second, &limits),
+            Err(ConnectionRefused::ServerFull)
+        ));
+        assert_eq!(tracker.total(), 3);
+    }
+
+    #[test]
+    fn dropped_slots_are_given_back() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        let limits = limits("max_connections_per_ip = 1");
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let slot = tracker.try_acquire(ip, &limits).unwrap();
+        assert!(tracker.try_acquire(ip, &limits).is_err());
+        drop(slot);
+        assert_eq!(tracker.total(), 0);
+        assert!(tracker.try_acquire(ip, &limits).is_ok());
+    }
+
+    #[test]
+    fn denied_ips_are_blocked_before_counting() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        let limits = limits(r#"deny = ["10.0.0.0/8"]"#);
+
+        assert!(matches!(
+            tracker.try_acquire("10.0.0.1".parse().unwrap(), &limits),
+            Err(ConnectionRefused::Blocked)
+        ));
+        assert_eq!(tracker.total(), 0);
+    }
+}
@@ -0,0 +1,532 @@
+//! Relays rooms of other servers. A link connects to the other server like any other client, once for every room it
+//! relays since a client is in one room at a time, and sends the messages of the other server's users here from bot
+//! users named `user@server`. Once connected it asks the other server to link back the same way, so one link relays
+//! both ways. Relayed users are marked with the server they came from, and messages from them are never relayed again,
+//! so servers that link to each other (or in a ring) do not send messages around forever.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use chat_client::{ChatClient, ClientError, Events};
+use chat_core::{
+    event::Event,
+    guidelines::AgainstGuidelines,
+    message::{Message, MessageKind},
+    user::{Origin, User, Username},
+};
+
+use crate::{
+    broadcast::{BroadcastMessage, DEFAULT_ROOM},
+    client::Client,
+    command::builtin::valid_room,
+    config::{valid_server_name, LinkConfig},
+    metrics::Metrics,
+    reload::SharedConfig,
+};
+
+/// Longest a link waits before connecting again
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// How long the other server has to connect back
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Every link binds the read port of its server while it waits to be connected back, so they take turns
+static CONNECTING: Mutex<()> = Mutex::new(());
+
+/// Starts the links in the config
+pub struct Federation;
+
+impl Federation {
+    /// Start a thread for every room of every link, relayed messages are sent to the broadcaster. `port` is the port
+    /// clients connect to here, the other servers are asked to link back to it. Links are not changed by reloading the
+    /// config.
+    pub fn start(
+        config: Arc<SharedConfig>,
+        port: Option<u16>,
+        broadcaster: Arc<Mutex<Sender<BroadcastMessage>>>,
+        metrics: Arc<Metrics>,
+    ) -> FederationHandle {
+        let current = config.get();
+        let mut links = Vec::new();
+
+        for link in current.federation.links() {
+            for room in link.rooms() {
+                let relay = RoomLink {
+                    link: link.clone(),
+                    room: room.clone(),
+                    name: current.federation.name().to_owned(),
+                    port: port.filter(|_| link.both_ways()),
+                    config: Arc::clone(&config),
+                    broadcaster: Arc::clone(&broadcaster),
+                    metrics: Arc::clone(&metrics),
+                    client: Arc::default(),
+                };
+
+                log::info!("linking #{room} to {}", link.name());
+                links.push(relay.spawn());
+            }
+        }
+
+        FederationHandle { links }
+    }
+    /// Link back to the server named `name` that linked to us as `client`, relaying `room` from it until the returned
+    /// link is dropped. `None` if links are not accepted here, the request is not valid or a link to a server with
+    /// that name is in the config already.
+    pub fn link_back(
+        client: &Client,
+        name: &str,
+        port: u16,
+        read_port: u16,
+        room: &str,
+    ) -> Option<LinkBack> {
+        let ip = client.ip();
+        let current = client.config.get();
+        let federation = &current.federation;
+        if !federation.accept_links() {
+            log::info!("not linking back to {name} at {ip}, links are not accepted");
+            return None;
+        }
+        if !valid_server_name(name) || name == federation.name() || !valid_room(room) {
+            log::info!("not linking back to {name:?} at {ip} for #{room}, not a valid link");
+            return None;
+        }
+        if federation.links().iter().any(|link| link.name() == name) {
+            log::info!("not linking back to {name}, it is linked to in the config");
+            return None;
+        }
+
+        let address = SocketAddr::new(ip, port).to_string();
+        let relay = RoomLink {
+            link: LinkConfig::back(name, address, read_port, room),
+            room: room.to_owned(),
+            name: federation.name().to_owned(),
+            port: None,
+            config: Arc::clone(&client.config),
+            broadcaster: Arc::clone(&client.broadcaster),
+            metrics: Arc::clone(&client.metrics),
+            client: Arc::default(),
+        };
+
+        log::info!("linking #{room} back to {name} at {ip}");
+        Some(LinkBack(Some(relay.spawn())))
+    }
+}
+
+/// Stops the threads started by `Federation::start()`
+pub struct FederationHandle {
+    links: Vec<LinkThread>,
+}
+
+struct LinkThread {
+    /// Dropped to tell the thread to stop, which also wakes it up while it waits to connect again
+    stop: Sender<()>,
+    client: Arc<Mutex<Option<ChatClient>>>,
+    thread: JoinHandle<()>,
+}
+
+impl LinkThread {
+    /// Tell the thread to stop and close its connection, returns the thread to wait for
+    fn stop(self) -> JoinHandle<()> {
+        // A thread checks for the stop after storing a new client, so either it sees the stop or its client is closed
+        // here
+        drop(self.stop);
+        if let Some(client) = self.client.lock().unwrap().as_ref() {
+            client.disconnect();
+        }
+        self.thread
+    }
+}
+
+impl FederationHandle {
+    pub fn stop(self) {
+        let threads = self
+            .links
+            .into_iter()
+            .map(LinkThread::stop)
+            .collect::<Vec<_>>();
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A link back to a server that linked here, see `Federation::link_back()`. It is stopped when dropped, which happens
+/// when the link of the other server disconnects.
+pub struct LinkBack(Option<LinkThread>);
+
+impl Drop for LinkBack {
+    fn drop(&mut self) {
+        // Not waited for, the client handler dropping it should not hang while the link connects
+        if let Some(link) = self.0.take() {
+            link.stop();
+        }
+    }
+}
+
+/// Relays one room from the other server of a link
+struct RoomLink {
+    link: LinkConfig,
+    room: String,
+    /// Name of this server, the link connects with it as its username
+    name: String,
+    /// Port clients connect to here, the other server is asked to link back to it. `None` for links back and links
+    /// that only relay one way.
+    port: Option<u16>,
+    config: Arc<SharedConfig>,
+    broadcaster: Arc<Mutex<Sender<BroadcastMessage>>>,
+    metrics: Arc<Metrics>,
+    /// The current connection, kept so `FederationHandle::stop()` can close it
+    client: Arc<Mutex<Option<ChatClient>>>,
+}
+
+impl RoomLink {
+    fn spawn(self) -> LinkThread {
+        let (stop, stopped) = mpsc::channel();
+        let client = Arc::clone(&self.client);
+
+        LinkThread {
+            stop,
+            client,
+            thread: thread::spawn(move || self.run(stopped)),
+        }
+    }
+    /// Connect and relay until told to stop, connecting again with a growing delay whenever the connection fails
+    fn run(self, stopped: Receiver<()>) {
+        let mut delay = self.link.retry_delay();
+
+        loop {
+            match self.connect() {
+                Ok(events) => {
+                    if let Err(mpsc::TryRecvError::Disconnected) = stopped.try_recv() {
+                        break;
+                    }
+                    log::info!("relaying #{} from {}", self.room, self.link.name());
+                    delay = self.link.retry_delay();
+                    self.relay(events);
+                    log::warn!("lost the link to {} for #{}", self.link.name(), self.room);
+                }
+                Err(error) => log::warn!(
+                    "failed to link to {} for #{}: {error}",
+                    self.link.name(),
+                    self.room
+                ),
+            }
+
+            match stopped.recv_timeout(delay) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => break,
+            }
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+
+        if let Some(client) = self.client.lock().unwrap().take() {
+            client.disconnect();
+        }
+        log::info!("stopped relaying #{} from {}", self.room, self.link.name());
+    }
+    fn connect(&self) -> Result<Events, ClientError> {
+        let (mut client, events) = {
+            let _connecting = CONNECTING.lock().unwrap();
+            ChatClient::builder()
+                .read_port(self.link.read_port())
+                .timeout(CONNECT_TIMEOUT)
+                .connect(self.link.address())?
+        };
+
+        client.rename(self.name.as_str())?;
+        if self.room != DEFAULT_ROOM {
+            client.join_room(&self.room)?;
+        }
+        if let Some(port) = self.port {
+            let read_port = self.config.get().net.read_port();
+            client.link_back(&self.name, port, read_port, &self.room)?;
+        }
+        *self.client.lock().unwrap() = Some(client);
+
+        Ok(events)
+    }
+    /// Relay messages until the connection is closed
+    fn relay(&self, events: Events) {
+        for response in events {
+            match response {
+                Ok(Event::Message(message)) => {
+                    if let Some(message) = self.relayed(message) {
+                        self.broadcaster
+                            .lock()
+                            .unwrap()
+                            .send(BroadcastMessage::ChatMessage(message))
+                            .ok();
+                    }
+                }
+                Ok(_) => {}
+                Err(error) => log::warn!(
+                    "{} sent an error for #{}: {error}",
+                    self.link.name(),
+                    self.room
+                ),
+            }
+        }
+    }
+    /// The message to send here for a message from the other server, if it should be relayed. Only new messages in
+    /// the room from clients and bots of the other server are, not messages the other server relayed itself, its own
+    /// notices (such as who joined), direct messages or messages that do not follow the guidelines here.
+    fn relayed(&self, message: Message) -> Option<Message> {
+        let local = matches!(
+            message.from().origin(),
+            Origin::Client | Origin::Integration
+        );
+        if !local
+            || message.room() != Some(self.room.as_str())
+            || message.kind() == MessageKind::Direct
+            || message.is_deleted()
+        {
+            return None;
+        }
+
+        let name = format!("{}@{}", message.from().username(), self.link.name());
+        Message::builder()
+            .from_who(
+                User::builder()
+                    .id(0)
+                    .username(Username::new(name.as_str()))
+                    .origin(Origin::Federated(self.link.name().to_owned()))
+                    .build(),
+            )
+            .payload(message.payload().clone())
+            .kind(message.kind())
+            .room(Some(self.room.clone()))
+            .build()
+            .against_guidelines(&self.config.get().message_guidelines)
+            .map_err(|error| {
+                self.metrics.rejected(&error);
+                log::info!("not relaying a message from {name}: {error}")
+            })
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread, time::Instant};
+
+    use chat_core::response::Response;
+
+    use super::*;
+    use crate::{client_listener::ClientListener, config::ServerConfig, shutdown::ShutdownHandle};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// A port nothing listens on, for read ports which are bound by the clients instead of the server
+    fn free_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    /// A server named `name` listening on a free port, linked to `other` if given as `(name, port, read_port)`.
+    /// Returns the port it listens on.
+    fn start(
+        name: &str,
+        read_port: u16,
+        other: Option<(&str, u16, u16)>,
+    ) -> (ShutdownHandle, JoinHandle<()>, u16) {
+        let link = match other {
+            Some((other, other_port, other_read_port)) => format!(
+                r#"
+                [[federation.links]]
+                name = "{other}"
+                address = "127.0.0.1:{other_port}"
+                read_port = {other_read_port}
+                rooms = ["general"]
+                retry_delay_ms = 100
+                "#
+            ),
+            None => String::new(),
+        };
+        let config: ServerConfig = toml::from_str(&format!(
+            r#"
+            [net]
+            ip = "127.0.0.1:0"
+            read_port = {read_port}
+            [shutdown]
+            timeout_secs = 1
+            [federation]
+            name = "{name}"
+            {link}
+            "#
+        ))
+        .unwrap();
+        let listener = ClientListener::new(SharedConfig::new(config, None)).unwrap();
+        let shutdown = listener.shutdown_handle();
+        let port = listener.local_addr().unwrap().port();
+
+        (shutdown, thread::spawn(move || listener.run()), port)
+    }
+
+    /// Connect as `name`, retrying while the server is starting or a link has the read port
+    fn connect(name: &str, port: u16, read_port: u16) -> (ChatClient, Events) {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match ChatClient::builder()
+                .read_port(read_port)
+                .connect(("127.0.0.1", port))
+            {
+                Ok((mut client, events)) => {
+                    client.rename(name).unwrap();
+                    return (client, events);
+                }
+                Err(error) if Instant::now() > deadline => panic!("could not connect: {error}"),
+                Err(_) => thread::sleep(Duration::from_millis(50)),
+            }
+        }
+    }
+
+    /// The events until `until` is true for one of them, or `wait` is over
+    fn events_until(
+        events: &Events,
+        wait: Duration,
+        mut until: impl FnMut(&Response) -> bool,
+    ) -> Vec<Response> {
+        let deadline = Instant::now() + wait;
+        let mut seen = Vec::new();
+        while Instant::now() < deadline {
+            match events.try_next() {
+                Ok(response) => {
+                    let done = until(&response);
+                    seen.push(response);
+                    if done {
+                        break;
+                    }
+                }
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        seen
+    }
+
+    /// Wait until the link of the other server is in our room
+    fn wait_for_link(client: &mut ChatClient, events: &Events, link: &str) {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            client.list_users().unwrap();
+            let seen = events_until(events, Duration::from_millis(200), |response| {
+                matches!(response, Ok(Event::UserList { users, .. })
+                    if users.iter().any(|user| user.username().to_string() == link))
+            });
+            if matches!(seen.last(), Some(Ok(Event::UserList { .. }))) {
+                return;
+            }
+        }
+        panic!("{link} never linked");
+    }
+
+    fn messages(seen: &[Response]) -> Vec<(String, String)> {
+        seen.iter()
+            .filter_map(|response| match response {
+                Ok(Event::Message(message)) => Some((
+                    message.from().username().to_string(),
+                    message.payload().to_string(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_messages_from_the_other_server_itself_are_relayed() {
+        let (broadcaster, _) = mpsc::channel();
+        let link = RoomLink {
+            link: toml::from_str(r#"name = "west""#).unwrap(),
+            room: DEFAULT_ROOM.to_owned(),
+            name: "east".to_owned(),
+            port: None,
+            config: Arc::new(SharedConfig::new(ServerConfig::default(), None)),
+            broadcaster: Arc::new(Mutex::new(broadcaster)),
+            metrics: Arc::default(),
+            client: Arc::default(),
+        };
+        let from = |name: &str, origin: Origin| {
+            let user = User::builder()
+                .id(1)
+                .username(Username::new(name))
+                .origin(origin)
+                .build();
+            let message = Message::builder()
+                .from_who(user)
+                .payload(chat_core::value::Value::from("hi"))
+                .room(Some(DEFAULT_ROOM.to_owned()))
+                .build();
+            link.relayed(message).map(|message| message.from().clone())
+        };
+
+        let relayed = from("bob", Origin::Client).unwrap();
+        assert_eq!(relayed.username().to_string(), "bob@west");
+        assert_eq!(relayed.origin(), &Origin::Federated("west".to_owned()));
+        assert_eq!(
+            from("deploys", Origin::Integration)
+                .unwrap()
+                .username()
+                .to_string(),
+            "deploys@west"
+        );
+        assert!(from("SERVER", Origin::Server).is_none());
+        assert!(from("amy@east", Origin::Federated("east".to_owned())).is_none());
+    }
+
+    #[test]
+    fn one_link_relays_both_ways_without_echoing_messages_back() {
+        let (east_read_port, west_read_port) = (free_port(), free_port());
+        let (east_shutdown, east, east_port) = start("east", east_read_port, None);
+        let (west_shutdown, west, west_port) = start(
+            "west",
+            west_read_port,
+            Some(("east", east_port, east_read_port)),
+        );
+
+        let (mut amy, amy_events) = connect("amy", east_port, east_read_port);
+        let (mut bob, bob_events) = connect("bob", west_port, west_read_port);
+        // West links to east and east links back
+        wait_for_link(&mut amy, &amy_events, "west");
+        wait_for_link(&mut bob, &bob_events, "east");
+
+        amy.send_message("hello west").unwrap();
+        let seen = events_until(&bob_events, TIMEOUT, |response| {
+            matches!(response, Ok(Event::Message(message))
+                if message.from().username().to_string() == "amy@east")
+        });
+        assert!(messages(&seen).contains(&("amy@east".to_owned(), "hello west".to_owned())));
+
+        bob.send_message("hello east").unwrap();
+        let seen = events_until(&amy_events, TIMEOUT, |response| {
+            matches!(response, Ok(Event::Message(message))
+                if message.from().username().to_string() == "bob@west")
+        });
+        assert!(messages(&seen).contains(&("bob@west".to_owned(), "hello east".to_owned())));
+
+        // Give relayed messages time to come back around if they were going to
+        let seen = events_until(&amy_events, Duration::from_millis(500), |_| false);
+        let echoed = messages(&seen)
+            .into_iter()
+            .filter(|(from, _)| from.contains('@'))
+            .collect::<Vec<_>>();
+        assert!(echoed.is_empty(), "echoed back to east: {echoed:?}");
+        let seen = events_until(&bob_events, Duration::from_millis(500), |_| false);
+        let echoed = messages(&seen)
+            .into_iter()
+            .filter(|(from, _)| from.contains('@'))
+            .collect::<Vec<_>>();
+        assert!(echoed.is_empty(), "echoed back to west: {echoed:?}");
+
+        amy.disconnect();
+        bob.disconnect();
+        for (shutdown, thread) in [(east_shutdown, east), (west_shutdown, west)] {
+            shutdown.shutdown(Default::default());
+            thread.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,763 @@
+//! A subset of irc (RFC 1459 and 2812) so people can join with clients such as weechat or irssi. NICK, USER, JOIN,
+//! PART, PRIVMSG, NAMES, PING and QUIT are understood, along with enough of CAP, MODE and WHO to keep common clients
+//! happy. Channels are rooms, and like every other client an irc client is in one room at a time so joining a channel
+//! parts the one it was in.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{mpsc::Sender, Arc, Mutex},
+    time::Duration,
+};
+
+use chat_core::{
+    command::CommandError,
+    event::Event,
+    guidelines::AgainstGuidelines,
+    message::{Message, MessageKind},
+    request::{Request, RequestError},
+    response::Response,
+    user::{Origin, User, Username},
+    value::Value,
+};
+
+use crate::{
+    broadcast::{BroadcastMessage, DEFAULT_ROOM},
+    client::ClientServices,
+    config::ServerConfig,
+    connection::{Connection, RequestReader, ResponseWriter},
+    reload::SharedConfig,
+};
+
+use super::Protocol;
+
+/// Longest line read from a client, irc allows 512 bytes but some clients send longer lines
+const MAX_LINE: usize = 4096;
+/// Most bytes of text sent in one line, longer text is split over several lines so it fits in 512 bytes along with
+/// the prefix and target
+const MAX_TEXT: usize = 400;
+/// How long a client has to register before it is disconnected
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Irc;
+
+impl Protocol for Irc {
+    fn name(&self) -> &'static str {
+        "irc"
+    }
+    fn connect(
+        &self,
+        stream: TcpStream,
+        key: usize,
+        services: &ClientServices,
+    ) -> Result<Connection, io::Error> {
+        let config = services.config.get();
+        let address = stream.peer_addr()?;
+
+        let mut writer = IrcWriter {
+            stream: stream.try_clone()?,
+            key,
+            server: config.irc.server_name().to_owned(),
+            nick: String::from("*"),
+            channel: None,
+            broadcaster: Arc::clone(&services.broadcaster),
+            closed: false,
+        };
+        let mut lines = Lines {
+            reader: BufReader::new(stream),
+        };
+
+        lines
+            .reader
+            .get_ref()
+            .set_read_timeout(Some(REGISTRATION_TIMEOUT))?;
+        let nick = register(&mut lines, &mut writer, &config)?;
+        lines.reader.get_ref().set_read_timeout(None)?;
+        writer.welcome()?;
+        log::info!("{address} registered on irc as {nick}");
+
+        let writer = Arc::new(Mutex::new(writer));
+        Ok(Connection {
+            reader: Box::new(IrcReader {
+                lines,
+                writer: Arc::clone(&writer),
+                config: Arc::clone(&services.config),
+                // The client handler starts out with a generated name and in the default room, so ask for the nick
+                // the client registered with and find out what room it is in
+                pending: VecDeque::from([
+                    Request::ChangeUserName(Value::String(nick)),
+                    Request::RoomList,
+                ]),
+            }),
+            writer,
+            addresses: (address, address),
+        })
+    }
+    fn refuse(&self, stream: &mut TcpStream, error: &RequestError) {
+        let _ = write!(stream, "ERROR :Closing link: {error}\r\n");
+    }
+}
+
+/// Wait for the client to send NICK and USER, answering what else it sends while registering. Returns the nick.
+fn register(
+    lines: &mut Lines,
+    writer: &mut IrcWriter,
+    config: &ServerConfig,
+) -> Result<String, io::Error> {
+    let mut nick = None;
+    let mut user = false;
+
+    while nick.is_none() || !user {
+        let message = lines.next_message()?;
+
+        match (message.command.as_str(), message.params.as_slice()) {
+            ("NICK", [new, ..]) => match check_nick(new, config) {
+                Ok(()) => {
+                    writer.nick = new.clone();
+                    nick = Some(new.clone());
+                }
+                Err(error) => writer.numeric("432", &[new, &error])?,
+            },
+            ("NICK", []) => writer.numeric("431", &["No nickname given"])?,
+            ("USER", [_, _, _, _, ..]) => user = true,
+            ("USER", _) => writer.numeric("461", &["USER", "Not enough parameters"])?,
+            ("CAP", params) => writer.cap(params)?,
+            ("PING", params) => writer.pong(params)?,
+            ("PASS" | "PONG", _) => {}
+            ("QUIT", _) => {
+                writer.close("quit")?;
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "irc client quit before registering",
+                ));
+            }
+            _ => writer.numeric("451", &["You have not registered"])?,
+        }
+    }
+
+    Ok(nick.unwrap_or_default())
+}
+
+/// Check a nick against the username guidelines, returns why it is not allowed
+fn check_nick(nick: &str, config: &ServerConfig) -> Result<(), String> {
+    Username::new(nick)
+        .against_guidelines(&config.username_guidelines)
+        .map(|_| ())
+        .map_err(|error| error.to_string())
+}
+
+/// A line sent by a client, the prefix and tags are dropped since only the server sends them
+#[derive(Debug)]
+struct IrcMessage {
+    /// Always upper case
+    command: String,
+    params: Vec<String>,
+}
+
+impl IrcMessage {
+    fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        if rest.starts_with('@') {
+            rest = rest.split_once(' ')?.1;
+        }
+        if rest.starts_with(':') {
+            rest = rest.split_once(' ')?.1;
+        }
+
+        // A line with only a trailing parameter has no command
+        if rest.starts_with(':') {
+            return None;
+        }
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut words = middle.split(' ').filter(|word| !word.is_empty());
+        let command = words.next()?.to_ascii_uppercase();
+        let mut params = words.map(str::to_owned).collect::<Vec<_>>();
+        params.extend(trailing.map(str::to_owned));
+
+        Some(Self { command, params })
+    }
+}
+
+/// Reads the lines of a client
+struct Lines {
+    reader: BufReader<TcpStream>,
+}
+
+impl Lines {
+    /// Block until the client sends a line that is not empty
+    fn next_message(&mut self) -> Result<IrcMessage, io::Error> {
+        loop {
+            let mut line = Vec::new();
+            let read = (&mut self.reader)
+                .take(MAX_LINE as u64)
+                .read_until(b'\n', &mut line)?;
+
+            if read == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "irc client closed the connection",
+                ));
+            }
+            if read == MAX_LINE && !line.ends_with(b"\n") {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("irc client sent a line over {MAX_LINE} bytes"),
+                ));
+            }
+            if let Some(message) = IrcMessage::parse(&String::from_utf8_lossy(&line)) {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+/// Turns the commands of a registered client into requests, commands that have no request are answered here
+struct IrcReader {
+    lines: Lines,
+    writer: Arc<Mutex<IrcWriter>>,
+    config: Arc<SharedConfig>,
+    /// Requests to hand to the client handler before reading another line, one command can turn into several
+    /// requests (e.g. joining a list of channels)
+    pending: VecDeque<Request>,
+}
+
+impl RequestReader for IrcReader {
+    fn read_request(&mut self) -> Result<Request, io::Error> {
+        loop {
+            if let Some(request) = self.pending.pop_front() {
+                return Ok(request);
+            }
+
+            let message = self.lines.next_message()?;
+            self.handle(message)?;
+        }
+    }
+}
+
+impl IrcReader {
+    fn handle(&mut self, message: IrcMessage) -> Result<(), io::Error> {
+        let writer = Arc::clone(&self.writer);
+        let mut writer = writer.lock().unwrap();
+
+        match (message.command.as_str(), message.params.as_slice()) {
+            ("PING", params) => writer.pong(params)?,
+            ("PONG" | "NOTICE", _) => {}
+            ("CAP", params) => writer.cap(params)?,
+            ("USER" | "PASS", _) => writer.numeric("462", &["You may not reregister"])?,
+            (command @ ("JOIN" | "PART" | "MODE"), []) => {
+                writer.numeric("461", &[command, "Not enough parameters"])?
+            }
+            ("NICK", []) => writer.numeric("431", &["No nickname given"])?,
+            ("NICK", [new, ..]) if *new == writer.nick => {}
+            ("NICK", [new, ..]) => match check_nick(new, &self.config.get()) {
+                Ok(()) => {
+                    let line = format!("{} NICK :{new}", writer.prefix(&writer.nick));
+                    writer.send_line(line)?;
+                    writer.nick = new.clone();
+                    self.pending
+                        .push_back(Request::ChangeUserName(Value::String(new.clone())));
+                }
+                Err(error) => writer.numeric("432", &[new, &error])?,
+            },
+            ("JOIN", [channels, ..]) if channels == "0" => {
+                self.command(format!("join {DEFAULT_ROOM}"))
+            }
+            ("JOIN", [channels, ..]) => {
+                for room in channels.split(',').map(room_name) {
+                    if writer.channel.as_deref() != Some(room) {
+                        self.command(format!("join {room}"));
+                    }
+                }
+            }
+            ("PART", [channels, ..]) => {
+                for room in channels.split(',').map(room_name) {
+                    if writer.channel.as_deref() != Some(room) {
+                        writer.numeric("442", &[&channel(room), "You're not on that channel"])?;
+                    } else if room == DEFAULT_ROOM {
+                        let nick = writer.nick.clone();
+                        writer.notice(
+                            &nick,
+                            &format!(
+                                "you are always in a room, join another one to leave {}",
+                                channel(room)
+                            ),
+                        )?;
+                    } else {
+                        self.command(format!("join {DEFAULT_ROOM}"));
+                    }
+                }
+            }
+            ("PRIVMSG", [] | [_]) => writer.numeric("412", &["No text to send"])?,
+            ("PRIVMSG", [targets, text, ..]) => {
+                for target in targets.split(',') {
+                    if target.starts_with('#') {
+                        if writer.channel.as_deref() != Some(room_name(target)) {
+                            writer.numeric(
+                                "404",
+                                &[target, "Cannot send to channel, join it first"],
+                            )?;
+                            continue;
+                        }
+                        if let Some(text) = channel_text(text) {
+                            self.pending
+                                .push_back(Request::SendMessage(Value::String(text)));
+                        }
+                    } else {
+                        let text = ctcp_action(text).unwrap_or(text);
+                        self.command(format!("msg {target} {text}"));
+                    }
+                }
+            }
+            ("NAMES", []) => self.pending.push_back(Request::UserList),
+            ("NAMES", [channels, ..]) => {
+                for room in channels.split(',').map(room_name) {
+                    if writer.channel.as_deref() == Some(room) {
+                        self.pending.push_back(Request::UserList);
+                    } else {
+                        writer.numeric("366", &[&channel(room), "End of /NAMES list"])?;
+                    }
+                }
+            }
+            ("MODE", [target, ..]) if target.starts_with('#') => {
+                writer.numeric("324", &[target, "+"])?
+            }
+            ("MODE", _) => writer.numeric("221", &["+"])?,
+            ("WHO", params) => {
+                let mask = params.first().map(String::as_str).unwrap_or("*");
+                writer.numeric("315", &[mask, "End of /WHO list"])?
+            }
+            ("QUIT", _) => {
+                writer.close("quit")?;
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "irc client quit",
+                ));
+            }
+            (command, _) => writer.numeric("421", &[command, "Unknown command"])?,
+        }
+
+        Ok(())
+    }
+    /// Queue a chat command such as `join general`
+    fn command(&mut self, command: String) {
+        self.pending
+            .push_back(Request::SendMessage(Value::String(format!("/{command}"))));
+    }
+}
+
+/// Room of a channel, the `#` is optional
+fn room_name(channel: &str) -> &str {
+    channel.strip_prefix('#').unwrap_or(channel)
+}
+
+fn channel(room: &str) -> String {
+    format!("#{room}")
+}
+
+/// The text of a CTCP ACTION (`/me` in most clients)
+fn ctcp_action(text: &str) -> Option<&str> {
+    text.strip_prefix("\x01ACTION ")
+        .map(|action| action.trim_end_matches('\x01'))
+}
+
+/// What to send for a PRIVMSG to a channel. Actions become `/me`, other CTCP requests are dropped and text starting
+/// with `/` is escaped so it is not taken for a command.
+fn channel_text(text: &str) -> Option<String> {
+    if let Some(action) = ctcp_action(text) {
+        return Some(format!("/me {action}"));
+    }
+    if text.starts_with('\x01') {
+        return None;
+    }
+    match text.starts_with('/') {
+        true => Some(format!("/{text}")),
+        false => Some(text.to_owned()),
+    }
+}
+
+/// A name that can be used as an irc nick, the characters irc gives a meaning to are replaced
+fn irc_nick(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.is_whitespace() || ",*?!@:#".contains(c) {
+            true => '_',
+            false => c,
+        })
+        .collect()
+}
+
+/// Turns responses into irc lines
+struct IrcWriter {
+    stream: TcpStream,
+    key: usize,
+    server: String,
+    /// Nick of the client, `*` before it registers
+    nick: String,
+    /// Room the client is in, the client is told it joined when the room list says it changed
+    channel: Option<String>,
+    broadcaster: Arc<Mutex<Sender<BroadcastMessage>>>,
+    /// Set once the client has been sent an ERROR, nothing is sent after that
+    closed: bool,
+}
+
+impl ResponseWriter for IrcWriter {
+    fn write_response(&mut self, response: &Response) -> Result<(), io::Error> {
+        match response {
+            Ok(Event::Message(message)) => self.message(message),
+            Ok(Event::Mention(message)) => self.mention(message),
+            Ok(Event::UserList { room, users }) => self.names(room, users),
+            Ok(Event::RoomList { current, .. }) => self.joined(current),
+            // Edits, reactions, typing and unread counts have nothing to map to
+            Ok(_) => Ok(()),
+            Err(error) => self.error(error),
+        }
+    }
+    fn disconnect(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl IrcWriter {
+    /// Send a line, anything that would end it early is removed
+    fn send_line(&mut self, mut line: String) -> Result<(), io::Error> {
+        if self.closed {
+            return Ok(());
+        }
+        line.retain(|c| !matches!(c, '\r' | '\n' | '\0'));
+        line.push_str("\r\n");
+        self.stream.write_all(line.as_bytes())
+    }
+    /// Send a reply from the server to the client, the last parameter is sent as the trailing one
+    fn numeric(&mut self, code: &str, params: &[&str]) -> Result<(), io::Error> {
+        let mut line = format!(":{} {code} {}", self.server, self.nick);
+        if let Some((last, middle)) = params.split_last() {
+            for param in middle {
+                line.push(' ');
+                line.push_str(param);
+            }
+            line.push_str(" :");
+            line.push_str(last);
+        }
+        self.send_line(line)
+    }
+    fn notice(&mut self, target: &str, text: &str) -> Result<(), io::Error> {
+        let start = format!(":{} NOTICE {target} :", self.server);
+        self.send_text(&start, text, false)
+    }
+    /// Send the text after `start`, a line for every line of the text and split up so no line is too long
+    fn send_text(&mut self, start: &str, text: &str, action: bool) -> Result<(), io::Error> {
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            for chunk in split_text(line, MAX_TEXT) {
+                match action {
+                    true => self.send_line(format!("{start}\x01ACTION {chunk}\x01"))?,
+                    false => self.send_line(format!("{start}{chunk}"))?,
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Prefix of lines sent on behalf of a user
+    fn prefix(&self, name: &str) -> String {
+        let nick = irc_nick(name);
+        format!(":{nick}!{nick}@{}", self.server)
+    }
+    /// Tell the client why it is being disconnected
+    fn close(&mut self, reason: &str) -> Result<(), io::Error> {
+        self.send_line(format!("ERROR :Closing link: {reason}"))?;
+        self.closed = true;
+        let _ = self.stream.shutdown(Shutdown::Both);
+        Ok(())
+    }
+    fn welcome(&mut self) -> Result<(), io::Error> {
+        let nick = self.nick.clone();
+        let server = self.server.clone();
+        self.numeric("001", &[&format!("Welcome to the chat, {nick}")])?;
+        self.numeric("002", &[&format!("Your host is {server}")])?;
+        self.numeric(
+            "003",
+            &["Every channel is a room of the chat, you are in one at a time"],
+        )?;
+        self.numeric("004", &[&server, env!("CARGO_PKG_VERSION"), "o", "o"])?;
+        self.numeric("005", &["CHANTYPES=#", "are supported by this server"])?;
+        self.numeric("422", &["MOTD File is missing"])
+    }
+    fn pong(&mut self, params: &[String]) -> Result<(), io::Error> {
+        let token = params.first().map(String::as_str).unwrap_or(&self.server);
+        let line = format!(":{0} PONG {0} :{token}", self.server);
+        self.send_line(line)
+    }
+    /// No capabilities are supported, but clients that ask should be told so
+    fn cap(&mut self, params: &[String]) -> Result<(), io::Error> {
+        let line = match params
+            .first()
+            .map(|sub| sub.to_ascii_uppercase())
+            .as_deref()
+        {
+            Some("LS") => format!(":{} CAP {} LS :", self.server, self.nick),
+            Some("LIST") => format!(":{} CAP {} LIST :", self.server, self.nick),
+            Some("REQ") => format!(
+                ":{} CAP {} NAK :{}",
+                self.server,
+                self.nick,
+                params.get(1).map(String::as_str).unwrap_or_default()
+            ),
+            _ => return Ok(()),
+        };
+        self.send_line(line)
+    }
+    fn message(&mut self, message: &Message) -> Result<(), io::Error> {
+        // Irc clients show what they send themselves
+        if message.from().key() == Some(self.key) || message.is_deleted() {
+            return Ok(());
+        }
+
+        // Irc only has text, other payloads are sent as a notice saying what they are
+        let (command, text) = match message.payload() {
+            Value::String(text) => ("PRIVMSG", text.clone()),
+            payload => ("NOTICE", placeholder(payload)),
+        };
+        let from = message.from();
+        let in_channel = message.room().is_some() && message.room() == self.channel.as_deref();
+
+        if is_server(from) {
+            let target = match in_channel {
+                true => channel(message.room().unwrap_or_default()),
+                false => self.nick.clone(),
+            };
+            return self.notice(&target, &text);
+        }
+
+        let target = match (message.kind(), message.room()) {
+            (MessageKind::Direct, _) | (_, None) => self.nick.clone(),
+            (_, Some(room)) => channel(room),
+        };
+        let start = format!(
+            "{} {command} {target} :",
+            self.prefix(&from.username().to_string())
+        );
+        let action = command == "PRIVMSG" && message.kind() == MessageKind::Action;
+        self.send_text(&start, &text, action)
+    }
+    /// Mentions in the room the client is in are already seen as messages
+    fn mention(&mut self, message: &Message) -> Result<(), io::Error> {
+        if message.room() == self.channel.as_deref() {
+            return Ok(());
+        }
+
+        let nick = self.nick.clone();
+        let said = match message.payload() {
+            Value::String(text) => text.clone(),
+            payload => placeholder(payload),
+        };
+        let text = format!(
+            "{} mentioned you in {}: {said}",
+            message.from().username(),
+            channel(message.room().unwrap_or_default()),
+        );
+        self.notice(&nick, &text)
+    }
+    fn names(&mut self, room: &str, users: &[User]) -> Result<(), io::Error> {
+        let channel = channel(room);
+        let mut names = users
+            .iter()
+            .map(|user| irc_nick(&user.username().to_string()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if names.is_empty() {
+            names = self.nick.clone();
+        }
+
+        for chunk in split_text(&names, MAX_TEXT) {
+            self.numeric("353", &["=", &channel, chunk.trim()])?;
+        }
+        self.numeric("366", &[&channel, "End of /NAMES list"])
+    }
+    /// The room list says what room the client is in, tell the client if that changed
+    fn joined(&mut self, current: &str) -> Result<(), io::Error> {
+        if self.channel.as_deref() == Some(current) {
+            return Ok(());
+        }
+
+        let prefix = self.prefix(&self.nick);
+        if let Some(old) = self.channel.replace(current.to_owned()) {
+            self.send_line(format!("{prefix} PART {}", channel(&old)))?;
+        }
+        self.send_line(format!("{prefix} JOIN {}", channel(current)))?;
+        self.broadcaster
+            .lock()
+            .unwrap()
+            .send(BroadcastMessage::UserList(self.key))
+            .ok();
+        Ok(())
+    }
+    fn error(&mut self, error: &RequestError) -> Result<(), io::Error> {
+        match error {
+            RequestError::Username(reason) => {
+                let nick = self.nick.clone();
+                self.numeric("432", &[&nick, &reason.to_string()])
+            }
+            RequestError::Command(CommandError::UnknownUser(name)) => {
+                self.numeric("401", &[name, "No such nick"])
+            }
+            RequestError::Command(CommandError::BadRoom(room)) => {
+                self.numeric("403", &[&channel(room), "No such channel"])
+            }
+            RequestError::ServerShutdown(_)
+            | RequestError::ConnectionRefused(_)
+            | RequestError::Bad(_) => self.close(&error.to_string()),
+            error => {
+                let nick = self.nick.clone();
+                self.notice(&nick, &error.to_string())
+            }
+        }
+    }
+}
+
+fn is_server(user: &User) -> bool {
+    user.origin() == &Origin::Server
+}
+
+/// What a payload that is not text is shown as, e.g. `[image]`
+fn placeholder(payload: &Value) -> String {
+    format!("[{}]", payload.kind())
+}
+
+/// Split text into pieces of at most `max` bytes without splitting a character
+fn split_text(text: &str, max: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+
+    while rest.len() > max {
+        let mut end = max;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece);
+        rest = tail;
+    }
+    pieces.push(rest);
+
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::SERVER_USER;
+
+    fn parse(line: &str) -> (String, Vec<String>) {
+        let message = IrcMessage::parse(line).unwrap();
+        (message.command, message.params)
+    }
+
+    #[test]
+    fn parses_command_and_params() {
+        assert_eq!(
+            parse("privmsg #general :hello there\r\n"),
+            (
+                "PRIVMSG".to_owned(),
+                vec!["#general".to_owned(), "hello there".to_owned()]
+            )
+        );
+        assert_eq!(
+            parse("USER  guest 0 *  :Real Name"),
+            (
+                "USER".to_owned(),
+                vec![
+                    "guest".to_owned(),
+                    "0".to_owned(),
+                    "*".to_owned(),
+                    "Real Name".to_owned()
+                ]
+            )
+        );
+        assert_eq!(parse("PING"), ("PING".to_owned(), vec![]));
+    }
+
+    #[test]
+    fn drops_tags_and_prefix() {
+        assert_eq!(
+            parse("@time=now :nick!user@host JOIN #rust"),
+            ("JOIN".to_owned(), vec!["#rust".to_owned()])
+        );
+    }
+
+    #[test]
+    fn trailing_colon_is_an_empty_param() {
+        assert_eq!(
+            parse("PRIVMSG #general :"),
+            (
+                "PRIVMSG".to_owned(),
+                vec!["#general".to_owned(), String::new()]
+            )
+        );
+        assert_eq!(parse("QUIT :"), ("QUIT".to_owned(), vec![String::new()]));
+    }
+
+    #[test]
+    fn only_the_first_colon_starts_the_trailing_param() {
+        assert_eq!(
+            parse("PRIVMSG #general ::) see: this"),
+            (
+                "PRIVMSG".to_owned(),
+                vec!["#general".to_owned(), ":) see: this".to_owned()]
+            )
+        );
+    }
+
+    #[test]
+    fn lines_without_a_command_are_refused() {
+        for line in [
+            "",
+            "\r\n",
+            "   ",
+            ":prefix-only",
+            "@tags-only",
+            ":nick ",
+            ":nick :trailing",
+        ] {
+            assert!(IrcMessage::parse(line).is_none(), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn short_text_is_one_piece() {
+        assert_eq!(split_text("hello", 10), vec!["hello"]);
+        assert_eq!(split_text("", 10), vec![""]);
+        assert_eq!(split_text("ends with:", 10), vec!["ends with:"]);
+    }
+
+    #[test]
+    fn long_text_is_split_at_max() {
+        assert_eq!(split_text("abcdefgh:", 4), vec!["abcd", "efgh", ":"]);
+        assert_eq!(split_text("abcd:", 4), vec!["abcd", ":"]);
+    }
+
+    #[test]
+    fn characters_are_not_split() {
+        // Each of these is two bytes
+        let pieces = split_text("ééé", 3);
+        assert_eq!(pieces, vec!["é", "é", "é"]);
+        assert_eq!(split_text("aéé:", 4), vec!["aé", "é:"]);
+    }
+
+    #[test]
+    fn non_text_payloads_are_placeholders() {
+        assert_eq!(placeholder(&Value::Image(vec![1, 2, 3])), "[image]");
+        assert_eq!(placeholder(&Value::File(Vec::new())), "[file]");
+    }
+
+    #[test]
+    fn only_the_server_itself_is_sent_as_notices() {
+        assert!(is_server(&SERVER_USER));
+        let bot = User::builder()
+            .username(Username::new("SERVER"))
+            .origin(Origin::Integration)
+            .build();
+        assert!(!is_server(&bot));
+    }
+}
@@ -0,0 +1,118 @@
+//! Front-ends for protocols other than the native one. Each gateway listens on its own port and turns its protocol
+//! into requests and responses, so its clients are handled by a `Client` like any other and share the broadcaster,
+//! rooms, guidelines and limits.
+
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use chat_core::request::RequestError;
+
+use crate::{
+    client::ClientServices,
+    client_listener::ClientSpawner,
+    connection::Connection,
+    shutdown::{wake_address, ShutdownHandle},
+};
+
+pub mod irc;
+pub mod websocket;
+
+/// A protocol spoken by a gateway
+pub trait Protocol: Send + Sync + 'static {
+    /// Name of the protocol, used in the log
+    fn name(&self) -> &'static str;
+    /// Do the handshake of the protocol and create the connection of the client with the key. This runs on the thread
+    /// of the client, so a slow handshake does not hold up the listener.
+    fn connect(
+        &self,
+        stream: TcpStream,
+        key: usize,
+        services: &ClientServices,
+    ) -> Result<Connection, io::Error>;
+    /// Tell a client that it was refused before it got a thread, in the protocol's own words
+    fn refuse(&self, stream: &mut TcpStream, error: &RequestError);
+}
+
+/// A bound gateway that has not started accepting clients yet, bound when the server starts so a bad address is a
+/// startup error
+pub struct GatewayListener {
+    listener: TcpListener,
+    protocol: Arc<dyn Protocol>,
+}
+
+impl GatewayListener {
+    pub fn bind(address: &str, protocol: impl Protocol) -> Result<Self, io::Error> {
+        let listener = TcpListener::bind(address)?;
+        log::info!("{} gateway listening on {address}", protocol.name());
+
+        Ok(Self {
+            listener,
+            protocol: Arc::new(protocol),
+        })
+    }
+    /// Accept clients on a new thread until a shutdown is requested, `GatewayHandle::stop()` wakes the thread up so it
+    /// can see the request
+    pub fn run(
+        self,
+        spawner: ClientSpawner,
+        shutdown: ShutdownHandle,
+    ) -> Result<GatewayHandle, io::Error> {
+        let address = wake_address(self.listener.local_addr()?);
+        let name = self.protocol.name();
+
+        let thread = thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                if shutdown.requested().is_some() {
+                    break;
+                }
+
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        log::warn!("{name} gateway failed to accept connection: {error}");
+                        continue;
+                    }
+                };
+                log::info!("{name} gateway got connection");
+
+                let slot = match spawner.acquire_slot(&stream) {
+                    Ok(slot) => slot,
+                    Err(error) => {
+                        log::warn!("{name} gateway refusing connection: {error}");
+                        self.protocol.refuse(&mut stream, &error);
+                        continue;
+                    }
+                };
+
+                let protocol = Arc::clone(&self.protocol);
+                spawner.spawn(slot, move |key, services| {
+                    protocol.connect(stream, key, services)
+                });
+            }
+
+            log::info!("{name} gateway stopped");
+        });
+
+        Ok(GatewayHandle { address, thread })
+    }
+}
+
+/// Stops the thread started by `GatewayListener::run()`, a shutdown has to be requested first
+pub struct GatewayHandle {
+    address: SocketAddr,
+    thread: JoinHandle<()>,
+}
+
+impl GatewayHandle {
+    pub fn stop(self) {
+        // The listener blocks until it gets a connection, so give it one
+        if let Err(error) = TcpStream::connect(self.address) {
+            log::warn!("failed to wake up gateway: {error}");
+        }
+        let _ = self.thread.join();
+    }
+}
@@ -0,0 +1,151 @@
+//! Websockets for clients that cannot open a raw tcp connection or speak bincode, such as browsers. Every text message
+//! holds one `Request` (from the client) or `Response` (from the server) as json, the same types the native protocol
+//! uses so a websocket client can do anything a native one can.
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chat_core::{
+    request::{Request, RequestError},
+    response::Response,
+};
+use tungstenite::{
+    protocol::{Role, WebSocketConfig},
+    Message, WebSocket,
+};
+
+use crate::{
+    client::ClientServices,
+    connection::{Connection, RequestReader, ResponseWriter},
+};
+
+use super::Protocol;
+
+/// How long a client has to finish the websocket handshake before it is disconnected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct WebSocketProtocol;
+
+impl Protocol for WebSocketProtocol {
+    fn name(&self) -> &'static str {
+        "websocket"
+    }
+    fn connect(
+        &self,
+        stream: TcpStream,
+        _key: usize,
+        services: &ClientServices,
+    ) -> Result<Connection, io::Error> {
+        let address = stream.peer_addr()?;
+        let config = WebSocketConfig::default()
+            .max_message_size(Some(services.config.get().websocket.max_message_bytes()));
+        let stream = SharedStream {
+            read: stream.try_clone()?,
+            write: Arc::new(Mutex::new(stream)),
+        };
+
+        stream.read.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let reader = tungstenite::accept_with_config(stream.try_clone()?, Some(config))
+            .map_err(|error| io::Error::other(error.to_string()))?;
+        stream.read.set_read_timeout(None)?;
+        log::info!("{address} connected over websocket");
+
+        Ok(Connection {
+            reader: Box::new(WebSocketReader { socket: reader }),
+            writer: Arc::new(Mutex::new(WebSocketWriter {
+                socket: WebSocket::from_raw_socket(stream, Role::Server, Some(config)),
+            })),
+            addresses: (address, address),
+        })
+    }
+    fn refuse(&self, stream: &mut TcpStream, error: &RequestError) {
+        // The handshake has not happened yet, so the refusal is a plain http response
+        let body = error.to_string();
+        let _ = write!(
+            stream,
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+    }
+}
+
+/// The tcp stream of a client shared by the websocket that reads and the one that writes. Reading does not take the
+/// lock so the writer is never held up by a client that is not sending anything, and every write is done in full
+/// under the lock so frames from the two websockets (the reader answers pings) cannot end up mixed together.
+struct SharedStream {
+    read: TcpStream,
+    write: Arc<Mutex<TcpStream>>,
+}
+
+impl SharedStream {
+    fn try_clone(&self) -> Result<Self, io::Error> {
+        Ok(Self {
+            read: self.read.try_clone()?,
+            write: Arc::clone(&self.write),
+        })
+    }
+}
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read.read(buf)
+    }
+}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write.lock().unwrap().write_all(buf)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.write.lock().unwrap().flush()
+    }
+}
+
+/// Reads json requests, pings are answered by tungstenite
+struct WebSocketReader {
+    socket: WebSocket<SharedStream>,
+}
+
+impl RequestReader for WebSocketReader {
+    fn read_request(&mut self) -> Result<Request, io::Error> {
+        loop {
+            let request = match self.socket.read().map_err(io::Error::other)? {
+                Message::Text(text) => serde_json::from_str(&text),
+                Message::Binary(data) => serde_json::from_slice(&data),
+                Message::Close(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "websocket client closed the connection",
+                    ))
+                }
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            };
+
+            return request.map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error));
+        }
+    }
+}
+
+/// Writes json responses
+struct WebSocketWriter {
+    socket: WebSocket<SharedStream>,
+}
+
+impl ResponseWriter for WebSocketWriter {
+    fn write_response(&mut self, response: &Response) -> Result<(), io::Error> {
+        let json = serde_json::to_string(response)?;
+        self.socket
+            .send(Message::text(json))
+            .map_err(io::Error::other)
+    }
+    fn disconnect(&mut self) {
+        let _ = self.socket.close(None);
+        let _ = self.socket.flush();
+        let _ = self.socket.get_ref().read.shutdown(Shutdown::Both);
+    }
+}
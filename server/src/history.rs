@@ -0,0 +1,124 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use chat_core::{
+    message::{Message, MessageError, MessageId},
+    request::RequestError,
+};
+
+/// A message along with who reacted to it
+struct Entry {
+    message: Message,
+    /// The keys of the clients that reacted with each emoji
+    reactors: HashMap<String, HashSet<usize>>,
+}
+
+/// The most recent messages sent to rooms, older messages are dropped once `capacity` is reached. Messages are kept
+/// in the order they were accepted so they are also sorted by id.
+pub struct History {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity.min(1024)),
+            capacity,
+        }
+    }
+    /// Add a message that has been given an id, messages without an id are ignored
+    pub fn push(&mut self, message: Message) {
+        if self.capacity == 0 || message.id().is_none() {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            message,
+            reactors: HashMap::new(),
+        });
+    }
+    pub fn get(&self, id: MessageId) -> Option<&Message> {
+        self.entry(id).map(|entry| &entry.message)
+    }
+    pub fn get_mut(&mut self, id: MessageId) -> Option<&mut Message> {
+        self.entry_mut(id).map(|entry| &mut entry.message)
+    }
+    /// Add the reaction of the client with the key `by`, a message can have at most `max_reactions` different
+    /// reactions. Returns the message if the reaction was added, or `None` if the client had already reacted with
+    /// the emoji.
+    pub fn react(
+        &mut self,
+        id: MessageId,
+        emoji: &str,
+        by: usize,
+        max_reactions: usize,
+    ) -> Result<Option<&Message>, RequestError> {
+        let entry = self
+            .entry_mut(id)
+            .filter(|entry| !entry.message.is_deleted())
+            .ok_or(RequestError::UnknownMessage(id))?;
+
+        if !entry.reactors.contains_key(emoji) && entry.reactors.len() >= max_reactions {
+            return Err(RequestError::Message(MessageError::TooManyReactions));
+        }
+        if !entry
+            .reactors
+            .entry(emoji.to_owned())
+            .or_default()
+            .insert(by)
+        {
+            return Ok(None);
+        }
+
+        entry.message.add_reaction(emoji);
+        Ok(Some(&entry.message))
+    }
+    /// Take back the reaction of the client with the key `by`. Returns the message if the reaction was removed, or
+    /// `None` if the client had not reacted with the emoji.
+    pub fn unreact(
+        &mut self,
+        id: MessageId,
+        emoji: &str,
+        by: usize,
+    ) -> Result<Option<&Message>, RequestError> {
+        let entry = self
+            .entry_mut(id)
+            .filter(|entry| !entry.message.is_deleted())
+            .ok_or(RequestError::UnknownMessage(id))?;
+
+        let Some(reactors) = entry.reactors.get_mut(emoji) else {
+            return Ok(None);
+        };
+        if !reactors.remove(&by) {
+            return Ok(None);
+        }
+        if reactors.is_empty() {
+            entry.reactors.remove(emoji);
+        }
+
+        entry.message.remove_reaction(emoji);
+        Ok(Some(&entry.message))
+    }
+    /// How many messages in `room` newer than `after` have not been deleted
+    pub fn unread(&self, room: &str, after: MessageId) -> usize {
+        self.entries
+            .iter()
+            .rev()
+            .take_while(|entry| entry.message.id().is_some_and(|id| id > after))
+            .filter(|entry| !entry.message.is_deleted() && entry.message.room() == Some(room))
+            .count()
+    }
+    fn entry(&self, id: MessageId) -> Option<&Entry> {
+        self.position(id).map(|index| &self.entries[index])
+    }
+    fn entry_mut(&mut self, id: MessageId) -> Option<&mut Entry> {
+        self.position(id).map(|index| &mut self.entries[index])
+    }
+    fn position(&self, id: MessageId) -> Option<usize> {
+        self.entries
+            .binary_search_by_key(&Some(id), |entry| entry.message.id())
+            .ok()
+    }
+}
@@ -0,0 +1,209 @@
+//! An http endpoint integrations (such as CI) can post messages to. A message is sent with
+//! `POST /messages` and a json body like `{"room": "general", "text": "build passed"}`, authenticated with
+//! `Authorization: Bearer <token>` using a token from `[[http_api.integrations]]`.
+
+use std::{
+    io::{self, Read},
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use chat_core::{
+    guidelines::AgainstGuidelines,
+    message::Message,
+    user::{Origin, User, Username},
+    value::Value,
+};
+use serde::Deserialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    broadcast::{BroadcastMessage, DEFAULT_ROOM},
+    command::builtin::valid_room,
+    config::IntegrationConfig,
+    metrics::Metrics,
+    reload::SharedConfig,
+};
+
+/// The body of `POST /messages`, the room is `DEFAULT_ROOM` if it is left out
+#[derive(Deserialize)]
+struct PostMessage {
+    text: String,
+    room: Option<String>,
+}
+
+/// Why a request was not accepted, sent back as `{"error": "..."}`
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+/// The http listener, bound when the server starts so a bad address is a startup error
+pub struct HttpApi {
+    server: Arc<Server>,
+}
+
+impl HttpApi {
+    pub fn bind(address: &str) -> Result<Self, io::Error> {
+        let server = Server::http(address).map_err(io::Error::other)?;
+        log::info!("http api listening on {address}");
+
+        Ok(Self {
+            server: Arc::new(server),
+        })
+    }
+    /// Serve requests on a new thread, accepted messages are sent to the broadcaster. The integrations are read from
+    /// the config for every request so tokens can be changed by reloading it.
+    pub fn run(
+        self,
+        config: Arc<SharedConfig>,
+        broadcaster: Arc<Mutex<Sender<BroadcastMessage>>>,
+        metrics: Arc<Metrics>,
+    ) -> HttpApiHandle {
+        let server = Arc::clone(&self.server);
+
+        let thread = thread::spawn(move || {
+            for mut request in server.incoming_requests() {
+                let (status, body) =
+                    match post_message(&config, &broadcaster, &metrics, &mut request) {
+                        Ok(()) => (202, serde_json::json!({ "accepted": true })),
+                        Err(error) => {
+                            log::info!("http api request refused: {}", error.message);
+                            (error.status, serde_json::json!({ "error": error.message }))
+                        }
+                    };
+
+                let response = Response::from_string(body.to_string())
+                    .with_status_code(status)
+                    .with_header(
+                        Header::from_bytes("Content-Type", "application/json")
+                            .expect("header is valid"),
+                    );
+                if let Err(error) = request.respond(response) {
+                    log::warn!("failed to respond to http api request: {error}");
+                }
+            }
+
+            log::info!("http api stopped");
+        });
+
+        HttpApiHandle {
+            server: self.server,
+            thread,
+        }
+    }
+}
+
+/// Stops the thread started by `HttpApi::run()`
+pub struct HttpApiHandle {
+    server: Arc<Server>,
+    thread: JoinHandle<()>,
+}
+
+impl HttpApiHandle {
+    pub fn stop(self) {
+        self.server.unblock();
+        let _ = self.thread.join();
+    }
+}
+
+/// Check a `POST /messages` request and send its message to the broadcaster as the integration the token belongs to
+fn post_message(
+    config: &SharedConfig,
+    broadcaster: &Mutex<Sender<BroadcastMessage>>,
+    metrics: &Metrics,
+    request: &mut Request,
+) -> Result<(), ApiError> {
+    if request.url() != "/messages" {
+        return Err(ApiError::new(
+            404,
+            "not found, messages are posted to /messages",
+        ));
+    }
+    if *request.method() != Method::Post {
+        return Err(ApiError::new(405, "only POST is allowed"));
+    }
+
+    let config = config.get();
+    let token = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .ok_or_else(|| ApiError::new(401, "missing \"Authorization: Bearer <token>\" header"))?;
+    let integration = find_integration(config.http_api.integrations(), token.trim())
+        .ok_or_else(|| ApiError::new(401, "unknown token"))?;
+
+    let max = config.http_api.max_body_bytes();
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(max as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|error| ApiError::new(400, format!("could not read the body: {error}")))?;
+    if body.len() > max {
+        return Err(ApiError::new(413, format!("the body is over {max} bytes")));
+    }
+    let post: PostMessage = serde_json::from_slice(&body)
+        .map_err(|error| ApiError::new(400, format!("bad json: {error}")))?;
+
+    let room = post.room.as_deref().unwrap_or(DEFAULT_ROOM);
+    let room = room.strip_prefix('#').unwrap_or(room);
+    if !valid_room(room) {
+        return Err(ApiError::new(
+            400,
+            format!("{room:?} is not a valid room name"),
+        ));
+    }
+
+    let message = Message::builder()
+        .from_who(
+            User::builder()
+                .id(0)
+                .username(Username::new(integration.name()))
+                .origin(Origin::Integration)
+                .build(),
+        )
+        .payload(Value::String(post.text))
+        .room(Some(room.to_owned()))
+        .build()
+        .against_guidelines(&config.message_guidelines)
+        .map_err(|error| {
+            metrics.rejected(&error);
+            ApiError::new(400, format!("bad message: {error}"))
+        })?;
+
+    log::info!("integration {} posted to #{room}", integration.name());
+    broadcaster
+        .lock()
+        .unwrap()
+        .send(BroadcastMessage::ChatMessage(message))
+        .map_err(|_| ApiError::new(503, "the server is shutting down"))
+}
+
+/// The integration the token belongs to, every token is compared in full so the time taken does not give away how
+/// much of a token was right
+fn find_integration<'a>(
+    integrations: &'a [IntegrationConfig],
+    token: &str,
+) -> Option<&'a IntegrationConfig> {
+    integrations.iter().fold(None, |found, integration| {
+        match constant_time_eq(integration.token().as_bytes(), token.as_bytes()) {
+            true => Some(integration),
+            false => found,
+        }
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,23 @@
+use std::collections::HashMap;
+
+use chat_core::encryption::PublicKey;
+
+/// The public keys users published for encrypted direct messages, by user id. Ids are not reused, so a key is
+/// forgotten once its user disconnects and a reconnecting user publishes its key again.
+#[derive(Default)]
+pub struct KeyDirectory {
+    keys: HashMap<usize, PublicKey>,
+}
+
+impl KeyDirectory {
+    /// Set the key of the user, replacing the one it published before
+    pub fn publish(&mut self, user: usize, key: PublicKey) {
+        self.keys.insert(user, key);
+    }
+    pub fn get(&self, user: usize) -> Option<PublicKey> {
+        self.keys.get(&user).copied()
+    }
+    pub fn remove(&mut self, user: usize) {
+        self.keys.remove(&user);
+    }
+}
@@ -1,4 +1,20 @@
 pub mod broadcast;
 pub mod client;
 pub mod client_listener;
+pub mod command;
 pub mod config;
+pub mod connection;
+pub mod connection_limit;
+pub mod federation;
+pub mod gateway;
+pub mod history;
+pub mod http_api;
+pub mod key_directory;
+pub mod mention;
+pub mod metrics;
+pub mod plugin;
+pub mod rate_limit;
+pub mod read_marker;
+pub mod reload;
+pub mod shutdown;
+pub mod webhook;
@@ -1,37 +1,100 @@
-use std::process;
+use std::{env, process, sync::Arc, thread};
 
-use chat_core::config::Config;
+use chat_core::config::{Config, ConfigArgs, ConfigError};
 use log::LevelFilter;
-use server::{client_listener::ClientListener, config::ServerConfig};
+use server::{
+    client_listener::ClientListener,
+    config::ServerConfig,
+    reload::{self, SharedConfig},
+    shutdown::ShutdownHandle,
+};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
 use simple_logger::SimpleLogger;
 
 fn main() {
-    let config = match ServerConfig::load() {
-        Ok(config) => config,
+    let args = match ConfigArgs::parse(env::args()) {
+        Ok(args) => args,
         Err(error) => {
-            log::warn!("failed to load conifg: {error}");
-            log::info!("using default config");
-            ServerConfig::default()
+            eprintln!("{error}");
+            process::exit(2);
         }
     };
+    if args.dump_default {
+        print!("{}", ServerConfig::TEMPLATE);
+        return;
+    }
+    let path = args.path;
 
+    // The logger lets everything through so the level can be changed when the config is reloaded
     SimpleLogger::new()
-        .with_level(if config.system.verbose() {
-            LevelFilter::Trace
-        } else {
-            LevelFilter::Warn
-        })
+        .with_level(LevelFilter::Trace)
         .init()
         .unwrap();
 
+    let config = match ServerConfig::load_layered(path.as_deref()) {
+        Ok(config) => config,
+        // Missing values are already the defaults, so a config that fails to load is broken and should be fixed
+        // instead of being replaced with the defaults
+        Err(error @ ConfigError::Invalid(_)) => {
+            log::error!("{error}");
+            process::exit(1);
+        }
+        Err(error) => {
+            let path = path.or_else(ServerConfig::find).unwrap_or_default();
+            log::error!("failed to load config {}: {error}", path.display());
+            process::exit(1);
+        }
+    };
+    log::set_max_level(reload::verbosity(&config));
+
     log::info!("config loaded");
 
-    match ClientListener::new(config) {
+    let client_listener = match ClientListener::new(SharedConfig::new(config, path)) {
         Ok(client_listener) => client_listener,
         Err(error) => {
             log::error!("Failed to start client: {error}");
             process::exit(1);
         }
+    };
+
+    let signals = match Signals::new([SIGINT, SIGTERM, SIGHUP]) {
+        Ok(signals) => signals,
+        Err(error) => {
+            log::error!("Failed to register signal handlers: {error}");
+            process::exit(1);
+        }
+    };
+    let shutdown = client_listener.shutdown_handle();
+    let config = client_listener.config();
+    thread::spawn(move || handle_signals(signals, shutdown, config));
+
+    client_listener.run();
+}
+
+/// SIGHUP reloads the config, the first SIGINT/SIGTERM shuts the server down gracefully and the second one exits
+/// right away
+fn handle_signals(mut signals: Signals, shutdown: ShutdownHandle, config: Arc<SharedConfig>) {
+    let mut shutting_down = false;
+
+    for signal in signals.forever() {
+        match signal {
+            SIGHUP => {
+                if let Err(error) = config.reload() {
+                    log::error!("failed to reload config, keeping the current one: {error}");
+                }
+            }
+            _ if shutting_down => {
+                log::warn!("recieved signal {signal} again, exiting now");
+                process::exit(1);
+            }
+            _ => {
+                log::info!("recieved signal {signal}, shutting down");
+                shutting_down = true;
+                shutdown.shutdown(config.get().shutdown.notice());
+            }
+        }
     }
-    .run();
 }
@@ -0,0 +1,16 @@
+/// A word starting with this mentions the user with the rest of the word as their username, e.g. `@joey`
+pub const MENTION_PREFIX: char = '@';
+
+/// The usernames mentioned in `text`, punctuation at the end of a mention (like in `@joey,`) is not part of the name
+pub fn mentioned_names(text: &str) -> Vec<&str> {
+    let mut names = text
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix(MENTION_PREFIX))
+        .map(|name| name.trim_end_matches(|c: char| c.is_ascii_punctuation()))
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+    names.sort_unstable();
+    names.dedup();
+
+    names
+}
@@ -0,0 +1,365 @@
+//! Counters and gauges about the running server, served in the prometheus text format on `GET /metrics`. Counters only
+//! go up, so rates such as messages per second are worked out by prometheus, e.g. `rate(chat_messages_total[1m])`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use chat_core::{message::MessageError, response::Response};
+use tiny_http::{Header, Method, Server};
+
+use crate::connection::{ResponseWriter, SharedWriter};
+
+/// Upper bounds of the broadcast latency buckets in seconds
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.25, 1.0,
+];
+
+/// Everything that is measured, shared by the listener, the client handlers and the broadcaster
+#[derive(Default)]
+pub struct Metrics {
+    /// Clients the broadcaster is sending to
+    connected_clients: AtomicUsize,
+    /// Messages given an id by the broadcaster
+    messages: AtomicU64,
+    /// Messages refused for not following the guidelines, by `MessageError` variant
+    rejections: Mutex<BTreeMap<&'static str, u64>>,
+    /// How long the broadcaster takes to handle a `BroadcastMessage`
+    broadcast_latency: Histogram,
+    /// Failed writes by the key of the client, a client is forgotten once it is gone
+    write_failures: Mutex<BTreeMap<usize, u64>>,
+    /// Failed writes of every client, including the ones that are gone
+    write_failures_total: AtomicU64,
+    pool_threads: AtomicUsize,
+    /// Pool threads handling a client
+    pool_busy: AtomicUsize,
+    /// Clients waiting for a pool thread
+    pool_queued: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn set_connected_clients(&self, clients: usize) {
+        self.connected_clients.store(clients, Ordering::Relaxed);
+    }
+    pub fn message_sent(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn rejected(&self, error: &MessageError) {
+        *self
+            .rejections
+            .lock()
+            .unwrap()
+            .entry(rejection_reason(error))
+            .or_default() += 1;
+    }
+    pub fn broadcast_handled(&self, took: Duration) {
+        self.broadcast_latency.observe(took);
+    }
+    pub fn write_failed(&self, key: usize) {
+        self.write_failures_total.fetch_add(1, Ordering::Relaxed);
+        *self.write_failures.lock().unwrap().entry(key).or_default() += 1;
+    }
+    /// Forget the client with the key, so the failures of clients that are gone do not pile up
+    pub fn client_gone(&self, key: usize) {
+        self.write_failures.lock().unwrap().remove(&key);
+    }
+    pub fn set_pool_threads(&self, threads: usize) {
+        self.pool_threads.store(threads, Ordering::Relaxed);
+    }
+    /// Count a client as waiting for a pool thread until `QueuedClient::start()` is called on the thread
+    pub fn client_queued(self: &Arc<Self>) -> QueuedClient {
+        self.pool_queued.fetch_add(1, Ordering::Relaxed);
+        QueuedClient {
+            metrics: Arc::clone(self),
+        }
+    }
+    /// Wrap the writer of the client with the key so its failed writes are counted, from the client handler and from
+    /// the broadcaster alike
+    pub fn metered_writer(self: &Arc<Self>, key: usize, writer: SharedWriter) -> SharedWriter {
+        Arc::new(Mutex::new(MeteredWriter {
+            key,
+            writer,
+            metrics: Arc::clone(self),
+        }))
+    }
+    /// Everything in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |value: &AtomicUsize| value.load(Ordering::Relaxed) as u64;
+
+        single(
+            &mut out,
+            "chat_connected_clients",
+            "gauge",
+            "Clients connected to the server",
+            load(&self.connected_clients),
+        );
+        single(
+            &mut out,
+            "chat_messages_total",
+            "counter",
+            "Messages sent out by the server, rate() of it is messages per second",
+            self.messages.load(Ordering::Relaxed),
+        );
+
+        metric(
+            &mut out,
+            "chat_guideline_rejections_total",
+            "counter",
+            "Messages refused for not following the message guidelines, by reason",
+        );
+        for (reason, count) in self.rejections.lock().unwrap().iter() {
+            let labels = format!("reason=\"{reason}\"");
+            sample(&mut out, "chat_guideline_rejections_total", &labels, count);
+        }
+
+        metric(
+            &mut out,
+            "chat_broadcast_duration_seconds",
+            "histogram",
+            "How long the broadcaster takes to handle a broadcast message",
+        );
+        self.broadcast_latency
+            .render(&mut out, "chat_broadcast_duration_seconds");
+
+        single(
+            &mut out,
+            "chat_write_failures_total",
+            "counter",
+            "Responses that could not be written to a client",
+            self.write_failures_total.load(Ordering::Relaxed),
+        );
+        metric(
+            &mut out,
+            "chat_client_write_failures_total",
+            "counter",
+            "Responses that could not be written to a connected client, by client key",
+        );
+        for (key, count) in self.write_failures.lock().unwrap().iter() {
+            let labels = format!("client=\"{key}\"");
+            sample(&mut out, "chat_client_write_failures_total", &labels, count);
+        }
+
+        single(
+            &mut out,
+            "chat_pool_threads",
+            "gauge",
+            "Threads in the pool, every client takes up one for as long as it is connected",
+            load(&self.pool_threads),
+        );
+        single(
+            &mut out,
+            "chat_pool_busy_threads",
+            "gauge",
+            "Pool threads handling a client",
+            load(&self.pool_busy),
+        );
+        single(
+            &mut out,
+            "chat_pool_queued_clients",
+            "gauge",
+            "Clients waiting for a pool thread",
+            load(&self.pool_queued),
+        );
+
+        out
+    }
+}
+
+/// The label of a guideline rejection, every variant is listed so a new one cannot be left out
+fn rejection_reason(error: &MessageError) -> &'static str {
+    match error {
+        MessageError::Empty => "empty",
+        MessageError::JustWhitespace => "just_whitespace",
+        MessageError::LeadingWhitespace => "leading_whitespace",
+        MessageError::TrailingWhitespace => "trailing_whitespace",
+        MessageError::TextOnly => "text_only",
+        MessageError::BadReaction => "bad_reaction",
+        MessageError::TooManyReactions => "too_many_reactions",
+        MessageError::Encrypted => "encrypted",
+        MessageError::EncryptedNotDirect => "encrypted_not_direct",
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// A metric without labels
+fn single(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    metric(out, name, kind, help);
+    sample(out, name, "", value);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    match labels {
+        "" => {
+            let _ = writeln!(out, "{name} {value}");
+        }
+        labels => {
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations that fit in each bucket but not the one before, made cumulative when rendered
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, took: Duration) {
+        let seconds = took.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(
+            u64::try_from(took.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+    fn render(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            sample(
+                out,
+                &format!("{name}_bucket"),
+                &format!("le=\"{bound}\""),
+                cumulative,
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        sample(out, &format!("{name}_bucket"), "le=\"+Inf\"", count);
+        let sum = Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed));
+        sample(out, &format!("{name}_sum"), "", sum.as_secs_f64());
+        sample(out, &format!("{name}_count"), "", count);
+    }
+}
+
+/// A client waiting for a pool thread, see `Metrics::client_queued()`
+pub struct QueuedClient {
+    metrics: Arc<Metrics>,
+}
+
+impl QueuedClient {
+    /// The client got a thread, it counts as busy until the returned value is dropped
+    pub fn start(self) -> BusyThread {
+        self.metrics.pool_busy.fetch_add(1, Ordering::Relaxed);
+        BusyThread {
+            metrics: Arc::clone(&self.metrics),
+        }
+    }
+}
+
+impl Drop for QueuedClient {
+    fn drop(&mut self) {
+        self.metrics.pool_queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A pool thread handling a client, see `QueuedClient::start()`
+pub struct BusyThread {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for BusyThread {
+    fn drop(&mut self) {
+        self.metrics.pool_busy.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts the failed writes of a client
+struct MeteredWriter {
+    key: usize,
+    writer: SharedWriter,
+    metrics: Arc<Metrics>,
+}
+
+impl ResponseWriter for MeteredWriter {
+    fn write_response(&mut self, response: &Response) -> Result<(), io::Error> {
+        let result = self.writer.lock().unwrap().write_response(response);
+        if result.is_err() {
+            self.metrics.write_failed(self.key);
+        }
+        result
+    }
+    fn disconnect(&mut self) {
+        self.writer.lock().unwrap().disconnect();
+    }
+}
+
+/// The http listener, bound when the server starts so a bad address is a startup error
+pub struct MetricsServer {
+    server: Arc<Server>,
+}
+
+impl MetricsServer {
+    pub fn bind(address: &str) -> Result<Self, io::Error> {
+        let server = Server::http(address).map_err(io::Error::other)?;
+        log::info!("metrics listening on {address}");
+
+        Ok(Self {
+            server: Arc::new(server),
+        })
+    }
+    /// Serve `GET /metrics` on a new thread
+    pub fn run(self, metrics: Arc<Metrics>) -> MetricsHandle {
+        let server = Arc::clone(&self.server);
+
+        let thread = thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let (status, body) = if request.url() != "/metrics" {
+                    (404, "not found, metrics are at /metrics\n".to_owned())
+                } else if *request.method() != Method::Get {
+                    (405, "only GET is allowed\n".to_owned())
+                } else {
+                    (200, metrics.render())
+                };
+
+                let response = tiny_http::Response::from_string(body)
+                    .with_status_code(status)
+                    .with_header(
+                        Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+                            .expect("header is valid"),
+                    );
+                if let Err(error) = request.respond(response) {
+                    log::warn!("failed to respond to metrics request: {error}");
+                }
+            }
+
+            log::info!("metrics stopped");
+        });
+
+        MetricsHandle {
+            server: self.server,
+            thread,
+        }
+    }
+}
+
+/// Stops the thread started by `MetricsServer::run()`
+pub struct MetricsHandle {
+    server: Arc<Server>,
+    thread: JoinHandle<()>,
+}
+
+impl MetricsHandle {
+    pub fn stop(self) {
+        self.server.unblock();
+        let _ = self.thread.join();
+    }
+}
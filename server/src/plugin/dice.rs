@@ -0,0 +1,171 @@
+use chat_core::{command::CommandError, request::RequestError, user::User};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::command::Args;
+
+use super::{parse_settings, Plugin, PluginCommandInfo, PluginContext, PluginError};
+
+pub const NAME: &str = "dice";
+
+const ROLL: PluginCommandInfo = PluginCommandInfo {
+    name: "roll",
+    usage: "/roll [<count>d<sides>]",
+    help: "roll dice for everyone in the room to see, e.g. /roll 2d6",
+};
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct DiceSettings {
+    max_dice: u32,
+    max_sides: u32,
+}
+
+impl Default for DiceSettings {
+    fn default() -> Self {
+        Self {
+            max_dice: 20,
+            max_sides: 1000,
+        }
+    }
+}
+
+/// Adds `/roll`, the result is said in the room of whoever rolled
+pub struct Dice {
+    settings: DiceSettings,
+}
+
+impl Dice {
+    pub fn create(settings: toml::Table) -> Result<Box<dyn Plugin>, PluginError> {
+        Ok(Box::new(Self {
+            settings: parse_settings(NAME, settings)?,
+        }))
+    }
+    /// Parse dice such as `2d6` or `d20`, a single die with six sides if nothing is given
+    fn parse(&self, dice: Option<&str>) -> Option<(u32, u32)> {
+        let Some(dice) = dice else {
+            return Some((1, 6));
+        };
+        let (count, sides) = dice
+            .to_ascii_lowercase()
+            .split_once('d')
+            .map(|(count, sides)| {
+                let count = if count.is_empty() {
+                    Ok(1)
+                } else {
+                    count.parse()
+                };
+                (count, sides.parse())
+            })?;
+        let (count, sides) = (count.ok()?, sides.ok()?);
+
+        ((1..=self.settings.max_dice).contains(&count)
+            && (2..=self.settings.max_sides).contains(&sides))
+        .then_some((count, sides))
+    }
+}
+
+impl Plugin for Dice {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+    fn commands(&self) -> Vec<PluginCommandInfo> {
+        vec![ROLL]
+    }
+    fn on_command(
+        &mut self,
+        ctx: &mut PluginContext,
+        user: &User,
+        room: &str,
+        _name: &str,
+        mut args: Args,
+    ) -> Result<(), RequestError> {
+        let (count, sides) = self
+            .parse(args.rest())
+            .ok_or_else(|| CommandError::Usage(ROLL.usage.to_owned()))?;
+
+        let mut rng = rand::thread_rng();
+        let rolls = (0..count)
+            .map(|_| rng.gen_range(1..=sides))
+            .collect::<Vec<_>>();
+        // Big enough that the most dice with the most sides the settings allow cannot overflow it
+        let total = rolls.iter().copied().map(u64::from).sum::<u64>();
+
+        let text = if count == 1 {
+            format!("{} rolled a d{sides}: {total}", user.username())
+        } else {
+            let rolls = rolls
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(" + ");
+            format!(
+                "{} rolled {count}d{sides}: {rolls} = {total}",
+                user.username()
+            )
+        };
+        ctx.say(room, text);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dice() -> Dice {
+        Dice {
+            settings: DiceSettings::default(),
+        }
+    }
+
+    #[test]
+    fn no_spec_is_one_six_sided_die() {
+        assert_eq!(dice().parse(None), Some((1, 6)));
+    }
+
+    #[test]
+    fn parses_count_and_sides() {
+        let dice = dice();
+        assert_eq!(dice.parse(Some("2d6")), Some((2, 6)));
+        assert_eq!(dice.parse(Some("d20")), Some((1, 20)));
+        assert_eq!(dice.parse(Some("3D8")), Some((3, 8)));
+        assert_eq!(dice.parse(Some("20d1000")), Some((20, 1000)));
+    }
+
+    #[test]
+    fn empty_or_malformed_specs_are_refused() {
+        let dice = dice();
+        for spec in ["", "d", "2d", "2", "six", "2d6d6", "-1d6", "2d-6", "1.5d6"] {
+            assert_eq!(dice.parse(Some(spec)), None, "{spec}");
+        }
+    }
+
+    #[test]
+    fn overflowing_specs_are_refused() {
+        let dice = dice();
+        for spec in [
+            "4294967296d6",
+            "1d4294967296",
+            "99999999999999999999d99999999999999999999",
+        ] {
+            assert_eq!(dice.parse(Some(spec)), None, "{spec}");
+        }
+    }
+
+    #[test]
+    fn specs_outside_the_settings_are_refused() {
+        let dice = dice();
+        for spec in ["0d6", "21d6", "1d1", "1d0", "1d1001"] {
+            assert_eq!(dice.parse(Some(spec)), None, "{spec}");
+        }
+
+        let dice = Dice {
+            settings: toml::from_str("max_dice = 2\nmax_sides = 4").unwrap(),
+        };
+        assert_eq!(dice.parse(Some("2d4")), Some((2, 4)));
+        assert_eq!(dice.parse(Some("3d4")), None);
+        assert_eq!(dice.parse(Some("2d5")), None);
+    }
+}
@@ -0,0 +1,261 @@
+//! Server side bots. A plugin is told when clients connect and disconnect, sees every message before it is broadcast
+//! and can add its own commands. Plugins run on the broadcaster thread in the order they are enabled in the config,
+//! so a hook should return quickly.
+
+use std::collections::BTreeMap;
+
+use chat_core::{
+    message::{Message, MessageKind},
+    request::RequestError,
+    user::{Origin, User, Username},
+    value::Value,
+};
+use thiserror::Error;
+
+use crate::{
+    broadcast::BroadcastMessage,
+    command::{Args, Command, CommandContext, CommandRegistry},
+    config::PluginsConfig,
+};
+
+pub mod dice;
+pub mod welcome;
+
+#[derive(Debug, Error)]
+pub enum PluginError {
+    #[error("there is no plugin named {0:?}")]
+    Unknown(String),
+    #[error("plugin {0:?} is enabled more than once")]
+    Duplicate(String),
+    #[error("bad settings for plugin {plugin:?}: {error}")]
+    Settings {
+        plugin: String,
+        error: toml::de::Error,
+    },
+}
+
+/// A command added by a plugin, it is listed in `/help` like the built in commands
+#[derive(Debug, Clone, Copy)]
+pub struct PluginCommandInfo {
+    /// Name of the command, used as `/name`
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+}
+
+/// What a plugin decided about a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Let the message through, changes made to it are kept
+    Allow,
+    /// Drop the message, the sender gets `RequestError::Rejected` with the reason
+    Veto(String),
+}
+
+/// Something a plugin asked for during a hook, done by the broadcaster once the hook returns
+pub(crate) enum Action {
+    /// Send a message to a room, or to every client if there is no room
+    Say { room: Option<String>, text: String },
+    /// Send a message to only the client with the key
+    Whisper { to: usize, text: String },
+}
+
+/// Passed to every hook, messages sent through it come from the bot user of the plugin
+pub struct PluginContext {
+    bot: User,
+    actions: Vec<Action>,
+}
+
+impl PluginContext {
+    pub(crate) fn new(plugin: &dyn Plugin) -> Self {
+        Self {
+            bot: User::builder()
+                .id(0)
+                .username(Username::new(plugin.name()))
+                .origin(Origin::Integration)
+                .build(),
+            actions: Vec::new(),
+        }
+    }
+    /// The user messages from the plugin are sent as
+    pub fn bot(&self) -> &User {
+        &self.bot
+    }
+    /// Send a message to everyone in `room`
+    pub fn say(&mut self, room: &str, text: impl Into<String>) {
+        self.actions.push(Action::Say {
+            room: Some(room.to_owned()),
+            text: text.into(),
+        });
+    }
+    /// Send a message to every client in every room
+    pub fn announce(&mut self, text: impl Into<String>) {
+        self.actions.push(Action::Say {
+            room: None,
+            text: text.into(),
+        });
+    }
+    /// Send a message only `user` can see
+    pub fn whisper(&mut self, user: &User, text: impl Into<String>) {
+        self.actions.push(Action::Whisper {
+            to: user.id(),
+            text: text.into(),
+        });
+    }
+    /// The messages asked for, built as the bot user
+    pub(crate) fn into_messages(self) -> Vec<(Option<usize>, Message)> {
+        let bot = self.bot;
+        self.actions
+            .into_iter()
+            .map(|action| {
+                let (to, room, kind, text) = match action {
+                    Action::Say { room, text } => (None, room, MessageKind::Text, text),
+                    Action::Whisper { to, text } => (Some(to), None, MessageKind::Direct, text),
+                };
+                let message = Message::builder()
+                    .from_who(bot.clone())
+                    .payload(Value::String(text))
+                    .kind(kind)
+                    .room(room)
+                    .build();
+                (to, message)
+            })
+            .collect()
+    }
+}
+
+/// A server side bot, every hook does nothing by default. `user` is always the user of the client that caused the
+/// hook, messages sent by plugins do not go through `on_message`.
+pub trait Plugin: Send {
+    /// Name of the plugin as it is enabled in the config, also the username of its bot user
+    fn name(&self) -> &'static str;
+    /// Commands the plugin handles in `on_command`, a command with the same name as a built in one is ignored
+    fn commands(&self) -> Vec<PluginCommandInfo> {
+        Vec::new()
+    }
+    /// A client connected and has been placed in `room`
+    fn on_connect(&mut self, _ctx: &mut PluginContext, _user: &User, _room: &str) {}
+    /// A client disconnected or was kicked
+    fn on_disconnect(&mut self, _ctx: &mut PluginContext, _user: &User) {}
+    /// A client sent a message, it has been checked against the message guidelines but changes made here are not
+    /// checked again
+    fn on_message(&mut self, _ctx: &mut PluginContext, _message: &mut Message) -> Verdict {
+        Verdict::Allow
+    }
+    /// A client ran one of the commands from `commands()` in `room`
+    fn on_command(
+        &mut self,
+        _ctx: &mut PluginContext,
+        _user: &User,
+        _room: &str,
+        _name: &str,
+        _args: Args,
+    ) -> Result<(), RequestError> {
+        Ok(())
+    }
+}
+
+/// Creates a plugin from its settings, the table under `[plugins.<name>]` in the config (empty if there is none)
+pub type PluginFactory = fn(toml::Table) -> Result<Box<dyn Plugin>, PluginError>;
+
+/// Every plugin that can be enabled in the config, binaries built on the server library can register their own with
+/// `PluginRegistry::register()`
+#[derive(Default)]
+pub struct PluginRegistry {
+    factories: BTreeMap<&'static str, PluginFactory>,
+}
+
+impl PluginRegistry {
+    /// Create a registry with the plugins that ship with the server
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::default();
+        registry.register(welcome::NAME, welcome::Welcome::create);
+        registry.register(dice::NAME, dice::Dice::create);
+        registry
+    }
+    /// Add a plugin, replacing any plugin that has the same name
+    pub fn register(&mut self, name: &'static str, factory: PluginFactory) {
+        log::debug!("registering plugin {name}");
+        self.factories.insert(name, factory);
+    }
+    /// Create the plugins enabled in the config, in the order they are listed
+    pub fn create(&self, config: &PluginsConfig) -> Result<Vec<Box<dyn Plugin>>, PluginError> {
+        let mut plugins: Vec<Box<dyn Plugin>> = Vec::new();
+
+        for (index, name) in config.enabled().iter().enumerate() {
+            if config.enabled()[..index].contains(name) {
+                return Err(PluginError::Duplicate(name.clone()));
+            }
+            let factory = self
+                .factories
+                .get(name.as_str())
+                .ok_or_else(|| PluginError::Unknown(name.clone()))?;
+
+            log::info!("enabling plugin {name}");
+            plugins.push(factory(config.settings(name))?);
+        }
+
+        Ok(plugins)
+    }
+}
+
+/// Parse the settings of a plugin, for use in a `PluginFactory`
+pub fn parse_settings<T>(plugin: &str, settings: toml::Table) -> Result<T, PluginError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    toml::Value::Table(settings)
+        .try_into()
+        .map_err(|error| PluginError::Settings {
+            plugin: plugin.to_owned(),
+            error,
+        })
+}
+
+/// Add a command for every command of the plugins, a plugin command runs on the broadcaster thread so it is only
+/// forwarded there
+pub fn register_commands(registry: &mut CommandRegistry, plugins: &[Box<dyn Plugin>]) {
+    for (index, plugin) in plugins.iter().enumerate() {
+        for info in plugin.commands() {
+            if registry.get(info.name).is_some() {
+                log::warn!(
+                    "plugin {} has a command /{} that already exists, it is ignored",
+                    plugin.name(),
+                    info.name
+                );
+                continue;
+            }
+            registry.register(PluginCommand {
+                plugin: index,
+                info,
+            });
+        }
+    }
+}
+
+/// Forwards a command to the plugin at `plugin` in the broadcaster
+struct PluginCommand {
+    plugin: usize,
+    info: PluginCommandInfo,
+}
+
+impl Command for PluginCommand {
+    fn name(&self) -> &'static str {
+        self.info.name
+    }
+    fn usage(&self) -> &'static str {
+        self.info.usage
+    }
+    fn help(&self) -> &'static str {
+        self.info.help
+    }
+    fn execute(&self, ctx: &mut CommandContext, mut args: Args) -> Result<(), RequestError> {
+        ctx.client.broadcast(BroadcastMessage::PluginCommand {
+            key: ctx.client.key(),
+            plugin: self.plugin,
+            name: self.info.name.to_owned(),
+            args: args.rest().unwrap_or_default().to_owned(),
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use chat_core::user::User;
+use serde::{Deserialize, Serialize};
+
+use super::{parse_settings, Plugin, PluginContext, PluginError};
+
+pub const NAME: &str = "welcome";
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct WelcomeSettings {
+    /// `{user}` is replaced with the username and `{room}` with the room the user was placed in
+    message: String,
+}
+
+impl Default for WelcomeSettings {
+    fn default() -> Self {
+        Self {
+            message: "Welcome {user}! Type /help to see the commands".to_owned(),
+        }
+    }
+}
+
+/// Greets every client that connects with a message only they can see
+pub struct Welcome {
+    settings: WelcomeSettings,
+}
+
+impl Welcome {
+    pub fn create(settings: toml::Table) -> Result<Box<dyn Plugin>, PluginError> {
+        Ok(Box::new(Self {
+            settings: parse_settings(NAME, settings)?,
+        }))
+    }
+}
+
+impl Plugin for Welcome {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+    fn on_connect(&mut self, ctx: &mut PluginContext, user: &User, room: &str) {
+        let message = self
+            .settings
+            .message
+            .replace("{user}", &user.username().to_string())
+            .replace("{room}", room);
+        ctx.whisper(user, message);
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chat_core::{request::RequestError, value::Value};
+
+use crate::config::RateLimitConfig;
+
+/// Max amount of ip buckets kept before full (idle) buckets are thrown away
+const IP_BUCKETS_BEFORE_PRUNE: usize = 1024;
+
+/// Classic token bucket, every message takes one token and tokens refill at a constant rate up to `capacity`
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill_per_second: f64) -> Self {
+        Self {
+            capacity: f64::from(capacity),
+            refill_per_second,
+            tokens: f64::from(capacity),
+            last_refill: Instant::now(),
+        }
+    }
+    /// Change the limits of the bucket, so a reloaded config applies to buckets that already exist. Tokens built up
+    /// under the old limits are kept, up to the new capacity.
+    pub fn set_limits(&mut self, capacity: u32, refill_per_second: f64) {
+        self.refill();
+        self.capacity = f64::from(capacity);
+        self.refill_per_second = refill_per_second;
+        self.tokens = self.tokens.min(self.capacity);
+    }
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+    /// Take a token, if there are none left returns how long until there will be one
+    pub fn try_take(&mut self) -> Result<(), Duration> {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            // A bucket that does not refill, or so slowly the wait does not fit in a `Duration`, has no retry time
+            let wait = (1.0 - self.tokens) / self.refill_per_second;
+            Err(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+        }
+    }
+    /// Give a token back, used when a message was allowed by this bucket but stopped by another check
+    fn give_back(&mut self) {
+        self.tokens = (self.tokens + 1.0).min(self.capacity);
+    }
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+/// Limits shared by every client connecting from the same ip address
+#[derive(Default)]
+pub struct IpRateLimiter {
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl IpRateLimiter {
+    pub fn try_take(&self, ip: IpAddr, config: &RateLimitConfig) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= IP_BUCKETS_BEFORE_PRUNE {
+            buckets.retain(|_, bucket| !bucket.is_full());
+        }
+
+        let bucket = buckets.entry(ip).or_insert_with(|| {
+            TokenBucket::new(config.ip_burst(), config.ip_messages_per_second())
+        });
+        bucket.set_limits(config.ip_burst(), config.ip_messages_per_second());
+        bucket.try_take()
+    }
+}
+
+/// Limits for a single user, also checks that the same message is not sent over and over again
+#[derive(Debug)]
+pub struct UserRateLimiter {
+    bucket: TokenBucket,
+    /// The last text message sent, when it was first sent and how many times in a row it has been sent
+    last_message: Option<(String, Instant, u32)>,
+}
+
+impl UserRateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            bucket: TokenBucket::new(config.burst(), config.messages_per_second()),
+            last_message: None,
+        }
+    }
+    /// Check if the user at `ip` can send `payload` right now
+    pub fn check(
+        &mut self,
+        ip: IpAddr,
+        ip_limiter: &IpRateLimiter,
+        payload: &Value,
+        config: &RateLimitConfig,
+    ) -> Result<(), RequestError> {
+        if !config.enabled() {
+            return Ok(());
+        }
+        self.bucket
+            .set_limits(config.burst(), config.messages_per_second());
+
+        self.check_repeat(payload, config)
+            .and_then(|_| self.bucket.try_take())
+            .and_then(|_| {
+                ip_limiter
+                    .try_take(ip, config)
+                    .inspect_err(|_| self.bucket.give_back())
+            })
+            // Only messages that get through count as repeats, so a limited client does not fall further behind
+            .inspect(|_| self.record_repeat(payload))
+            .map_err(|retry_after| {
+                log::info!("rate limited {ip}, retry after {retry_after:?}");
+                RequestError::RateLimited {
+                    retry_after_ms: retry_after.as_millis().try_into().unwrap_or(u64::MAX),
+                }
+            })
+    }
+    /// Check that the message is not sent too many times in a row, how long until it can be sent again if it is
+    fn check_repeat(&mut self, payload: &Value, config: &RateLimitConfig) -> Result<(), Duration> {
+        let Value::String(text) = payload else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let window = config.repeat_window();
+
+        match &mut self.last_message {
+            // A repeat outside of the window starts counting again
+            Some((_, first_sent, _)) if now.duration_since(*first_sent) >= window => {
+                self.last_message = None;
+            }
+            Some((last, first_sent, count)) if last == text && *count >= config.max_repeats() => {
+                return Err(window - now.duration_since(*first_sent));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+    /// Count a message that was let through, see `check_repeat()`
+    fn record_repeat(&mut self, payload: &Value) {
+        let Value::String(text) = payload else {
+            return;
+        };
+
+        match &mut self.last_message {
+            Some((last, _, count)) if last == text => *count += 1,
+            _ => self.last_message = Some((text.clone(), Instant::now(), 1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        thread,
+    };
+
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn config(toml: &str) -> RateLimitConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_says_when_to_retry() {
+        let mut bucket = TokenBucket::new(3, 1.0);
+        for _ in 0..3 {
+            assert!(bucket.try_take().is_ok());
+        }
+
+        let retry_after = bucket.try_take().unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn bucket_that_does_not_refill_never_has_a_retry_time() {
+        let mut bucket = TokenBucket::new(1, 0.0);
+        assert!(bucket.try_take().is_ok());
+        assert_eq!(bucket.try_take(), Err(Duration::MAX));
+    }
+
+    #[test]
+    fn bucket_that_refills_too_slowly_for_a_duration_never_has_a_retry_time() {
+        for rate in [1e-20, 1e-300, f64::MIN_POSITIVE] {
+            let mut bucket = TokenBucket::new(1, rate);
+            assert!(bucket.try_take().is_ok());
+            assert_eq!(bucket.try_take(), Err(Duration::MAX), "{rate}");
+        }
+
+        let config = config("burst = 1\nmessages_per_second = 1e-300");
+        let mut limiter = UserRateLimiter::new(&config);
+        let ip_limiter = IpRateLimiter::default();
+        assert!(limiter
+            .check(IP, &ip_limiter, &Value::from("one"), &config)
+            .is_ok());
+        assert!(limiter
+            .check(IP, &ip_limiter, &Value::from("two"), &config)
+            .is_err());
+    }
+
+    #[test]
+    fn bucket_with_no_capacity_takes_nothing() {
+        let mut bucket = TokenBucket::new(0, 0.0);
+        assert!(bucket.try_take().is_err());
+    }
+
+    #[test]
+    fn given_back_tokens_do_not_go_over_capacity() {
+        let mut bucket = TokenBucket::new(1, 0.0);
+        bucket.give_back();
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_err());
+    }
+
+    #[test]
+    fn smaller_capacity_drops_extra_tokens() {
+        let mut bucket = TokenBucket::new(5, 0.0);
+        bucket.set_limits(2, 0.0);
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_err());
+    }
+
+    #[test]
+    fn new_refill_rate_applies_to_an_existing_bucket() {
+        let mut bucket = TokenBucket::new(1, 0.0);
+        assert!(bucket.try_take().is_ok());
+
+        bucket.set_limits(1, 1000.0);
+        thread::sleep(Duration::from_millis(10));
+        assert!(bucket.try_take().is_ok());
+    }
+
+    #[test]
+    fn reloaded_limits_apply_to_connected_users() {
+        let ip_limiter = IpRateLimiter::default();
+        let mut limiter = UserRateLimiter::new(&config("burst = 5"));
+        let reloaded = config("burst = 1\nmessages_per_second = 0.0");
+
+        let payload = Value::from("one");
+        assert!(limiter.check(IP, &ip_limiter, &payload, &reloaded).is_ok());
+        let payload = Value::from("two");
+        assert!(limiter.check(IP, &ip_limiter, &payload, &reloaded).is_err());
+    }
+
+    #[test]
+    fn repeats_are_limited_within_the_window() {
+        let ip_limiter = IpRateLimiter::default();
+        let config = config("burst = 10\nmax_repeats = 2");
+        let mut limiter = UserRateLimiter::new(&config);
+
+        let payload = Value::from("spam");
+        assert!(limiter.check(IP, &ip_limiter, &payload, &config).is_ok());
+        assert!(limiter.check(IP, &ip_limiter, &payload, &config).is_ok());
+        assert!(matches!(
+            limiter.check(IP, &ip_limiter, &payload, &config),
+            Err(RequestError::RateLimited { .. })
+        ));
+        // A different message is fine
+        let payload = Value::from("not spam");
+        assert!(limiter.check(IP, &ip_limiter, &payload, &config).is_ok());
+    }
+
+    #[test]
+    fn rate_limited_messages_do_not_count_as_repeats() {
+        let ip_limiter = IpRateLimiter::default();
+        let config = config("burst = 1\nmessages_per_second = 50.0\nmax_repeats = 2");
+        let mut limiter = UserRateLimiter::new(&config);
+
+        let payload = Value::from("hello");
+        assert!(limiter.check(IP, &ip_limiter, &payload, &config).is_ok());
+        assert!(limiter.check(IP, &ip_limiter, &payload, &config).is_err());
+
+        // Only the first message got through, so this is the second repeat and not the third
+        thread::sleep(Duration::from_millis(40));
+        assert!(limiter.check(IP, &ip_limiter, &payload, &config).is_ok());
+    }
+
+    #[test]
+    fn disabled_limits_let_everything_through() {
+        let ip_limiter = IpRateLimiter::default();
+        let config = config("enabled = false\nburst = 0\nip_burst = 0");
+        let mut limiter = UserRateLimiter::new(&config);
+
+        for _ in 0..10 {
+            let payload = Value::from("again");
+            assert!(limiter.check(IP, &ip_limiter, &payload, &config).is_ok());
+        }
+    }
+}
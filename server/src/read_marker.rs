@@ -0,0 +1,45 @@
+use std::collections::{BTreeSet, HashMap};
+
+use chat_core::message::MessageId;
+
+/// The id of the last message each user has read in each room they have been in, by user id. Ids stay the same when
+/// a user changes their name and are not reused, so the markers of a user are forgotten once it disconnects.
+#[derive(Default)]
+pub struct ReadMarkers {
+    markers: HashMap<usize, HashMap<String, MessageId>>,
+}
+
+impl ReadMarkers {
+    /// Start tracking `room` for `user` with everything up to `last_id` read, does nothing if it is already tracked
+    pub fn track(&mut self, user: usize, room: &str, last_id: MessageId) {
+        self.markers
+            .entry(user)
+            .or_default()
+            .entry(room.to_owned())
+            .or_insert(last_id);
+    }
+    /// Move the marker of `user` in `room` forward to `up_to`, a marker never moves back
+    pub fn mark(&mut self, user: usize, room: &str, up_to: MessageId) {
+        let marker = self
+            .markers
+            .entry(user)
+            .or_default()
+            .entry(room.to_owned())
+            .or_insert(up_to);
+        *marker = (*marker).max(up_to);
+    }
+    /// The id of the last message `user` has read in `room`, `None` if the room is not tracked for them
+    pub fn get(&self, user: usize, room: &str) -> Option<MessageId> {
+        self.markers.get(&user)?.get(room).copied()
+    }
+    /// The rooms tracked for `user`
+    pub fn rooms(&self, user: usize) -> BTreeSet<&str> {
+        self.markers
+            .get(&user)
+            .map(|rooms| rooms.keys().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+    pub fn remove(&mut self, user: usize) {
+        self.markers.remove(&user);
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use chat_core::config::{Config, ConfigError};
+use log::LevelFilter;
+use toml::Value;
+
+use crate::config::ServerConfig;
+
+/// Settings that are never logged
+const SECRET_KEYS: &[&str] = &["token"];
+
+/// A `ServerConfig` that can be swapped out while the server is running. Clients call `get()` whenever they need the
+/// config, so they always see the latest one without holding a lock.
+pub struct SharedConfig {
+    current: RwLock<Arc<ServerConfig>>,
+    /// The path given with `--config`, reloading uses the default locations if there is none
+    path: Option<PathBuf>,
+}
+
+impl SharedConfig {
+    pub fn new(config: ServerConfig, path: Option<PathBuf>) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(config)),
+            path,
+        }
+    }
+    /// The config as it is right now
+    pub fn get(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+    /// Load the config file again and swap it in. Settings that cannot change while the server is running are kept
+    /// as they were (with a warning), if the new config cannot be loaded or is not valid with the kept settings the
+    /// current one is kept.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        log::info!("reloading config");
+        let mut new = ServerConfig::load_layered(self.path.as_deref())?;
+        let old = self.get();
+
+        for setting in new.keep_unreloadable(&old) {
+            log::warn!("{setting} cannot be changed while the server is running, restart the server to change it");
+        }
+        // Checked again since settings such as `limits.max_connections` depend on the kept ones
+        let new = new.validated()?;
+
+        log_diff(&old, &new);
+
+        log::set_max_level(verbosity(&new));
+        *self.current.write().unwrap() = Arc::new(new);
+        log::info!("config reloaded");
+
+        Ok(())
+    }
+}
+
+/// The log level the server should use with this config
+pub fn verbosity(config: &ServerConfig) -> LevelFilter {
+    if config.system.verbose() {
+        LevelFilter::Trace
+    } else {
+        LevelFilter::Warn
+    }
+}
+
+/// Log every setting that is different between the two configs
+fn log_diff(old: &ServerConfig, new: &ServerConfig) {
+    let (Ok(mut old), Ok(mut new)) = (Value::try_from(old), Value::try_from(new)) else {
+        log::warn!("could not compare the old and new config");
+        return;
+    };
+    hide_secrets(&mut old);
+    hide_secrets(&mut new);
+
+    let mut changes = Vec::new();
+    diff_values("", &old, &new, &mut changes);
+
+    if changes.is_empty() {
+        log::info!("config did not change");
+    }
+    for change in changes {
+        log::info!("config changed: {change}");
+    }
+}
+
+fn diff_values(path: &str, old: &Value, new: &Value, changes: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Table(old), Value::Table(new)) => {
+            for (key, old_value) in old {
+                let path = join_path(path, key);
+                match new.get(key) {
+                    Some(new_value) => diff_values(&path, old_value, new_value, changes),
+                    None => changes.push(format!("{path} removed (was {old_value})")),
+                }
+            }
+            for (key, new_value) in new.iter().filter(|(key, _)| !old.contains_key(*key)) {
+                changes.push(format!("{} added ({new_value})", join_path(path, key)));
+            }
+        }
+        (old, new) if old != new => changes.push(format!("{path}: {old} -> {new}")),
+        _ => {}
+    }
+}
+
+/// Replace every value whose key is in `SECRET_KEYS`, so secrets (and changes to them) never end up in the log
+fn hide_secrets(value: &mut Value) {
+    match value {
+        Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    *value = Value::String("<hidden>".to_owned());
+                } else {
+                    hide_secrets(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(hide_secrets),
+        _ => {}
+    }
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    /// A shared config with `old` as the current config, reloaded from a file holding `new`
+    fn reload(name: &str, old: &str, new: &str) -> (SharedConfig, Result<(), ConfigError>) {
+        let path = env::temp_dir().join(format!(
+            "chat-reload-test-{name}-{}.toml",
+            std::process::id()
+        ));
+        fs::write(&path, new).unwrap();
+        let shared = SharedConfig::new(toml::from_str(old).unwrap(), Some(path.clone()));
+
+        let result = shared.reload();
+        fs::remove_file(path).unwrap();
+        (shared, result)
+    }
+
+    #[test]
+    fn reload_applies_changed_settings() {
+        let (shared, result) = reload(
+            "applies",
+            "[limits]\nmax_connections = 20",
+            "[limits]\nmax_connections = 10",
+        );
+        assert!(result.is_ok());
+        assert_eq!(shared.get().limits.max_connections(), 10);
+    }
+
+    #[test]
+    fn reload_is_validated_with_the_kept_settings() {
+        // Valid on its own, but system.threads cannot change so 50 connections would share 20 threads
+        let (shared, result) = reload(
+            "kept",
+            "[system]\nthreads = 20\n[limits]\nmax_connections = 20",
+            "[system]\nthreads = 50\n[limits]\nmax_connections = 50",
+        );
+        assert!(matches!(result, Err(ConfigError::Invalid(_))));
+        let current = shared.get();
+        assert_eq!(current.system.threads(), 20);
+        assert_eq!(current.limits.max_connections(), 20);
+    }
+}
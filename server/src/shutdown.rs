@@ -0,0 +1,62 @@
+use std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chat_core::request::ShutdownNotice;
+
+/// Used to stop a running `ClientListener` from another thread
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    notice: Arc<Mutex<Option<ShutdownNotice>>>,
+    /// Address of the listener, connecting to it wakes up the listener so it can see a shutdown was requested
+    wake_addr: SocketAddr,
+}
+
+impl ShutdownHandle {
+    pub fn new(listener: &TcpListener) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            notice: Arc::new(Mutex::new(None)),
+            wake_addr: wake_address(listener.local_addr()?),
+        })
+    }
+    /// Ask the server to shut down, `notice` is sent to every connected client
+    pub fn shutdown(&self, notice: ShutdownNotice) {
+        log::info!("shutdown requested: {notice}");
+        *self.notice.lock().unwrap() = Some(notice);
+
+        // The listener blocks until it gets a connection, so give it one
+        if let Err(error) = TcpStream::connect(self.wake_addr) {
+            log::warn!("failed to wake up client listener: {error}");
+        }
+    }
+    /// Returns the notice if a shutdown has been requested
+    pub fn requested(&self) -> Option<ShutdownNotice> {
+        self.notice.lock().unwrap().clone()
+    }
+}
+
+/// The address to connect to to wake up a listener bound to `address`, which may be unspecified
+pub fn wake_address(mut address: SocketAddr) -> SocketAddr {
+    if address.ip().is_unspecified() {
+        address.set_ip(match address {
+            SocketAddr::V4(_) => [127, 0, 0, 1].into(),
+            SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    address
+}
+
+/// Block until `done` returns true, returns false if `deadline` passed first
+pub fn wait_until(deadline: Instant, mut done: impl FnMut() -> bool) -> bool {
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    true
+}
@@ -0,0 +1,483 @@
+use std::{
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread,
+    time::Duration,
+};
+
+use chat_core::{
+    message::{Message, MessageId},
+    value::Value,
+};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::config::{WebhookConfig, WebhookEventKind};
+
+/// Longest a failed POST waits before it is retried
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Something that happened on the server, POSTed as json to every webhook that selected it. The `event` field holds
+/// the name of the variant.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Message {
+        id: MessageId,
+        room: Option<String>,
+        user: String,
+        /// Kind of the payload, see `Value::kind()`
+        kind: &'static str,
+        /// Only set for text, other payloads (like images) are left out
+        text: Option<String>,
+        timestamp: Option<DateTime<Utc>>,
+    },
+    Join {
+        user: String,
+        room: String,
+    },
+    Leave {
+        user: String,
+        room: String,
+    },
+    Moderation {
+        action: ModerationAction,
+        /// Username of the moderator
+        by: String,
+        /// Username of who was kicked or whose message was changed
+        target: String,
+        room: Option<String>,
+        message: Option<MessageId>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    Kick,
+    Edit,
+    Delete,
+}
+
+impl WebhookEvent {
+    pub fn message(message: &Message) -> Self {
+        Self::Message {
+            id: message.id().unwrap_or_default(),
+            room: message.room().map(str::to_owned),
+            user: message.from().username().to_string(),
+            kind: message.payload().kind(),
+            text: match message.payload() {
+                Value::String(text) => Some(text.clone()),
+                _ => None,
+            },
+            timestamp: message.timestamp(),
+        }
+    }
+    pub fn kind(&self) -> WebhookEventKind {
+        match self {
+            Self::Message { .. } => WebhookEventKind::Message,
+            Self::Join { .. } => WebhookEventKind::Join,
+            Self::Leave { .. } => WebhookEventKind::Leave,
+            Self::Moderation { .. } => WebhookEventKind::Moderation,
+        }
+    }
+    fn room(&self) -> Option<&str> {
+        match self {
+            Self::Message { room, .. } | Self::Moderation { room, .. } => room.as_deref(),
+            Self::Join { room, .. } | Self::Leave { room, .. } => Some(room),
+        }
+    }
+}
+
+/// A webhook along with the queue of its thread
+struct Webhook {
+    url: String,
+    events: Vec<WebhookEventKind>,
+    rooms: Vec<String>,
+    pattern: Option<Regex>,
+    queue: SyncSender<WebhookEvent>,
+}
+
+impl Webhook {
+    /// Events are sent if the webhook selected their kind, they are in one of its rooms (or not in a room at all)
+    /// and messages match its pattern
+    fn wants(&self, event: &WebhookEvent) -> bool {
+        if !self.events.contains(&event.kind()) {
+            return false;
+        }
+        if let Some(room) = event.room() {
+            if !self.rooms.is_empty() && !self.rooms.iter().any(|wanted| wanted == room) {
+                return false;
+            }
+        }
+        match (event, &self.pattern) {
+            (WebhookEvent::Message { text, .. }, Some(pattern)) => {
+                text.as_deref().is_some_and(|text| pattern.is_match(text))
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Every webhook in the config, each one has its own thread and a bounded queue so a slow endpoint cannot hold up
+/// the broadcaster or the other webhooks
+#[derive(Default)]
+pub struct Webhooks {
+    webhooks: Vec<Webhook>,
+}
+
+impl Webhooks {
+    /// Start a thread for every webhook, the threads stop once this is dropped and their queues are empty. The
+    /// config should already be validated.
+    pub fn start(configs: &[WebhookConfig]) -> Self {
+        let webhooks = configs
+            .iter()
+            .filter_map(|config| {
+                let pattern = match config.pattern().map(Regex::new).transpose() {
+                    Ok(pattern) => pattern,
+                    Err(error) => {
+                        log::error!("not starting webhook {}: {error}", config.url());
+                        return None;
+                    }
+                };
+                let (queue, rx) = mpsc::sync_channel(config.queue_size());
+
+                let worker = Worker::new(config);
+                thread::spawn(move || worker.run(rx));
+                log::info!("started webhook {}", config.url());
+
+                Some(Webhook {
+                    url: config.url().to_owned(),
+                    events: config.events().to_vec(),
+                    rooms: config.rooms().to_vec(),
+                    pattern,
+                    queue,
+                })
+            })
+            .collect();
+
+        Self { webhooks }
+    }
+    /// Whether any webhook selected the kind of event, so events nobody wants are not built
+    pub fn selected(&self, kind: WebhookEventKind) -> bool {
+        self.webhooks
+            .iter()
+            .any(|webhook| webhook.events.contains(&kind))
+    }
+    /// Queue the event for every webhook that wants it, it is dropped for webhooks whose queue is full
+    pub fn send(&self, event: WebhookEvent) {
+        for webhook in self.webhooks.iter().filter(|webhook| webhook.wants(&event)) {
+            match webhook.queue.try_send(event.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("queue of webhook {} is full, dropping event", webhook.url)
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    log::warn!("thread of webhook {} stopped, dropping event", webhook.url)
+                }
+            }
+        }
+    }
+}
+
+/// POSTs the events of one webhook
+struct Worker {
+    url: String,
+    agent: ureq::Agent,
+    max_retries: u32,
+    retry_delay: Duration,
+}
+
+impl Worker {
+    fn new(config: &WebhookConfig) -> Self {
+        Self {
+            url: config.url().to_owned(),
+            agent: ureq::AgentBuilder::new().timeout(config.timeout()).build(),
+            max_retries: config.max_retries(),
+            retry_delay: config.retry_delay(),
+        }
+    }
+    fn run(self, rx: Receiver<WebhookEvent>) {
+        for event in rx {
+            let body = match serde_json::to_string(&event) {
+                Ok(body) => body,
+                Err(error) => {
+                    log::error!("could not serialize webhook event {event:?}: {error}");
+                    continue;
+                }
+            };
+            self.deliver(&body);
+        }
+
+        log::info!("webhook {} stopped", self.url);
+    }
+    /// POST the body, retrying with a growing delay while the endpoint cannot be reached or has a server error.
+    /// Other errors (such as 404) are not retried since sending the same body again would not help.
+    fn deliver(&self, body: &str) {
+        let mut delay = self.retry_delay;
+
+        for attempt in 0..=self.max_retries {
+            if attempt > 0 {
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+
+            let error = match self
+                .agent
+                .post(&self.url)
+                .set("Content-Type", "application/json")
+                .send_string(body)
+            {
+                Ok(_) => return,
+                Err(error) => error,
+            };
+            let retry = match &error {
+                ureq::Error::Status(status, _) => *status >= 500 || *status == 429,
+                ureq::Error::Transport(_) => true,
+            };
+            log::warn!(
+                "webhook {} failed (attempt {}): {error}",
+                self.url,
+                attempt + 1
+            );
+            if !retry {
+                break;
+            }
+        }
+
+        log::error!("giving up on a webhook event for {}", self.url);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc::RecvTimeoutError, Arc},
+        time::Instant,
+    };
+
+    use chat_core::user::{User, Username};
+    use chrono::TimeZone;
+    use serde_json::json;
+    use tiny_http::{Response, Server};
+
+    use super::*;
+
+    /// A local endpoint that answers with `statuses` in order and 200 after that. Every body it gets is sent on
+    /// `requests` along with when it came in. With `hold` it waits for a message on it before answering each request.
+    struct Stub {
+        url: String,
+        requests: Receiver<(String, Instant)>,
+        server: Arc<Server>,
+    }
+
+    impl Stub {
+        fn start(statuses: Vec<u16>, hold: Option<Receiver<()>>) -> Self {
+            let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+            let url = format!("http://{}/hook", server.server_addr());
+            let (tx, requests) = mpsc::channel();
+            let mut statuses = statuses.into_iter();
+
+            thread::spawn({
+                let server = Arc::clone(&server);
+                move || {
+                    for mut request in server.incoming_requests() {
+                        let mut body = String::new();
+                        request.as_reader().read_to_string(&mut body).unwrap();
+                        tx.send((body, Instant::now())).ok();
+                        if let Some(hold) = &hold {
+                            hold.recv().ok();
+                        }
+                        let status = statuses.next().unwrap_or(200);
+                        request.respond(Response::empty(status)).ok();
+                    }
+                }
+            });
+
+            Self {
+                url,
+                requests,
+                server,
+            }
+        }
+        /// The body of the next request, `None` if there is none within `wait`
+        fn next(&self, wait: Duration) -> Option<(serde_json::Value, Instant)> {
+            match self.requests.recv_timeout(wait) {
+                Ok((body, at)) => Some((serde_json::from_str(&body).unwrap(), at)),
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => None,
+            }
+        }
+    }
+
+    impl Drop for Stub {
+        fn drop(&mut self) {
+            self.server.unblock();
+        }
+    }
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn webhooks(stub: &Stub, settings: &str) -> Webhooks {
+        let config: WebhookConfig =
+            toml::from_str(&format!("url = \"{}\"\n{settings}", stub.url)).unwrap();
+        Webhooks::start(&[config])
+    }
+
+    fn message(room: &str, payload: Value) -> WebhookEvent {
+        let mut message = Message::builder()
+            .from_who(User::builder().id(3).username(Username::new("amy")).build())
+            .payload(payload)
+            .room(Some(room.to_owned()))
+            .build();
+        message.stamp(7, Utc.with_ymd_and_hms(2026, 1, 2, 3, 4, 5).unwrap());
+        WebhookEvent::message(&message)
+    }
+
+    #[test]
+    fn events_are_posted_as_json() {
+        let stub = Stub::start(Vec::new(), None);
+        let webhooks = webhooks(&stub, "");
+
+        webhooks.send(message("general", Value::from("hello")));
+        assert_eq!(
+            stub.next(WAIT).unwrap().0,
+            json!({
+                "event": "message",
+                "id": 7,
+                "room": "general",
+                "user": "amy",
+                "kind": "string",
+                "text": "hello",
+                "timestamp": "2026-01-02T03:04:05Z",
+            })
+        );
+
+        webhooks.send(message("general", Value::Image(vec![1, 2, 3])));
+        let (body, _) = stub.next(WAIT).unwrap();
+        assert_eq!(body["kind"], "image");
+        assert_eq!(body["text"], serde_json::Value::Null);
+
+        webhooks.send(WebhookEvent::Join {
+            user: "amy".to_owned(),
+            room: "dev".to_owned(),
+        });
+        assert_eq!(
+            stub.next(WAIT).unwrap().0,
+            json!({"event": "join", "user": "amy", "room": "dev"})
+        );
+
+        webhooks.send(WebhookEvent::Moderation {
+            action: ModerationAction::Kick,
+            by: "mod".to_owned(),
+            target: "amy".to_owned(),
+            room: None,
+            message: None,
+        });
+        assert_eq!(
+            stub.next(WAIT).unwrap().0,
+            json!({
+                "event": "moderation",
+                "action": "kick",
+                "by": "mod",
+                "target": "amy",
+                "room": null,
+                "message": null,
+            })
+        );
+    }
+
+    #[test]
+    fn only_selected_events_rooms_and_patterns_are_sent() {
+        let stub = Stub::start(Vec::new(), None);
+        let webhooks = webhooks(
+            &stub,
+            r#"events = ["message", "moderation"]
+            rooms = ["dev"]
+            pattern = "(?i)deploy""#,
+        );
+        assert!(webhooks.selected(WebhookEventKind::Message));
+        assert!(webhooks.selected(WebhookEventKind::Moderation));
+        assert!(!webhooks.selected(WebhookEventKind::Join));
+        assert!(!webhooks.selected(WebhookEventKind::Leave));
+        assert!(!Webhooks::default().selected(WebhookEventKind::Message));
+
+        // Not selected, in another room, not matching the pattern and not text
+        webhooks.send(WebhookEvent::Join {
+            user: "amy".to_owned(),
+            room: "dev".to_owned(),
+        });
+        webhooks.send(message("general", Value::from("Deploy done")));
+        webhooks.send(message("dev", Value::from("lunch?")));
+        webhooks.send(message("dev", Value::File(b"deploy".to_vec())));
+        // Sent, events that are not in a room are sent whatever the rooms are
+        webhooks.send(message("dev", Value::from("Deploy done")));
+        webhooks.send(WebhookEvent::Moderation {
+            action: ModerationAction::Kick,
+            by: "mod".to_owned(),
+            target: "amy".to_owned(),
+            room: None,
+            message: None,
+        });
+
+        let (body, _) = stub.next(WAIT).unwrap();
+        assert_eq!(
+            (&body["event"], &body["text"]),
+            (&json!("message"), &json!("Deploy done"))
+        );
+        let (body, _) = stub.next(WAIT).unwrap();
+        assert_eq!(body["event"], "moderation");
+        assert!(stub.next(Duration::from_millis(200)).is_none());
+    }
+
+    #[test]
+    fn server_errors_are_retried_with_a_growing_delay() {
+        let stub = Stub::start(vec![500, 503], None);
+        let webhooks = webhooks(&stub, "retry_delay_ms = 100");
+
+        webhooks.send(message("general", Value::from("hello")));
+        let (first, first_at) = stub.next(WAIT).unwrap();
+        let (second, second_at) = stub.next(WAIT).unwrap();
+        let (third, third_at) = stub.next(WAIT).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(second, third);
+        assert!(second_at - first_at >= Duration::from_millis(100));
+        assert!(third_at - second_at >= Duration::from_millis(200));
+        // The third attempt succeeded
+        assert!(stub.next(Duration::from_millis(500)).is_none());
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let stub = Stub::start(vec![404], None);
+        let webhooks = webhooks(&stub, "retry_delay_ms = 10");
+
+        webhooks.send(message("general", Value::from("hello")));
+        assert!(stub.next(WAIT).is_some());
+        assert!(stub.next(Duration::from_millis(200)).is_none());
+    }
+
+    #[test]
+    fn events_are_dropped_while_the_queue_is_full() {
+        let (release, hold) = mpsc::channel();
+        let stub = Stub::start(Vec::new(), Some(hold));
+        let webhooks = webhooks(&stub, "queue_size = 1");
+
+        // The first event is being sent and held up by the endpoint
+        webhooks.send(message("general", Value::from("first")));
+        assert!(stub.next(WAIT).is_some());
+
+        // Only one more fits in the queue, the rest are dropped without waiting for the endpoint
+        let started = Instant::now();
+        for text in ["second", "third", "fourth", "fifth"] {
+            webhooks.send(message("general", Value::from(text)));
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        for _ in 0..5 {
+            release.send(()).unwrap();
+        }
+        let (body, _) = stub.next(WAIT).unwrap();
+        assert_eq!(body["text"], "second");
+        assert!(stub.next(Duration::from_millis(200)).is_none());
+    }
+}
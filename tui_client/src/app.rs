@@ -0,0 +1,275 @@
+use std::{
+    sync::mpsc::TryRecvError,
+    time::{Duration, Instant},
+};
+
+use chat_client::{chat_log::ChatLog, config::DisplayConfig, e2e::KeyPair, ChatClient, Events};
+use chat_core::{event::Event, message::MessageId, request::Request};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// How often the member and room lists are asked for, the server does not tell us when users come and go
+const REFRESH: Duration = Duration::from_secs(5);
+/// How many lines page up and page down scroll
+const PAGE: u16 = 10;
+/// Sends the rest of the line encrypted so only the user can read it, handled here since the server cannot
+const ENCRYPTED_MESSAGE: &str = "/emsg ";
+
+/// Everything the terminal client shows, drawn by `ui::draw()`
+pub struct App {
+    client: ChatClient,
+    events: Events,
+    display: DisplayConfig,
+    log: ChatLog,
+    input: String,
+    /// Lines that were sent, oldest first
+    history: Vec<String>,
+    /// The line of `history` in the input while going through it with up and down
+    history_index: Option<usize>,
+    /// What was typed before going through the history, put back when going past the newest line
+    draft: String,
+    /// How many lines the messages are scrolled up from the bottom
+    scroll: u16,
+    /// The room we are in and the newest message in it we told the server we have read
+    read: Option<(String, Option<MessageId>)>,
+    /// When the member and room lists were last asked for
+    refreshed: Instant,
+    /// Encrypted messages waiting for the public key of the user they are for, by user id
+    pending: Vec<(usize, String)>,
+    quit: bool,
+}
+
+impl App {
+    pub fn new(
+        mut client: ChatClient,
+        events: Events,
+        display: DisplayConfig,
+    ) -> Result<Self, bincode::Error> {
+        client.request(&Request::RoomList)?;
+        client.list_users()?;
+        let key_pair = KeyPair::generate();
+        client.publish_key(key_pair.public_key())?;
+        let mut log = ChatLog::default();
+        log.set_key_pair(key_pair);
+
+        Ok(Self {
+            client,
+            events,
+            display,
+            log,
+            input: String::new(),
+            history: Vec::new(),
+            history_index: None,
+            draft: String::new(),
+            scroll: 0,
+            read: None,
+            refreshed: Instant::now(),
+            pending: Vec::new(),
+            quit: false,
+        })
+    }
+    pub fn log(&self) -> &ChatLog {
+        &self.log
+    }
+    pub fn display(&self) -> &DisplayConfig {
+        &self.display
+    }
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+    pub fn scroll(&self) -> u16 {
+        self.scroll
+    }
+    /// Clamp the scroll to the amount of lines that can be scrolled, done when drawing since that is when it is known
+    pub fn clamp_scroll(&mut self, max: u16) {
+        self.scroll = self.scroll.min(max);
+    }
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+    /// Apply every event the server has sent since the last call, returns false once the connection is closed
+    pub fn poll_events(&mut self) -> bool {
+        loop {
+            match self.events.try_next() {
+                Ok(Ok(Event::PublicKey { user, key: None })) => {
+                    if self.pending.iter().any(|(to, _)| *to == user) {
+                        self.pending.retain(|(to, _)| *to != user);
+                        self.log
+                            .push_notice("the user has no key, the encrypted message was not sent");
+                    }
+                }
+                Ok(Ok(event)) => self.log.apply(event),
+                Ok(Err(error)) => self.log.push_error(error),
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+    /// Keep the member and room lists up to date and tell the server we have read what is shown, the terminal is always
+    /// looked at so everything in our room counts as read
+    pub fn sync(&mut self) -> Result<(), bincode::Error> {
+        self.send_pending()?;
+        let Some(room) = self.log.current_room().map(str::to_owned) else {
+            return Ok(());
+        };
+        let latest = self.log.latest_in(&room);
+
+        let room_changed = self.read.as_ref().is_none_or(|(read, _)| *read != room);
+        if room_changed {
+            self.client.list_users()?;
+        }
+        if self.refreshed.elapsed() >= REFRESH {
+            self.client.list_users()?;
+            self.client.request(&Request::RoomList)?;
+            self.refreshed = Instant::now();
+        }
+        if room_changed {
+            self.read = Some((room.clone(), None));
+            self.scroll = 0;
+        }
+
+        if let Some((_, read)) = &mut self.read {
+            if let Some(latest) = latest.filter(|latest| Some(*latest) > *read) {
+                self.client.request(&Request::MarkRead {
+                    room,
+                    up_to: latest,
+                })?;
+                *read = Some(latest);
+            }
+        }
+
+        Ok(())
+    }
+    pub fn handle_key(&mut self, key: KeyEvent) -> Result<(), bincode::Error> {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if control => self.quit = true,
+            KeyCode::Esc => self.quit = true,
+            KeyCode::Enter => self.send()?,
+            KeyCode::Char(c) if !control => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Up => self.previous_line(),
+            KeyCode::Down => self.next_line(),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_add(PAGE),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(PAGE),
+            KeyCode::Tab => self.switch_room(1)?,
+            KeyCode::BackTab => self.switch_room(-1)?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+    /// Send the input as a message, commands like `/join` are handled by the server
+    fn send(&mut self) -> Result<(), bincode::Error> {
+        let text = self.input.trim_end().to_owned();
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        match text.strip_prefix(ENCRYPTED_MESSAGE) {
+            Some(args) => self.send_encrypted(args)?,
+            None => self.client.send_message(text.as_str())?,
+        }
+        if self.history.last() != Some(&text) {
+            self.history.push(text);
+        }
+        self.input.clear();
+        self.history_index = None;
+        self.scroll = 0;
+
+        Ok(())
+    }
+    /// Handle `/emsg <username> <message>`, the key of the user is asked for first if we do not have it yet
+    fn send_encrypted(&mut self, args: &str) -> Result<(), bincode::Error> {
+        let Some((name, text)) = args
+            .trim_start()
+            .split_once(' ')
+            .filter(|(_, text)| !text.trim().is_empty())
+        else {
+            self.log.push_notice("usage: /emsg <username> <message>");
+            return Ok(());
+        };
+        let Some(user) = self
+            .log
+            .members()
+            .iter()
+            .find(|user| user.username().to_string() == name)
+            .map(|user| user.id())
+        else {
+            self.log.push_notice(format!(
+                "{name} is not in this room, encrypted messages need their key"
+            ));
+            return Ok(());
+        };
+
+        if self.log.public_key(user).is_none() {
+            self.client.request_key(user)?;
+        }
+        self.pending.push((user, text.trim().to_owned()));
+        self.send_pending()
+    }
+    /// Encrypt and send the pending messages for users whose key we have
+    fn send_pending(&mut self) -> Result<(), bincode::Error> {
+        let mut waiting = Vec::new();
+
+        for (to, text) in std::mem::take(&mut self.pending) {
+            let (Some(key), Some(key_pair)) = (self.log.public_key(to), self.log.key_pair()) else {
+                waiting.push((to, text));
+                continue;
+            };
+            match key_pair.encrypt(key, &text) {
+                Ok(payload) => self.client.send_encrypted(to, payload)?,
+                Err(error) => self
+                    .log
+                    .push_notice(format!("the encrypted message was not sent: {error}")),
+            }
+        }
+        self.pending = waiting;
+
+        Ok(())
+    }
+    /// Put the line sent before the one in the input into the input
+    fn previous_line(&mut self) {
+        let index = match self.history_index {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(&mut self.input);
+                self.history.len() - 1
+            }
+        };
+        self.history_index = Some(index);
+        self.input = self.history[index].clone();
+    }
+    /// Put the line sent after the one in the input into the input, or what was typed before going through the
+    /// history once there are no newer lines
+    fn next_line(&mut self) {
+        let Some(index) = self.history_index else {
+            return;
+        };
+        if index + 1 < self.history.len() {
+            self.history_index = Some(index + 1);
+            self.input = self.history[index + 1].clone();
+        } else {
+            self.history_index = None;
+            self.input = std::mem::take(&mut self.draft);
+        }
+    }
+    /// Join the room `offset` tabs away from the current one
+    fn switch_room(&mut self, offset: isize) -> Result<(), bincode::Error> {
+        let rooms = self.log.rooms();
+        if rooms.len() < 2 {
+            return Ok(());
+        }
+        let current = rooms
+            .iter()
+            .position(|room| Some(room.name.as_str()) == self.log.current_room())
+            .unwrap_or_default();
+        let next = (current as isize + offset).rem_euclid(rooms.len() as isize) as usize;
+
+        let room = rooms[next].name.clone();
+        self.client.join_room(&room)
+    }
+}
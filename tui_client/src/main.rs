@@ -0,0 +1,84 @@
+use std::{env, io, process, time::Duration};
+
+use chat_client::{config::ClientConfig, ChatClient, DEFAULT_ADDRESS};
+use chat_core::config::{Config, ConfigArgs};
+use crossterm::event::{self, Event, KeyEventKind};
+use ratatui::DefaultTerminal;
+use tui_client::{app::App, ui};
+
+/// How long to wait for a key before checking for events from the server again
+const TICK: Duration = Duration::from_millis(100);
+
+fn main() {
+    let args = match ConfigArgs::parse(env::args()) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{error}");
+            process::exit(2);
+        }
+    };
+    if args.dump_default {
+        print!("{}", ClientConfig::TEMPLATE);
+        return;
+    }
+
+    // The same config file as the gui client, but without one the defaults are used instead of asking for a name
+    let config = match ClientConfig::load_layered(args.path.as_deref()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Could not load config: {error}");
+            process::exit(1);
+        }
+    };
+
+    let (mut client, events) = match ChatClient::connect(DEFAULT_ADDRESS) {
+        Ok(connection) => connection,
+        Err(error) => {
+            eprintln!("Could not connect to the server: {error}");
+            process::exit(1);
+        }
+    };
+    if let Some(name) = config.username.name() {
+        if let Err(error) = client.rename(name) {
+            eprintln!("Could not set the username: {error}");
+            process::exit(1);
+        }
+    }
+    let app = match App::new(client, events, config.display) {
+        Ok(app) => app,
+        Err(error) => {
+            eprintln!("Could not send to the server: {error}");
+            process::exit(1);
+        }
+    };
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, app);
+    ratatui::restore();
+
+    if let Err(error) = result {
+        eprintln!("{error}");
+        process::exit(1);
+    }
+}
+
+/// Draw the app and handle keys until the user quits or the connection is closed
+fn run(terminal: &mut DefaultTerminal, mut app: App) -> Result<(), io::Error> {
+    while !app.should_quit() {
+        if !app.poll_events() {
+            return Err(io::Error::other("connection to the server closed"));
+        }
+        app.sync().map_err(io::Error::other)?;
+        terminal.draw(|frame| ui::draw(frame, &mut app))?;
+
+        if event::poll(TICK)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    app.handle_key(key).map_err(io::Error::other)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use chat_client::chat_log::ChatLine;
+use chat_core::message::Message;
+use ratatui::{
+    layout::{Constraint, Layout, Position, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, Borders, List, Paragraph, Tabs},
+    Frame,
+};
+
+use crate::app::App;
+
+/// Width of the member list
+const MEMBERS_WIDTH: u16 = 24;
+
+/// Draw the room tabs, the messages with the member list next to them, who is typing and the input line
+pub fn draw(frame: &mut Frame, app: &mut App) {
+    let [tabs, body, typing_area, input] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(3),
+        Constraint::Length(1),
+        Constraint::Length(3),
+    ])
+    .areas(frame.area());
+    let [messages, members] =
+        Layout::horizontal([Constraint::Min(20), Constraint::Length(MEMBERS_WIDTH)]).areas(body);
+
+    draw_tabs(frame, app, tabs);
+    draw_messages(frame, app, messages);
+    draw_members(frame, app, members);
+    if let Some(typing) = app.log().typing() {
+        frame.render_widget(Line::from(typing).dim(), typing_area);
+    }
+    draw_input(frame, app, input);
+}
+
+fn draw_tabs(frame: &mut Frame, app: &App, area: Rect) {
+    let log = app.log();
+    let titles = log.rooms().iter().map(|room| {
+        let current = log.current_room() == Some(room.name.as_str());
+        match room.unread {
+            0 => Line::from(format!("#{}", room.name)),
+            unread if current => Line::from(format!("#{} ({unread})", room.name)),
+            // Rooms with messages we have not read stand out
+            unread => Line::from(format!("#{} ({unread})", room.name)).bold(),
+        }
+    });
+    let selected = log
+        .rooms()
+        .iter()
+        .position(|room| Some(room.name.as_str()) == log.current_room());
+
+    frame.render_widget(
+        Tabs::new(titles)
+            .select(selected)
+            .highlight_style(Style::new().reversed()),
+        area,
+    );
+}
+
+/// The messages, wrapped to the width of the pane and scrolled so the newest is at the bottom
+fn draw_messages(frame: &mut Frame, app: &mut App, area: Rect) {
+    let block = Block::new().borders(Borders::ALL);
+    let inner = block.inner(area);
+    let width = usize::from(inner.width.max(1));
+
+    let mut lines = Vec::new();
+    for line in app.log().lines() {
+        match line {
+            ChatLine::Message(message) => {
+                let style = message_style(app, message);
+                if let Some(parent) = message.reply_to() {
+                    let quote = format!("  ↪ {}", app.log().quote(parent));
+                    push_wrapped(&mut lines, &quote, width, Style::new().dim());
+                }
+                push_wrapped(&mut lines, &message_text(app, message), width, style);
+            }
+            ChatLine::Error(error) => {
+                push_wrapped(&mut lines, &error.to_string(), width, Style::new().red());
+            }
+            ChatLine::Notice(notice) => {
+                push_wrapped(&mut lines, notice, width, Style::new().dim());
+            }
+        }
+    }
+
+    let height = usize::from(inner.height);
+    let max_scroll = lines.len().saturating_sub(height);
+    app.clamp_scroll(u16::try_from(max_scroll).unwrap_or(u16::MAX));
+    let end = lines.len() - usize::from(app.scroll());
+    let start = end.saturating_sub(height);
+
+    let title = match app.scroll() {
+        0 => "Messages".to_owned(),
+        scroll => format!("Messages (scrolled up {scroll})"),
+    };
+    frame.render_widget(
+        Paragraph::new(lines[start..end].to_vec()).block(block.title(title)),
+        area,
+    );
+}
+
+/// A message along with its reactions on one line
+fn message_text(app: &App, message: &Message) -> String {
+    let mut text = app.display().format_message(message);
+    for reaction in message.reactions() {
+        text.push_str(&format!(" [{} {}]", reaction.emoji, reaction.count));
+    }
+    text
+}
+
+/// Messages that mention us stand out like in the gui client
+fn message_style(app: &App, message: &Message) -> Style {
+    if message.id().is_some_and(|id| app.log().is_mentioned(id)) {
+        Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+    } else {
+        Style::new()
+    }
+}
+
+/// Split `text` into lines of at most `width` characters, every line of the text starts a new line
+fn push_wrapped(lines: &mut Vec<Line<'static>>, text: &str, width: usize, style: Style) {
+    for text_line in text.lines() {
+        let chars = text_line.chars().collect::<Vec<_>>();
+        if chars.is_empty() {
+            lines.push(Line::styled(String::new(), style));
+        }
+        for chunk in chars.chunks(width) {
+            lines.push(Line::styled(chunk.iter().collect::<String>(), style));
+        }
+    }
+}
+
+fn draw_members(frame: &mut Frame, app: &App, area: Rect) {
+    let members = app.log().members();
+    let names = members
+        .iter()
+        .map(|user| user.username().to_string())
+        .collect::<Vec<_>>();
+
+    frame.render_widget(
+        List::new(names).block(
+            Block::new()
+                .borders(Borders::ALL)
+                .title(format!("Members ({})", members.len())),
+        ),
+        area,
+    );
+}
+
+/// The input line, the end of the input is shown if it does not fit
+fn draw_input(frame: &mut Frame, app: &App, area: Rect) {
+    let title = match app.log().current_room() {
+        Some(room) => format!("#{room}"),
+        None => "Message".to_owned(),
+    };
+    let block = Block::new().borders(Borders::ALL).title(title);
+    let inner = block.inner(area);
+
+    let width = usize::from(inner.width.saturating_sub(1));
+    let chars = app.input().chars().count();
+    let shown = app
+        .input()
+        .chars()
+        .skip(chars.saturating_sub(width))
+        .collect::<String>();
+    let cursor = inner.x + u16::try_from(shown.chars().count()).unwrap_or_default();
+
+    frame.render_widget(Paragraph::new(shown).block(block), area);
+    frame.set_cursor_position(Position::new(cursor, inner.y));
+}